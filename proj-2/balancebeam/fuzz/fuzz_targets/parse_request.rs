@@ -0,0 +1,47 @@
+//! Feeds arbitrary bytes to balancebeam's request parser. Run it from the balancebeam directory
+//! with `cargo +nightly fuzz run parse_request`.
+//!
+//! Besides not panicking, every request the parser accepts must have exactly one way to read
+//! its length, and must come out the same when re-parsed from what we would forward upstream.
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/http2.rs"]
+mod http2;
+#[allow(dead_code)]
+#[path = "../../src/request.rs"]
+mod request;
+#[allow(dead_code)]
+#[path = "../../src/response.rs"]
+mod response;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = request::Limits::default();
+    let (request, head_len) = match request::parse_request(data, &limits) {
+        Ok(Some(parsed)) => parsed,
+        _ => return,
+    };
+    assert!(head_len <= data.len());
+    let headers = request.headers();
+    assert!(headers.get_all(http::header::CONTENT_LENGTH).iter().count() <= 1);
+    assert!(!headers.contains_key(http::header::TRANSFER_ENCODING));
+    if let Some(length) = headers.get(http::header::CONTENT_LENGTH) {
+        assert!(request::parse_content_length(length.as_bytes()).is_some());
+    }
+
+    let mut forwarded = Vec::new();
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(request::write_to_stream(&request, &mut forwarded))
+        .unwrap();
+    let (reparsed, reparsed_len) = request::parse_request(&forwarded, &limits)
+        .expect("a request we accepted was rejected once forwarded")
+        .expect("a request we accepted was incomplete once forwarded");
+    assert_eq!(reparsed_len, forwarded.len());
+    assert_eq!(reparsed.method(), request.method());
+    assert_eq!(reparsed.uri(), request.uri());
+    assert_eq!(reparsed.headers(), request.headers());
+});
@@ -0,0 +1,45 @@
+//! Feeds arbitrary bytes to balancebeam's response parser. Run it from the balancebeam directory
+//! with `cargo +nightly fuzz run parse_response`.
+//!
+//! Besides not panicking, every response the parser accepts must have at most one way to read
+//! its length, and must come out the same when re-parsed from what we would send the client.
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/http2.rs"]
+mod http2;
+#[allow(dead_code)]
+#[path = "../../src/request.rs"]
+mod request;
+#[allow(dead_code)]
+#[path = "../../src/response.rs"]
+mod response;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (response, head_len) = match response::parse_response(data) {
+        Ok(Some(parsed)) => parsed,
+        _ => return,
+    };
+    assert!(head_len <= data.len());
+    let headers = response.headers();
+    assert!(headers.get_all(http::header::CONTENT_LENGTH).iter().count() <= 1);
+    assert!(
+        !(headers.contains_key(http::header::CONTENT_LENGTH)
+            && headers.contains_key(http::header::TRANSFER_ENCODING))
+    );
+
+    let mut forwarded = Vec::new();
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(response::write_to_stream(&response, &mut forwarded))
+        .unwrap();
+    let (reparsed, reparsed_len) = response::parse_response(&forwarded)
+        .expect("a response we accepted was rejected once forwarded")
+        .expect("a response we accepted was incomplete once forwarded");
+    assert_eq!(reparsed_len, forwarded.len());
+    assert_eq!(reparsed.status(), response.status());
+    assert_eq!(reparsed.headers(), response.headers());
+});
@@ -0,0 +1,264 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// How each access log line is laid out.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// One JSON object per line
+    Json,
+    /// Apache/nginx Combined Log Format, followed by balancebeam's own fields as key=value pairs
+    Combined,
+}
+
+/// Which upstream served a request, and how long it took. Attached to responses as an extension
+/// by whoever talks to the upstream.
+#[derive(Clone)]
+pub struct UpstreamTiming {
+    pub address: String,
+    pub latency: Duration,
+}
+
+/// Marks a response as the result of the client exceeding its rate limit.
+#[derive(Clone)]
+pub struct RateLimited;
+
+/// What we need to remember about a request until its response is ready to log.
+pub struct RequestInfo {
+    time: SystemTime,
+    client_ip: String,
+    method: String,
+    path: String,
+    protocol: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+    rate_limited_client: bool,
+}
+
+impl RequestInfo {
+    /// `rate_limited_client` says whether this client is subject to a rate limit at all.
+    pub fn new(
+        request: &http::Request<Vec<u8>>,
+        client_ip: &str,
+        rate_limited_client: bool,
+    ) -> RequestInfo {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        RequestInfo {
+            time: SystemTime::now(),
+            client_ip: client_ip.to_string(),
+            method: request.method().to_string(),
+            path: request
+                .uri()
+                .path_and_query()
+                .map_or("/".to_string(), |path| path.to_string()),
+            protocol: format!("{:?}", request.version()),
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+            request_id: header("x-request-id"),
+            rate_limited_client,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Entry<'a> {
+    time: String,
+    client_ip: &'a str,
+    method: &'a str,
+    path: &'a str,
+    protocol: &'a str,
+    status: u16,
+    bytes: usize,
+    upstream: Option<&'a str>,
+    upstream_latency_ms: Option<f64>,
+    latency_ms: f64,
+    /// "allowed", "limited", or "unlimited" if the client has no rate limit
+    rate_limit: &'static str,
+    request_id: Option<&'a str>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+enum Output {
+    Stdout,
+    File {
+        path: PathBuf,
+        file: parking_lot::Mutex<std::fs::File>,
+    },
+}
+
+/// Writes one line per proxied request to stdout or a file.
+pub struct AccessLog {
+    format: Format,
+    output: Output,
+}
+
+fn open_for_append(path: &PathBuf) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+impl AccessLog {
+    /// Logs to `destination`, which is a file path or "-" for stdout.
+    pub fn new(destination: &str, format: Format) -> std::io::Result<AccessLog> {
+        let output = if destination == "-" {
+            Output::Stdout
+        } else {
+            let path = PathBuf::from(destination);
+            let file = open_for_append(&path)?;
+            Output::File {
+                path,
+                file: parking_lot::Mutex::new(file),
+            }
+        };
+        Ok(AccessLog { format, output })
+    }
+
+    /// Reopens the log file, so that logrotate can move the old one out of the way and then
+    /// signal us to start writing to a new one.
+    pub fn reopen(&self) {
+        if let Output::File { path, file } = &self.output {
+            match open_for_append(path) {
+                Ok(reopened) => {
+                    *file.lock() = reopened;
+                    log::info!("Reopened access log {:?}", path);
+                }
+                Err(err) => log::error!("Could not reopen access log {:?}: {}", path, err),
+            }
+        }
+    }
+
+    pub fn record(&self, info: &RequestInfo, response: &http::Response<Vec<u8>>) {
+        let upstream = response.extensions().get::<UpstreamTiming>();
+        let rate_limit = if response.extensions().get::<RateLimited>().is_some() {
+            "limited"
+        } else if info.rate_limited_client {
+            "allowed"
+        } else {
+            "unlimited"
+        };
+        let latency = info.time.elapsed().unwrap_or_default();
+        let entry = Entry {
+            time: rfc3339(info.time),
+            client_ip: &info.client_ip,
+            method: &info.method,
+            path: &info.path,
+            protocol: &info.protocol,
+            status: response.status().as_u16(),
+            bytes: response.body().len(),
+            upstream: upstream.map(|upstream| upstream.address.as_str()),
+            upstream_latency_ms: upstream.map(|upstream| millis(upstream.latency)),
+            latency_ms: millis(latency),
+            rate_limit,
+            request_id: info.request_id.as_deref(),
+            referer: info.referer.as_deref(),
+            user_agent: info.user_agent.as_deref(),
+        };
+        let line = match self.format {
+            Format::Json => {
+                serde_json::to_string(&entry).expect("access log entry is always serializable")
+            }
+            Format::Combined => combined(info.time, &entry),
+        };
+        self.write_line(&line);
+    }
+
+    fn write_line(&self, line: &str) {
+        let result = match &self.output {
+            Output::Stdout => writeln!(std::io::stdout().lock(), "{}", line),
+            // NOTE: one write per line, so lines from concurrent requests don't interleave
+            Output::File { file, .. } => file.lock().write_all(format!("{}\n", line).as_bytes()),
+        };
+        if let Err(err) = result {
+            log::warn!("Failed to write to access log: {}", err);
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// `client - - [10/Oct/2000:13:55:36 +0000] "GET /a HTTP/1.1" 200 2326 "referer" "agent"`, plus
+/// balancebeam's fields.
+fn combined(time: SystemTime, entry: &Entry) -> String {
+    let (year, month, day, hour, minute, second, _) = utc(time);
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let quoted = |value: Option<&str>| match value {
+        Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => "\"-\"".to_string(),
+    };
+    format!(
+        "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] {} {} {} {} {} upstream={} \
+        upstream_time={} request_time={:.3} rate_limit={} request_id={}",
+        entry.client_ip,
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second,
+        quoted(Some(&format!(
+            "{} {} {}",
+            entry.method, entry.path, entry.protocol
+        ))),
+        entry.status,
+        entry.bytes,
+        quoted(entry.referer),
+        quoted(entry.user_agent),
+        entry.upstream.unwrap_or("-"),
+        entry
+            .upstream_latency_ms
+            .map_or("-".to_string(), |ms| format!("{:.3}", ms / 1000.0)),
+        entry.latency_ms / 1000.0,
+        entry.rate_limit,
+        entry.request_id.unwrap_or("-"),
+    )
+}
+
+/// e.g. 2000-10-10T13:55:36.123Z
+fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    )
+}
+
+/// Splits a time into UTC (year, month, day, hour, minute, second, millisecond).
+fn utc(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::{mirror, request, response, socket, ProxyState};
+
+/// Serves the admin interface on its own listener, which should only be reachable by operators.
+///
+/// Endpoints:
+///
+/// * `POST /cache/purge` drops every cached response. `?path=/a/b` restricts the purge to one
+///   path and `?prefix=/a/` to every path under a prefix.
+/// * `GET /concurrency` reports each upstream's in-flight request limit and how many requests
+///   it has in flight, by pool.
+/// * `GET /pools` reports how many requests each pool has served, how many of them failed and
+///   how long they took on average.
+/// * `GET /workers` reports how many clients each --reuseport-workers worker has accepted (an
+///   empty list without workers).
+/// * `GET /canaries` lists the share of requests each canary pool gets, and which pool it takes
+///   them from. `POST /canaries?pool=api-v2&percent=25` changes the share of every route that
+///   has `api-v2` as its canary.
+/// * `GET /mirrors` reports, by shadow pool, how many requests were mirrored to it and how its
+///   responses compared to the real ones.
+/// * `GET /upstreams` lists every upstream of each pool (as discovered, for pools that name
+///   hosts or SRV records), with its priority and weight (and the weight it gets while slow
+///   start ramps it up), whether it is alive and, for pools balanced with `ewma`, its latency
+///   estimate in `latency_ms`.
+pub async fn serve(listener: socket::Listener, state: Arc<ProxyState>) {
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::warn!("Failed to accept admin connection: {}", err);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let limits = request::Limits::default();
+            let mut buffered = Vec::new();
+            while let Ok(request) =
+                request::read_from_stream(&mut stream, &mut buffered, &limits).await
+            {
+                let response = handle_request(&state, &request).await;
+                log::info!(
+                    "admin: {} -> {}",
+                    request::format_request_line(&request),
+                    response.status()
+                );
+                if response::write_to_stream(&response, &mut stream)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let params: Vec<(String, String)> =
+        form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    match (request.method(), request.uri().path()) {
+        (&http::Method::POST, "/cache/purge") => {
+            let cache = match &state.cache {
+                Some(cache) => cache,
+                None => {
+                    return make_json(
+                        http::StatusCode::NOT_FOUND,
+                        "{\"error\":\"caching is disabled\"}",
+                    )
+                }
+            };
+            let purged = match (param("path"), param("prefix")) {
+                (Some(path), _) => cache.purge(|_, entry_path| entry_path == path),
+                (None, Some(prefix)) => cache.purge(|_, entry_path| entry_path.starts_with(prefix)),
+                (None, None) => cache.purge(|_, _| true),
+            };
+            make_json(http::StatusCode::OK, &format!("{{\"purged\":{}}}", purged))
+        }
+        (&http::Method::GET, "/concurrency") => {
+            let pools: serde_json::Map<String, serde_json::Value> = state
+                .pools
+                .iter()
+                .map(|pool| {
+                    let upstreams = pool
+                        .limits
+                        .snapshot()
+                        .into_iter()
+                        .map(|(address, (limit, in_flight))| {
+                            let usage =
+                                serde_json::json!({ "limit": limit, "in_flight": in_flight });
+                            (address, usage)
+                        })
+                        .collect();
+                    (pool.name.clone(), serde_json::Value::Object(upstreams))
+                })
+                .collect();
+            make_json(
+                http::StatusCode::OK,
+                &serde_json::Value::Object(pools).to_string(),
+            )
+        }
+        (&http::Method::GET, "/workers") => {
+            let workers: Vec<serde_json::Value> = state
+                .workers_accepted
+                .iter()
+                .map(|accepted| serde_json::json!({ "accepted": accepted.load(Ordering::Relaxed) }))
+                .collect();
+            make_json(
+                http::StatusCode::OK,
+                &serde_json::Value::Array(workers).to_string(),
+            )
+        }
+        (&http::Method::GET, "/pools") => {
+            let pools: serde_json::Map<String, serde_json::Value> = state
+                .pools
+                .iter()
+                .map(|pool| {
+                    let (requests, errors, latency) = pool.stats.snapshot();
+                    let (error_rate, latency_ms) = if requests == 0 {
+                        (0.0, 0.0)
+                    } else {
+                        (
+                            errors as f64 / requests as f64,
+                            latency.as_secs_f64() * 1000.0 / requests as f64,
+                        )
+                    };
+                    let stats = serde_json::json!({
+                        "requests": requests,
+                        "errors": errors,
+                        "error_rate": error_rate,
+                        "latency_ms": latency_ms,
+                    });
+                    (pool.name.clone(), stats)
+                })
+                .collect();
+            make_json(
+                http::StatusCode::OK,
+                &serde_json::Value::Object(pools).to_string(),
+            )
+        }
+        (&http::Method::GET, "/canaries") => {
+            let canaries: serde_json::Map<String, serde_json::Value> = state
+                .routes()
+                .filter_map(|route| Some((route, route.canary.as_ref()?)))
+                .map(|(route, canary)| {
+                    let split = serde_json::json!({
+                        "pool": route.pool.as_ref().map(|pool| &pool.name),
+                        "percent": canary.percent(),
+                    });
+                    (canary.pool.name.clone(), split)
+                })
+                .collect();
+            make_json(
+                http::StatusCode::OK,
+                &serde_json::Value::Object(canaries).to_string(),
+            )
+        }
+        (&http::Method::POST, "/canaries") => {
+            let (pool, percent) = match (param("pool"), param("percent")) {
+                (Some(pool), Some(percent)) => match percent.parse::<f64>() {
+                    Ok(percent) => (pool, percent),
+                    Err(_) => {
+                        return make_json(
+                            http::StatusCode::BAD_REQUEST,
+                            "{\"error\":\"percent is not a number\"}",
+                        )
+                    }
+                },
+                _ => {
+                    return make_json(
+                        http::StatusCode::BAD_REQUEST,
+                        "{\"error\":\"pool and percent are required\"}",
+                    )
+                }
+            };
+            let canaries: Vec<_> = state
+                .routes()
+                .filter_map(|route| route.canary.as_ref())
+                .filter(|canary| canary.pool.name == pool)
+                .collect();
+            if canaries.is_empty() {
+                return make_json(
+                    http::StatusCode::NOT_FOUND,
+                    "{\"error\":\"no route has that canary pool\"}",
+                );
+            }
+            for canary in &canaries {
+                if let Err(err) = canary.set_percent(percent) {
+                    let error = serde_json::json!({ "error": err });
+                    return make_json(http::StatusCode::BAD_REQUEST, &error.to_string());
+                }
+            }
+            make_json(
+                http::StatusCode::OK,
+                &format!("{{\"routes\":{}}}", canaries.len()),
+            )
+        }
+        (&http::Method::GET, "/mirrors") => {
+            let mut stats: HashMap<String, mirror::Stats> = HashMap::new();
+            for mirror in state.routes().filter_map(|route| route.mirror.as_ref()) {
+                stats
+                    .entry(mirror.pool.name.clone())
+                    .or_default()
+                    .add(&mirror.stats());
+            }
+            let pools: serde_json::Map<String, serde_json::Value> = stats
+                .into_iter()
+                .map(|(pool, stats)| {
+                    let compared = stats.mirrored - stats.failed;
+                    let average_ms = |total: std::time::Duration| {
+                        if compared == 0 {
+                            0.0
+                        } else {
+                            total.as_secs_f64() * 1000.0 / compared as f64
+                        }
+                    };
+                    let stats = serde_json::json!({
+                        "mirrored": stats.mirrored,
+                        "skipped": stats.skipped,
+                        "failed": stats.failed,
+                        "status_mismatches": stats.status_mismatches,
+                        "primary_latency_ms": average_ms(stats.primary_latency),
+                        "shadow_latency_ms": average_ms(stats.shadow_latency),
+                    });
+                    (pool, stats)
+                })
+                .collect();
+            make_json(
+                http::StatusCode::OK,
+                &serde_json::Value::Object(pools).to_string(),
+            )
+        }
+        (&http::Method::GET, "/upstreams") => {
+            let mut pools = serde_json::Map::new();
+            for pool in &state.pools {
+                let upstreams = pool
+                    .upstreams()
+                    .await
+                    .into_iter()
+                    .map(|(endpoint, alive, effective_weight, latency)| {
+                        serde_json::json!({
+                            "address": endpoint.address,
+                            "priority": endpoint.priority,
+                            "weight": endpoint.weight,
+                            "effective_weight": effective_weight,
+                            "alive": alive,
+                            "latency_ms": latency.map(|latency| latency.as_secs_f64() * 1000.0),
+                        })
+                    })
+                    .collect();
+                pools.insert(pool.name.clone(), serde_json::Value::Array(upstreams));
+            }
+            make_json(
+                http::StatusCode::OK,
+                &serde_json::Value::Object(pools).to_string(),
+            )
+        }
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+fn make_json(status: http::StatusCode, body: &str) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body.as_bytes().to_vec())
+        .unwrap()
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+
+use base64::Engine;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::config::AuthConfig;
+use crate::response;
+
+/// Header we tell the upstream who the client authenticated as in. Clients can't set it
+/// themselves: we drop any copy they send on an authenticated route.
+pub const USER_HEADER: &str = "x-authenticated-user";
+
+/// Why a request was refused.
+enum Denied {
+    /// No credentials, or ones we couldn't verify: 401
+    Unauthenticated(&'static str),
+    /// Valid credentials that aren't good for this route: 403
+    Forbidden(&'static str),
+}
+
+/// Checks the credentials on requests to a route.
+pub enum Authenticator {
+    /// HTTP Basic, against users from an htpasswd file
+    Basic {
+        realm: String,
+        users: HashMap<String, String>,
+    },
+    /// A static key in a request header, by the name of whoever it was issued to
+    ApiKey {
+        header: http::HeaderName,
+        keys: HashMap<String, String>,
+    },
+    /// Bearer JWTs signed by one of the keys in a JWKS file
+    Jwt(JwtVerifier),
+}
+
+pub struct JwtVerifier {
+    keys: Vec<JwtKey>,
+    audience: Option<String>,
+    issuer: Option<String>,
+    leeway: u64,
+    /// Claims to forward to the upstream, and the header to forward each one in
+    claim_headers: Vec<(String, http::HeaderName)>,
+}
+
+struct JwtKey {
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+impl Authenticator {
+    /// Sets up the authentication a route's config asks for, reading any files it refers to.
+    pub fn new(config: &AuthConfig) -> Result<Authenticator, String> {
+        match config {
+            AuthConfig::Basic { htpasswd, realm } => Ok(Authenticator::Basic {
+                realm: realm.clone(),
+                users: load_htpasswd(htpasswd)?,
+            }),
+            AuthConfig::ApiKey { header, keys } => Ok(Authenticator::ApiKey {
+                header: http::HeaderName::from_bytes(header.as_bytes())
+                    .map_err(|_| format!("invalid API key header name \"{}\"", header))?,
+                keys: keys.clone(),
+            }),
+            AuthConfig::Jwt {
+                jwks,
+                audience,
+                issuer,
+                leeway,
+                claims,
+            } => Ok(Authenticator::Jwt(JwtVerifier {
+                keys: load_jwks(jwks)?,
+                audience: audience.clone(),
+                issuer: issuer.clone(),
+                leeway: *leeway,
+                claim_headers: claims
+                    .iter()
+                    .map(|(claim, header)| {
+                        let header = http::HeaderName::from_bytes(header.as_bytes())
+                            .map_err(|_| format!("invalid claim header name \"{}\"", header))?;
+                        Ok((claim.clone(), header))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?,
+            })),
+        }
+    }
+
+    /// Verifies the request's credentials. On success, replaces any identity headers the
+    /// client sent with the ones we vouch for; otherwise returns the 401 or 403 to send back.
+    pub async fn authenticate(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Result<(), http::Response<Vec<u8>>> {
+        request.headers_mut().remove(USER_HEADER);
+        if let Authenticator::Jwt(verifier) = self {
+            for (_, header) in &verifier.claim_headers {
+                request.headers_mut().remove(header);
+            }
+        }
+
+        let identity = match self {
+            Authenticator::Basic { users, .. } => check_basic(users, request).await,
+            Authenticator::ApiKey { header, keys } => check_api_key(header, keys, request),
+            Authenticator::Jwt(verifier) => verifier.verify(request),
+        };
+        let headers = match identity {
+            Ok(headers) => headers,
+            Err(Denied::Unauthenticated(reason)) => {
+                log::debug!("Refusing unauthenticated request: {}", reason);
+                let mut response = response::make_http_error(http::StatusCode::UNAUTHORIZED);
+                if let Some(challenge) = self.challenge() {
+                    response
+                        .headers_mut()
+                        .insert(http::header::WWW_AUTHENTICATE, challenge);
+                }
+                return Err(response);
+            }
+            Err(Denied::Forbidden(reason)) => {
+                log::debug!("Refusing authenticated request: {}", reason);
+                return Err(response::make_http_error(http::StatusCode::FORBIDDEN));
+            }
+        };
+        for (name, value) in headers {
+            request.headers_mut().append(name, value);
+        }
+        Ok(())
+    }
+
+    /// What to put in WWW-Authenticate on a 401.
+    fn challenge(&self) -> Option<http::HeaderValue> {
+        match self {
+            Authenticator::Basic { realm, .. } => {
+                let realm = realm.replace(['\\', '"'], "");
+                http::HeaderValue::from_str(&format!("Basic realm=\"{}\"", realm)).ok()
+            }
+            Authenticator::ApiKey { .. } => None,
+            Authenticator::Jwt(_) => Some(http::HeaderValue::from_static("Bearer")),
+        }
+    }
+}
+
+impl JwtVerifier {
+    fn verify(
+        &self,
+        request: &http::Request<Vec<u8>>,
+    ) -> Result<Vec<(http::HeaderName, http::HeaderValue)>, Denied> {
+        let token = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let (scheme, token) = value.split_once(' ')?;
+                scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+            })
+            .ok_or(Denied::Unauthenticated("no bearer token"))?;
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| Denied::Unauthenticated("malformed token"))?;
+
+        // NOTE: the algorithm comes from the key, never just from the token, so a token can't
+        // pick a weaker one (e.g. HS256 keyed with an RSA public key)
+        let claims = self
+            .keys
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| header.kid.is_none() || key.id == header.kid)
+            .find_map(|key| {
+                let mut validation = Validation::new(key.algorithm);
+                validation.leeway = self.leeway;
+                validation.validate_nbf = true;
+                // We check these ourselves, so that a mismatch gets 403 rather than 401
+                validation.validate_aud = false;
+                validation.set_required_spec_claims(&["exp"]);
+                jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+                    token,
+                    &key.key,
+                    &validation,
+                )
+                .ok()
+            })
+            .ok_or(Denied::Unauthenticated("token failed verification"))?
+            .claims;
+
+        if let Some(audience) = &self.audience {
+            let matches = match &claims.get("aud") {
+                Some(serde_json::Value::String(aud)) => aud == audience,
+                Some(serde_json::Value::Array(auds)) => auds
+                    .iter()
+                    .any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !matches {
+                return Err(Denied::Forbidden("token is for another audience"));
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(|iss| iss.as_str()) != Some(issuer.as_str()) {
+                return Err(Denied::Forbidden("token is from another issuer"));
+            }
+        }
+
+        let mut headers = Vec::new();
+        if let Some(subject) = claims.get("sub").and_then(|sub| sub.as_str()) {
+            if let Ok(value) = http::HeaderValue::from_str(subject) {
+                headers.push((http::HeaderName::from_static(USER_HEADER), value));
+            }
+        }
+        for (claim, header) in &self.claim_headers {
+            let value = match claims.get(claim) {
+                Some(serde_json::Value::String(value)) => value.clone(),
+                Some(serde_json::Value::Array(values)) => values
+                    .iter()
+                    .map(|value| match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                Some(value) => value.to_string(),
+                None => continue,
+            };
+            if let Ok(value) = http::HeaderValue::from_str(&value) {
+                headers.push((header.clone(), value));
+            }
+        }
+        Ok(headers)
+    }
+}
+
+async fn check_basic(
+    users: &HashMap<String, String>,
+    request: &http::Request<Vec<u8>>,
+) -> Result<Vec<(http::HeaderName, http::HeaderValue)>, Denied> {
+    let credentials = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            let (scheme, encoded) = value.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("basic").then_some(encoded)
+        })
+        .and_then(|encoded| {
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .ok()
+        })
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or(Denied::Unauthenticated("no Basic credentials"))?;
+    let (user, password) = credentials
+        .split_once(':')
+        .ok_or(Denied::Unauthenticated("malformed Basic credentials"))?;
+    // Compare against every user, and check the password of an unknown one against some other
+    // user's hash, so that timing doesn't reveal which user names exist
+    let mut known = None;
+    for (name, hash) in users {
+        if constant_time_eq(name.as_bytes(), user.as_bytes()) {
+            known = Some(hash);
+        }
+    }
+    let hash = known
+        .or_else(|| users.values().next())
+        .ok_or(Denied::Unauthenticated("unknown user"))?
+        .clone();
+    // bcrypt is deliberately slow; keep it off the threads that serve connections
+    let password = password.to_string();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    if known.is_none() {
+        return Err(Denied::Unauthenticated("unknown user"));
+    }
+    if !verified {
+        return Err(Denied::Unauthenticated("wrong password"));
+    }
+    let user = http::HeaderValue::from_str(user)
+        .map_err(|_| Denied::Unauthenticated("user name can't be forwarded"))?;
+    Ok(vec![(http::HeaderName::from_static(USER_HEADER), user)])
+}
+
+fn check_api_key(
+    header: &http::HeaderName,
+    keys: &HashMap<String, String>,
+    request: &http::Request<Vec<u8>>,
+) -> Result<Vec<(http::HeaderName, http::HeaderValue)>, Denied> {
+    let presented = request
+        .headers()
+        .get(header)
+        .ok_or(Denied::Unauthenticated("no API key"))?;
+    // Compare against every key, so that timing doesn't reveal which one came close
+    let mut owner = None;
+    for (name, key) in keys {
+        if constant_time_eq(key.as_bytes(), presented.as_bytes()) {
+            owner = Some(name);
+        }
+    }
+    let owner = owner.ok_or(Denied::Unauthenticated("unknown API key"))?;
+    let owner = http::HeaderValue::from_str(owner)
+        .map_err(|_| Denied::Unauthenticated("key owner can't be forwarded"))?;
+    Ok(vec![(http::HeaderName::from_static(USER_HEADER), owner)])
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Reads `user:hash` lines from an htpasswd file.
+fn load_htpasswd(path: &str) -> Result<HashMap<String, String>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    let mut users = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (user, hash) = line
+            .split_once(':')
+            .ok_or_else(|| format!("malformed line in {}: {:?}", path, line))?;
+        users.insert(user.to_string(), hash.to_string());
+    }
+    Ok(users)
+}
+
+/// Checks a password against an htpasswd hash: bcrypt ($2y$), Apache MD5 ($apr1$), SHA-1
+/// ({SHA}), or anything crypt(3) understands.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let computed =
+            base64::engine::general_purpose::STANDARD.encode(Sha1::digest(password.as_bytes()));
+        return constant_time_eq(computed.as_bytes(), digest.as_bytes());
+    }
+    if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        let computed = apr1(password.as_bytes(), salt.as_bytes());
+        return constant_time_eq(computed.as_bytes(), hash.as_bytes());
+    }
+    pwhash::unix::verify(password, hash)
+}
+
+/// Apache's variant of MD5-crypt, which is what `htpasswd -m` writes.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let salt = &salt[..salt.len().min(8)];
+
+    let mut alternate = Md5::new();
+    alternate.update(password);
+    alternate.update(salt);
+    alternate.update(password);
+    let alternate = alternate.finalize();
+
+    let mut context = Md5::new();
+    context.update(password);
+    context.update(MAGIC);
+    context.update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        context.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.update([0]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finalize();
+    }
+
+    let mut encoded = String::new();
+    let mut push = |mut value: u32, count: usize| {
+        for _ in 0..count {
+            encoded.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+    format!("$apr1${}${}", String::from_utf8_lossy(salt), encoded)
+}
+
+/// Loads the RS256, ES256 and HS256 keys from a JWKS file, skipping any others.
+fn load_jwks(path: &str) -> Result<Vec<JwtKey>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    let jwks: JwkSet = serde_json::from_str(&contents)
+        .map_err(|err| format!("could not parse {}: {}", path, err))?;
+    let mut keys = Vec::new();
+    for jwk in &jwks.keys {
+        let algorithm = match &jwk.algorithm {
+            AlgorithmParameters::RSA(_) => Algorithm::RS256,
+            AlgorithmParameters::EllipticCurve(params) if params.curve == EllipticCurve::P256 => {
+                Algorithm::ES256
+            }
+            AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+            _ => {
+                log::warn!(
+                    "Skipping unsupported key {:?} in {}",
+                    jwk.common.key_id,
+                    path
+                );
+                continue;
+            }
+        };
+        if let Some(declared) = jwk.common.key_algorithm {
+            let supported = matches!(
+                (declared, algorithm),
+                (KeyAlgorithm::RS256, Algorithm::RS256)
+                    | (KeyAlgorithm::ES256, Algorithm::ES256)
+                    | (KeyAlgorithm::HS256, Algorithm::HS256)
+            );
+            if !supported {
+                log::warn!(
+                    "Skipping key {:?} in {}: only RS256, ES256 and HS256 are supported",
+                    jwk.common.key_id,
+                    path
+                );
+                continue;
+            }
+        }
+        let key = DecodingKey::from_jwk(jwk)
+            .map_err(|err| format!("invalid key {:?} in {}: {}", jwk.common.key_id, path, err))?;
+        keys.push(JwtKey {
+            id: jwk.common.key_id.clone(),
+            algorithm,
+            key,
+        });
+    }
+    if keys.is_empty() {
+        return Err(format!("{} contains no usable keys", path));
+    }
+    Ok(keys)
+}
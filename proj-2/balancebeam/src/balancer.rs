@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How a pool chooses between its live upstreams (within a priority).
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// At random, in proportion to the upstreams' weights
+    Random,
+    /// The less loaded of two upstreams picked at random (by weight), where load is the peak
+    /// EWMA of an upstream's latency times the requests it has in flight
+    Ewma,
+}
+
+/// What a failed request counts as, so that an upstream can't attract traffic by failing fast.
+const FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// What each request in flight to an upstream we have no latency for yet counts as, so that
+/// new upstreams get probed without being flooded.
+const UNMEASURED_PENALTY: Duration = Duration::from_millis(100);
+
+/// Latency estimate for one upstream.
+struct Estimate {
+    /// Peak EWMA of the latency, in seconds (0 until the first response)
+    cost: f64,
+    /// When `cost` was last updated
+    updated: Instant,
+    in_flight: usize,
+    /// The upstream has left its pool, so the estimate goes once its last request finishes
+    removed: bool,
+}
+
+/// Tracks a peak exponentially weighted moving average of each upstream's latency. A response
+/// slower than the average replaces it outright, so that an upstream that starts to struggle is
+/// avoided right away; faster ones only pull it down gradually. Estimates also decay while an
+/// upstream gets no traffic, so that upstreams that were slow get probed again now and then.
+pub struct PeakEwma {
+    /// Time constant of the moving average
+    decay: Duration,
+    estimates: parking_lot::Mutex<HashMap<String, Estimate>>,
+}
+
+/// Held while a request is in flight to an upstream; `finish` it with the upstream's latency.
+pub struct Pending<'a> {
+    ewma: Option<&'a PeakEwma>,
+    address: String,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(ewma) = self.ewma {
+            let mut estimates = ewma.estimates.lock();
+            if let Some(estimate) = estimates.get_mut(&self.address) {
+                estimate.in_flight -= 1;
+                if estimate.removed && estimate.in_flight == 0 {
+                    estimates.remove(&self.address);
+                }
+            }
+        }
+    }
+}
+
+impl Pending<'_> {
+    /// A request that isn't tracked (e.g. because its pool balances at random).
+    pub fn untracked(address: &str) -> Pending<'static> {
+        Pending {
+            ewma: None,
+            address: address.to_string(),
+        }
+    }
+
+    /// Records how long the upstream took to answer, and whether it succeeded.
+    pub fn finish(self, latency: Duration, succeeded: bool) {
+        let ewma = match self.ewma {
+            Some(ewma) => ewma,
+            None => return,
+        };
+        let sample = if succeeded {
+            latency
+        } else {
+            latency.max(FAILURE_PENALTY)
+        };
+        if let Some(estimate) = ewma.estimates.lock().get_mut(&self.address) {
+            ewma.observe(estimate, sample.as_secs_f64());
+        }
+    }
+}
+
+impl PeakEwma {
+    pub fn new(decay: Duration) -> PeakEwma {
+        PeakEwma {
+            decay: decay.max(Duration::from_millis(1)),
+            estimates: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How much of the estimate survives `elapsed` without a new sample.
+    fn weight(&self, elapsed: Duration) -> f64 {
+        (-elapsed.as_secs_f64() / self.decay.as_secs_f64()).exp()
+    }
+
+    fn observe(&self, estimate: &mut Estimate, sample: f64) {
+        let now = Instant::now();
+        if sample > estimate.cost {
+            estimate.cost = sample;
+        } else {
+            let weight = self.weight(now - estimate.updated);
+            estimate.cost = estimate.cost * weight + sample * (1.0 - weight);
+        }
+        estimate.updated = now;
+    }
+
+    /// Counts a request as in flight to `address` until the returned guard is dropped.
+    pub fn start(&self, address: &str) -> Pending<'_> {
+        self.estimates
+            .lock()
+            .entry(address.to_string())
+            .or_insert_with(|| Estimate {
+                cost: 0.0,
+                updated: Instant::now(),
+                in_flight: 0,
+                removed: false,
+            })
+            .in_flight += 1;
+        Pending {
+            ewma: Some(self),
+            address: address.to_string(),
+        }
+    }
+
+    /// The upstream's current latency estimate, decayed for the time it has gone without a
+    /// response.
+    pub fn latency(&self, address: &str) -> Option<Duration> {
+        let estimates = self.estimates.lock();
+        let estimate = estimates.get(address)?;
+        Some(Duration::from_secs_f64(
+            estimate.cost * self.weight(estimate.updated.elapsed()),
+        ))
+    }
+
+    /// How loaded an upstream looks: lower is better.
+    pub fn load(&self, address: &str) -> f64 {
+        let estimates = self.estimates.lock();
+        let estimate = match estimates.get(address) {
+            Some(estimate) => estimate,
+            None => return 0.0,
+        };
+        let cost = estimate.cost * self.weight(estimate.updated.elapsed());
+        if cost == 0.0 {
+            estimate.in_flight as f64 * UNMEASURED_PENALTY.as_secs_f64()
+        } else {
+            cost * (estimate.in_flight + 1) as f64
+        }
+    }
+
+    /// Drops what we know about an upstream that has left its pool, as soon as it has no
+    /// requests in flight.
+    pub fn forget(&self, address: &str) {
+        let mut estimates = self.estimates.lock();
+        match estimates.get_mut(address) {
+            Some(estimate) if estimate.in_flight > 0 => estimate.removed = true,
+            Some(_) => {
+                estimates.remove(address);
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,638 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use http::header;
+
+use crate::auth;
+
+/// Status codes that a shared cache may store (the "heuristically cacheable" codes of RFC 9110
+/// section 15.1). Other responses are always passed through.
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Approximate per-entry bookkeeping overhead counted against the cache size, so that many tiny
+/// responses can't grow the cache without bound.
+const ENTRY_OVERHEAD: usize = 256;
+
+/// The Cache-Control directives the cache acts on (RFC 9111 section 5.2 and RFC 5861).
+#[derive(Default, Debug)]
+pub struct CacheControl {
+    pub max_age: Option<Duration>,
+    pub s_maxage: Option<Duration>,
+    pub stale_while_revalidate: Option<Duration>,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub must_revalidate: bool,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &http::HeaderMap) -> CacheControl {
+        let mut directives = CacheControl::default();
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for directive in value.split(',') {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                    None => (directive, None),
+                };
+                let seconds = argument
+                    .and_then(|argument| argument.parse().ok())
+                    .map(Duration::from_secs);
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "max-age" => directives.max_age = seconds,
+                    "s-maxage" => directives.s_maxage = seconds,
+                    "stale-while-revalidate" => directives.stale_while_revalidate = seconds,
+                    "no-store" => directives.no_store = true,
+                    // The field-list forms (no-cache="Set-Cookie") are treated as the bare
+                    // directive, which is stricter
+                    "no-cache" => directives.no_cache = true,
+                    "private" => directives.private = true,
+                    "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                    _ => {}
+                }
+            }
+        }
+        directives
+    }
+}
+
+/// Where a stored response body lives.
+#[derive(Clone)]
+enum Body {
+    Memory(Vec<u8>),
+    Disk(PathBuf),
+}
+
+struct Entry {
+    /// Request path, used to find entries to purge
+    path: String,
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Body,
+    size: usize,
+    /// When the entry was stored or last revalidated, and how old the response was then
+    stored_at: Instant,
+    initial_age: Duration,
+    freshness_lifetime: Duration,
+    stale_while_revalidate: Duration,
+    /// Set by no-cache: the entry may only be used after a successful revalidation
+    always_revalidate: bool,
+    /// Position in the LRU order
+    last_used: u64,
+    /// A background revalidation is in flight
+    revalidating: bool,
+}
+
+impl Entry {
+    fn current_age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn validators(&self) -> http::HeaderMap {
+        let mut conditional = http::HeaderMap::new();
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            conditional.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            conditional.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        conditional
+    }
+
+    fn snapshot(&self) -> Stored {
+        Stored {
+            status: self.status,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            age: self.current_age(),
+        }
+    }
+}
+
+/// A response copied out of the cache.
+pub struct Stored {
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Body,
+    age: Duration,
+}
+
+impl Stored {
+    /// Builds the response to send for `request`. Returns None if a disk-backed body has gone
+    /// missing, in which case the request should be treated as a miss.
+    pub async fn into_response(
+        self,
+        request: &http::Request<Vec<u8>>,
+        cache_status: &'static str,
+    ) -> Option<http::Response<Vec<u8>>> {
+        let mut headers = self.headers;
+        headers.insert(header::AGE, http::HeaderValue::from(self.age.as_secs()));
+        headers.insert("x-cache", http::HeaderValue::from_static(cache_status));
+
+        // Answer the client's own conditional request if what we have is still what it has
+        if self.status == http::StatusCode::OK && client_has_current_copy(request, &headers) {
+            let mut response = http::Response::builder()
+                .status(http::StatusCode::NOT_MODIFIED)
+                .body(Vec::new())
+                .unwrap();
+            headers.remove(header::CONTENT_LENGTH);
+            *response.headers_mut() = headers;
+            return Some(response);
+        }
+
+        let body = if request.method() == http::Method::HEAD {
+            Vec::new()
+        } else {
+            match self.body {
+                Body::Memory(body) => body,
+                Body::Disk(path) => tokio::fs::read(&path).await.ok()?,
+            }
+        };
+        let mut response = http::Response::builder()
+            .status(self.status)
+            .body(body)
+            .unwrap();
+        *response.headers_mut() = headers;
+        Some(response)
+    }
+}
+
+/// Evaluates If-None-Match / If-Modified-Since from the client against a stored response.
+pub fn client_has_current_copy(request: &http::Request<Vec<u8>>, stored: &http::HeaderMap) -> bool {
+    if let Some(if_none_match) = request.headers().get(header::IF_NONE_MATCH) {
+        let etag = match stored.get(header::ETAG).and_then(|etag| etag.to_str().ok()) {
+            Some(etag) => etag.trim_start_matches("W/"),
+            None => return false,
+        };
+        return if_none_match.to_str().is_ok_and(|candidates| {
+            candidates.split(',').any(|candidate| {
+                let candidate = candidate.trim();
+                candidate == "*" || candidate.trim_start_matches("W/") == etag
+            })
+        });
+    }
+    match (
+        http_date(request.headers(), header::IF_MODIFIED_SINCE),
+        http_date(stored, header::LAST_MODIFIED),
+    ) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+fn http_date(headers: &http::HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+pub enum Lookup {
+    /// A fresh response can be served without contacting the upstream
+    Fresh(Stored),
+    /// A stale response may be served while the entry is refreshed in the background.
+    /// `revalidate` holds the conditional headers to refresh it with, or is None if another
+    /// request is already refreshing it.
+    StaleWhileRevalidate {
+        stored: Stored,
+        revalidate: Option<http::HeaderMap>,
+    },
+    /// There is a stored response, but it must be revalidated first by sending these
+    /// conditional headers to the upstream
+    Revalidate(http::HeaderMap),
+    Miss,
+}
+
+/// Returns true if responses to this request may be served from or stored in the cache.
+/// Requests carrying credentials (or an identity we authenticated) are never cached, since the
+/// cache is shared between clients.
+pub fn is_cacheable_request(request: &http::Request<Vec<u8>>) -> bool {
+    (request.method() == http::Method::GET || request.method() == http::Method::HEAD)
+        && !request.headers().contains_key(header::AUTHORIZATION)
+        && !request.headers().contains_key(auth::USER_HEADER)
+        && !CacheControl::parse(request.headers()).no_store
+}
+
+/// Requests with unsafe methods (POST, PUT, DELETE, ...) invalidate what we have stored for
+/// their URL (RFC 9111 section 4.4).
+pub fn invalidates(request: &http::Request<Vec<u8>>) -> bool {
+    !request.method().is_safe()
+}
+
+/// Identifies the resource a request is for, regardless of the headers named by Vary. `scope`
+/// says where the request was sent (see Cache), since the same URL can be served by different
+/// upstreams on different listeners or routes.
+pub fn primary_key(scope: &str, request: &http::Request<Vec<u8>>) -> String {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        })
+        .unwrap_or("");
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    format!("{} {}{}", scope, host.to_ascii_lowercase(), path)
+}
+
+fn variant_key(
+    primary: &str,
+    vary: &[header::HeaderName],
+    request: &http::Request<Vec<u8>>,
+) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        key.push('\n');
+        key.push_str(name.as_str());
+        key.push(':');
+        for value in request.headers().get_all(name) {
+            key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            key.push(',');
+        }
+    }
+    key
+}
+
+/// Returns the header names a response varies on, or None if it varies on "*" and can never be
+/// matched.
+fn vary_names(response: &http::Response<Vec<u8>>) -> Option<Vec<header::HeaderName>> {
+    let mut names = Vec::new();
+    for value in response.headers().get_all(header::VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = header::HeaderName::from_bytes(name.as_bytes()) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    Some(names)
+}
+
+/// Works out how long a response may be served from cache, or None if it must not be stored.
+fn freshness(response: &http::Response<Vec<u8>>) -> Option<(CacheControl, Duration)> {
+    if !CACHEABLE_STATUSES.contains(&response.status().as_u16()) {
+        return None;
+    }
+    let directives = CacheControl::parse(response.headers());
+    if directives.no_store
+        || directives.private
+        || response.headers().contains_key(header::SET_COOKIE)
+    {
+        return None;
+    }
+    let has_validators = response.headers().contains_key(header::ETAG)
+        || response.headers().contains_key(header::LAST_MODIFIED);
+    let lifetime = if let Some(s_maxage) = directives.s_maxage {
+        s_maxage
+    } else if let Some(max_age) = directives.max_age {
+        max_age
+    } else if let Some(expires) = response.headers().get(header::EXPIRES) {
+        // An invalid Expires (commonly "0") means the response is already stale
+        let date = http_date(response.headers(), header::DATE).unwrap_or_else(SystemTime::now);
+        expires
+            .to_str()
+            .ok()
+            .and_then(|expires| httpdate::parse_http_date(expires).ok())
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default()
+    } else if has_validators {
+        // No explicit lifetime, but we can still save bandwidth by revalidating
+        Duration::ZERO
+    } else {
+        return None;
+    };
+    if directives.no_cache && !has_validators {
+        return None;
+    }
+    Some((directives, lifetime))
+}
+
+/// How old a response already was when we received it (RFC 9111 section 4.2.3).
+fn initial_age(headers: &http::HeaderMap) -> Duration {
+    let age = headers
+        .get(header::AGE)
+        .and_then(|age| age.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let apparent_age = http_date(headers, header::DATE)
+        .and_then(|date| SystemTime::now().duration_since(date).ok())
+        .unwrap_or_default();
+    age.max(apparent_age)
+}
+
+/// What the cache knows about a resource (identified by its primary key).
+struct Resource {
+    /// Header names the resource's responses vary on
+    vary: Vec<header::HeaderName>,
+    /// Number of stored variants
+    entries: usize,
+}
+
+struct Inner {
+    resources: HashMap<String, Resource>,
+    /// Stored responses, by variant key
+    entries: HashMap<String, Entry>,
+    /// Variant keys ordered from least to most recently used
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+    size: usize,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = clock;
+            self.lru.insert(clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.lru.remove(&entry.last_used);
+        self.size -= entry.size;
+        let primary = key.split('\n').next().unwrap();
+        if let Some(resource) = self.resources.get_mut(primary) {
+            resource.entries -= 1;
+            if resource.entries == 0 {
+                self.resources.remove(primary);
+            }
+        }
+        if let Body::Disk(path) = entry.body {
+            let _ = std::fs::remove_file(path);
+        }
+        true
+    }
+
+    fn find(&self, scope: &str, request: &http::Request<Vec<u8>>) -> Option<String> {
+        let primary = primary_key(scope, request);
+        let vary = &self.resources.get(&primary)?.vary;
+        let key = variant_key(&primary, vary, request);
+        self.entries.contains_key(&key).then_some(key)
+    }
+}
+
+/// An HTTP cache shared by all clients (RFC 9111), holding up to `max_size` bytes of responses
+/// and evicting the least recently used ones when full. Every operation takes a scope naming the
+/// listener and pool a request went to; entries stored under one scope are never found under
+/// another.
+pub struct Cache {
+    max_size: usize,
+    max_object_size: usize,
+    /// If set, response bodies are kept in files here rather than in memory
+    disk_dir: Option<PathBuf>,
+    next_file_id: std::sync::atomic::AtomicU64,
+    inner: parking_lot::Mutex<Inner>,
+}
+
+impl Cache {
+    pub fn new(
+        max_size: usize,
+        max_object_size: usize,
+        disk_dir: Option<PathBuf>,
+    ) -> Result<Cache, std::io::Error> {
+        if let Some(dir) = &disk_dir {
+            std::fs::create_dir_all(dir)?;
+            // Entries a previous run left behind are unindexed. Only remove those: the directory
+            // is the operator's, and may hold other things
+            for file in std::fs::read_dir(dir)? {
+                let file = file?;
+                if file.file_name().to_string_lossy().starts_with("entry-")
+                    && file.file_type()?.is_file()
+                {
+                    std::fs::remove_file(file.path())?;
+                }
+            }
+        }
+        Ok(Cache {
+            max_size,
+            max_object_size,
+            disk_dir,
+            next_file_id: std::sync::atomic::AtomicU64::new(0),
+            inner: parking_lot::Mutex::new(Inner {
+                resources: HashMap::new(),
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                size: 0,
+            }),
+        })
+    }
+
+    pub fn lookup(&self, scope: &str, request: &http::Request<Vec<u8>>) -> Lookup {
+        let mut inner = self.inner.lock();
+        let key = match inner.find(scope, request) {
+            Some(key) => key,
+            None => return Lookup::Miss,
+        };
+        inner.touch(&key);
+        let entry = inner.entries.get_mut(&key).unwrap();
+
+        let request_directives = CacheControl::parse(request.headers());
+        let client_wants_revalidation = request_directives.no_cache
+            || request_directives.max_age == Some(Duration::ZERO)
+            || request
+                .headers()
+                .get(header::PRAGMA)
+                .is_some_and(|pragma| pragma == "no-cache");
+        let age = entry.current_age();
+        if !client_wants_revalidation && !entry.always_revalidate {
+            if age < entry.freshness_lifetime {
+                return Lookup::Fresh(entry.snapshot());
+            }
+            if age < entry.freshness_lifetime + entry.stale_while_revalidate {
+                let revalidate = (!entry.revalidating).then(|| entry.validators());
+                entry.revalidating = true;
+                return Lookup::StaleWhileRevalidate {
+                    stored: entry.snapshot(),
+                    revalidate,
+                };
+            }
+        }
+        let conditional = entry.validators();
+        if conditional.is_empty() {
+            Lookup::Miss
+        } else {
+            Lookup::Revalidate(conditional)
+        }
+    }
+
+    /// Stores the response to a GET request if it is cacheable.
+    pub async fn store(
+        &self,
+        scope: &str,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        if request.method() != http::Method::GET || !is_cacheable_request(request) {
+            return;
+        }
+        let (directives, freshness_lifetime) = match freshness(response) {
+            Some(freshness) => freshness,
+            None => return,
+        };
+        let vary = match vary_names(response) {
+            Some(vary) => vary,
+            None => return,
+        };
+        let size = response.body().len() + ENTRY_OVERHEAD;
+        if size > self.max_object_size || size > self.max_size {
+            return;
+        }
+
+        let body = match &self.disk_dir {
+            Some(dir) => {
+                let id = self
+                    .next_file_id
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let path = dir.join(format!("entry-{}", id));
+                if let Err(err) = tokio::fs::write(&path, response.body()).await {
+                    log::warn!("Could not write cache file {:?}: {}", path, err);
+                    return;
+                }
+                Body::Disk(path)
+            }
+            None => Body::Memory(response.body().clone()),
+        };
+
+        let primary = primary_key(scope, request);
+        let key = variant_key(&primary, &vary, request);
+        let entry = Entry {
+            path: request.uri().path().to_string(),
+            status: response.status(),
+            headers: response.headers().clone(),
+            body,
+            size,
+            stored_at: Instant::now(),
+            initial_age: initial_age(response.headers()),
+            freshness_lifetime,
+            stale_while_revalidate: if directives.must_revalidate {
+                Duration::ZERO
+            } else {
+                directives.stale_while_revalidate.unwrap_or_default()
+            },
+            always_revalidate: directives.no_cache,
+            last_used: 0,
+            revalidating: false,
+        };
+
+        let mut inner = self.inner.lock();
+        if inner
+            .resources
+            .get(&primary)
+            .is_some_and(|resource| resource.vary != vary)
+        {
+            // The resource's Vary changed, so previously stored variants can no longer be found
+            let stale_keys: Vec<String> = inner
+                .entries
+                .keys()
+                .filter(|existing| existing.split('\n').next() == Some(primary.as_str()))
+                .cloned()
+                .collect();
+            for stale_key in stale_keys {
+                inner.remove(&stale_key);
+            }
+        }
+        inner.remove(&key);
+        inner
+            .resources
+            .entry(primary)
+            .or_insert(Resource { vary, entries: 0 })
+            .entries += 1;
+        inner.size += entry.size;
+        inner.entries.insert(key.clone(), entry);
+        inner.touch(&key);
+        while inner.size > self.max_size {
+            let oldest = match inner.lru.iter().next() {
+                Some((_, oldest)) => oldest.clone(),
+                None => break,
+            };
+            log::debug!("Evicting {} from the cache", oldest.replace('\n', " "));
+            inner.remove(&oldest);
+        }
+    }
+
+    /// Applies a 304 Not Modified received while revalidating: the stored entry is updated with
+    /// the new headers and becomes fresh again. Returns None if the entry is gone.
+    pub fn freshen(
+        &self,
+        scope: &str,
+        request: &http::Request<Vec<u8>>,
+        not_modified: &http::Response<Vec<u8>>,
+    ) -> Option<Stored> {
+        let mut inner = self.inner.lock();
+        let key = inner.find(scope, request)?;
+        inner.touch(&key);
+        let entry = inner.entries.get_mut(&key).unwrap();
+        for name in not_modified.headers().keys() {
+            if name == header::CONTENT_LENGTH {
+                continue;
+            }
+            entry.headers.remove(name);
+            for value in not_modified.headers().get_all(name) {
+                entry.headers.append(name.clone(), value.clone());
+            }
+        }
+        let mut refreshed = http::Response::new(Vec::new());
+        *refreshed.status_mut() = entry.status;
+        *refreshed.headers_mut() = entry.headers.clone();
+        if let Some((directives, lifetime)) = freshness(&refreshed) {
+            entry.freshness_lifetime = lifetime;
+            entry.always_revalidate = directives.no_cache;
+        }
+        entry.stored_at = Instant::now();
+        entry.initial_age = initial_age(&entry.headers);
+        entry.revalidating = false;
+        Some(entry.snapshot())
+    }
+
+    /// Lets another request start a background revalidation after one failed.
+    pub fn revalidation_failed(&self, scope: &str, request: &http::Request<Vec<u8>>) {
+        let mut inner = self.inner.lock();
+        if let Some(key) = inner.find(scope, request) {
+            inner.entries.get_mut(&key).unwrap().revalidating = false;
+        }
+    }
+
+    /// Drops every stored variant of the resource a request is for.
+    pub fn invalidate(&self, scope: &str, request: &http::Request<Vec<u8>>) {
+        let primary = primary_key(scope, request);
+        self.purge(|key, _| key.split('\n').next() == Some(primary.as_str()));
+    }
+
+    /// Removes all entries whose variant key and request path satisfy `filter`, returning how
+    /// many were removed.
+    pub fn purge<F>(&self, filter: F) -> usize
+    where
+        F: Fn(&str, &str) -> bool,
+    {
+        let mut inner = self.inner.lock();
+        let keys: Vec<String> = inner
+            .entries
+            .iter()
+            .filter(|(key, entry)| filter(key, &entry.path))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            inner.remove(key);
+        }
+        keys.len()
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::config::{CanaryConfig, CanaryMatchConfig};
+use crate::pool::Pool;
+
+/// Sends a share of a route's requests, and any that ask for it, to a canary pool instead of
+/// the route's own pool.
+pub struct Canary {
+    pub pool: Arc<Pool>,
+    /// Percentage of requests to send to the canary; the admin interface can change it
+    percent: parking_lot::Mutex<f64>,
+    /// Requests with this header value always go to the canary
+    header: Option<(http::HeaderName, String)>,
+    /// Requests with this cookie value always go to the canary
+    cookie: Option<(String, String)>,
+}
+
+impl Canary {
+    pub fn new(
+        config: &CanaryConfig,
+        pools: &HashMap<String, Arc<Pool>>,
+    ) -> Result<Canary, String> {
+        let pool = pools
+            .get(&config.pool)
+            .ok_or_else(|| format!("route has unknown canary pool \"{}\"", config.pool))?
+            .clone();
+        check_percent(config.percent)?;
+        let header = match &config.header {
+            Some(CanaryMatchConfig { name, value }) => Some((
+                http::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("invalid canary header name \"{}\"", name))?,
+                value.clone(),
+            )),
+            None => None,
+        };
+        Ok(Canary {
+            pool,
+            percent: parking_lot::Mutex::new(config.percent),
+            header,
+            cookie: config
+                .cookie
+                .as_ref()
+                .map(|cookie| (cookie.name.clone(), cookie.value.clone())),
+        })
+    }
+
+    pub fn percent(&self) -> f64 {
+        *self.percent.lock()
+    }
+
+    pub fn set_percent(&self, percent: f64) -> Result<(), String> {
+        check_percent(percent)?;
+        log::info!(
+            "Sending {}% of requests to canary pool {}",
+            percent,
+            self.pool.name
+        );
+        *self.percent.lock() = percent;
+        Ok(())
+    }
+
+    /// Decides whether a request goes to the canary.
+    pub fn chooses(&self, request: &http::Request<Vec<u8>>) -> bool {
+        if let Some((name, value)) = &self.header {
+            if request
+                .headers()
+                .get_all(name)
+                .iter()
+                .any(|header| header.as_bytes() == value.as_bytes())
+            {
+                return true;
+            }
+        }
+        if let Some((name, value)) = &self.cookie {
+            let has_cookie = request
+                .headers()
+                .get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|header| header.to_str().ok())
+                .flat_map(|header| header.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .any(|(cookie_name, cookie_value)| cookie_name == name && cookie_value == value);
+            if has_cookie {
+                return true;
+            }
+        }
+        let percent = self.percent();
+        percent > 0.0 && rand::thread_rng().gen_bool(percent / 100.0)
+    }
+}
+
+fn check_percent(percent: f64) -> Result<(), String> {
+    if (0.0..=100.0).contains(&percent) {
+        Ok(())
+    } else {
+        Err(format!(
+            "canary percentage {} is not between 0 and 100",
+            percent
+        ))
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// When to trip a circuit, and how to test whether the upstream has recovered.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How far back we look when computing an upstream's failure rate
+    pub window: Duration,
+    /// Don't judge an upstream on fewer requests than this
+    pub min_requests: usize,
+    /// Percentage of failed requests within the window that opens the circuit
+    pub failure_rate: u32,
+    /// Requests that take longer than this count as failures too (if set)
+    pub slow_call: Option<Duration>,
+    /// How long an open circuit rejects requests before letting probes through
+    pub open_time: Duration,
+    /// How many probes a half-open circuit lets through; all of them have to succeed for the
+    /// circuit to close
+    pub probes: usize,
+}
+
+enum State {
+    /// Requests flow normally; we keep (when, failed) for each one in the window
+    Closed { outcomes: VecDeque<(Instant, bool)> },
+    /// Nothing goes to the upstream until `until`
+    Open { until: Instant },
+    /// A few probe requests are let through to see whether the upstream has recovered
+    HalfOpen {
+        started: usize,
+        succeeded: usize,
+        since: Instant,
+    },
+}
+
+/// A circuit breaker for every upstream of a pool.
+pub struct Breakers {
+    /// None if circuit breaking is disabled for the pool
+    config: Option<Config>,
+    states: parking_lot::Mutex<HashMap<String, State>>,
+}
+
+impl Breakers {
+    pub fn new(config: Option<Config>) -> Breakers {
+        Breakers {
+            config,
+            states: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Decides whether a request may go to `address`. If its circuit is half-open, this takes
+    /// up one of its probe slots, so only call it for an upstream we're about to use.
+    pub fn admit(&self, address: &str) -> bool {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return true,
+        };
+        let mut states = self.states.lock();
+        let state = match states.get_mut(address) {
+            Some(state) => state,
+            None => return true,
+        };
+        let now = Instant::now();
+        match state {
+            State::Closed { .. } => true,
+            State::Open { until } if now < *until => false,
+            State::Open { .. } => {
+                log::info!("Circuit for {} is half-open; probing it", address);
+                *state = State::HalfOpen {
+                    started: 1,
+                    succeeded: 0,
+                    since: now,
+                };
+                true
+            }
+            State::HalfOpen { started, since, .. } => {
+                // NOTE: a probe that was admitted but never sent (e.g. shed for overload) would
+                // otherwise hold its slot forever
+                if now.duration_since(*since) > config.open_time {
+                    *started = 0;
+                    *since = now;
+                }
+                if *started < config.probes {
+                    *started += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Drops what we know about an upstream that has gone away, so that a new upstream that
+    /// later turns up at the same address starts with a closed circuit.
+    pub fn forget(&self, address: &str) {
+        self.states.lock().remove(address);
+    }
+
+    /// Records how a request to `address` went.
+    pub fn record(&self, address: &str, latency: Duration, succeeded: bool) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+        let failed = !succeeded || config.slow_call.is_some_and(|slow| latency > slow);
+        let now = Instant::now();
+        let mut states = self.states.lock();
+        let state = states
+            .entry(address.to_string())
+            .or_insert_with(|| State::Closed {
+                outcomes: VecDeque::new(),
+            });
+        match state {
+            State::Closed { outcomes } => {
+                outcomes.push_back((now, failed));
+                while outcomes
+                    .front()
+                    .is_some_and(|(when, _)| now.duration_since(*when) > config.window)
+                {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|(_, failed)| *failed).count();
+                if outcomes.len() >= config.min_requests
+                    && failures * 100 >= outcomes.len() * config.failure_rate as usize
+                {
+                    log::warn!(
+                        "Opening circuit for {}: {} of its last {} requests failed",
+                        address,
+                        failures,
+                        outcomes.len()
+                    );
+                    *state = State::Open {
+                        until: now + config.open_time,
+                    };
+                }
+            }
+            // Requests that were sent before the circuit opened don't tell us anything new
+            State::Open { .. } => {}
+            State::HalfOpen { succeeded, .. } => {
+                if failed {
+                    log::warn!("Probe to {} failed; reopening its circuit", address);
+                    *state = State::Open {
+                        until: now + config.open_time,
+                    };
+                } else {
+                    *succeeded += 1;
+                    if *succeeded >= config.probes {
+                        log::info!(
+                            "Upstream {} passed its probes; closing its circuit",
+                            address
+                        );
+                        *state = State::Closed {
+                            outcomes: VecDeque::new(),
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
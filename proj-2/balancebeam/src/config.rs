@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::balancer::Algorithm;
+use crate::upstream::Protocol;
+
+/// Contents of the file passed with --config. The file may be written in YAML or JSON (JSON is a
+/// subset of YAML). Upstreams given with --upstream form an extra pool named "default", which
+/// also serves any request that no configured route matches.
+///
+/// ```yaml
+/// pools:
+///   greeter:
+///     upstreams: ["10.0.0.1:50051", "10.0.0.2:50051"]
+///     protocol: http2
+///     health_check: { type: grpc, service: helloworld.Greeter }
+/// routes:
+///   - grpc_service: helloworld.Greeter
+///     pool: greeter
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub pools: HashMap<String, PoolConfig>,
+    /// Routes are tried in order; the first one that matches a request wins
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Pages for the errors balancebeam itself responds with, by status code or class
+    #[serde(default)]
+    pub error_pages: HashMap<String, ErrorPageConfig>,
+    /// Addresses to listen on besides --bind, each with its own routes and limits
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// An address to listen on, sharing the pools (and their health) with every other listener.
+/// Limits left unset fall back to the command-line options, and a listener without `routes`
+/// uses the top-level ones.
+///
+/// ```yaml
+/// listeners:
+///   - bind: 0.0.0.0:443
+///     tls: { cert: /etc/balancebeam/cert.pem, key: /etc/balancebeam/key.pem }
+///     max_connections: 10000
+///   - bind: 127.0.0.1:8080
+///     routes:
+///       - { path_prefix: /internal/, pool: internal }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// IP/port, or unix:<path>
+    pub bind: String,
+    /// Terminates TLS on the listener's connections
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Routes for the listener's requests, instead of the top-level ones
+    #[serde(default)]
+    pub routes: Option<Vec<RouteConfig>>,
+    /// Overrides --max-connections for this listener
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Overrides --max-connections-per-ip for this listener
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Overrides --max-requests-per-minute for this listener
+    #[serde(default)]
+    pub max_requests_per_minute: Option<usize>,
+    /// Overrides --max-requests-per-connection for this listener
+    #[serde(default)]
+    pub max_requests_per_connection: Option<usize>,
+    /// Overrides --max-body-size for this listener (routes can still set their own)
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: String,
+    /// PEM private key matching `cert`
+    pub key: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Each one is an IP address and port, a hostname and port (an upstream per address it
+    /// resolves to) or srv+<name> (an upstream per address of each of its SRV records' targets)
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    /// Where to discover the pool's upstreams from, instead of listing them in `upstreams`
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    #[serde(default = "default_protocol")]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    /// Overrides --max-upstream-requests for this pool
+    #[serde(default)]
+    pub max_upstream_requests: Option<usize>,
+    /// Overrides --adaptive-concurrency for this pool
+    #[serde(default)]
+    pub adaptive_concurrency: Option<bool>,
+    /// Overrides --circuit-breaker for this pool
+    #[serde(default)]
+    pub circuit_breaker: Option<bool>,
+    /// Overrides --slow-start for this pool
+    #[serde(default)]
+    pub slow_start: Option<u64>,
+    /// Overrides --balancer for this pool
+    #[serde(default)]
+    pub balancer: Option<Algorithm>,
+}
+
+/// How active health checks probe the upstreams of a pool.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum HealthCheckConfig {
+    /// Send a GET request for `path` and expect a non-500 response
+    Http { path: String },
+    /// Call grpc.health.v1.Health/Check for `service` (empty = the server as a whole) and
+    /// expect SERVING
+    Grpc {
+        #[serde(default)]
+        service: String,
+    },
+}
+
+/// A source of upstreams that can change while balancebeam runs. Either way, the source is a
+/// JSON or YAML list of upstreams, each an IP address and port or an object with an `address`
+/// and optionally a `weight` and `priority`:
+///
+/// ```yaml
+/// discovery: { type: file, path: /etc/balancebeam/api-upstreams.yaml }
+/// discovery: { type: http, url: "http://registry.internal/pools/api", interval: 5 }
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum DiscoveryConfig {
+    /// Read the list from a file, and again whenever it changes
+    File { path: String },
+    /// Fetch the list with a GET request (plain HTTP only) every `interval` seconds
+    Http {
+        url: String,
+        #[serde(default = "default_discovery_interval")]
+        interval: u64,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Matches request paths starting with this prefix
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Matches gRPC calls to this fully-qualified service (e.g. "helloworld.Greeter")
+    #[serde(default)]
+    pub grpc_service: Option<String>,
+    /// Narrows a grpc_service route down to a single method (e.g. "SayHello")
+    #[serde(default)]
+    pub grpc_method: Option<String>,
+    /// Where to send the route's requests; a route without a pool needs static_files
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Serves the route's requests from a directory instead of a pool
+    #[serde(default)]
+    pub static_files: Option<StaticFilesConfig>,
+    /// If non-empty, only clients in these networks (e.g. "10.0.0.0/8") may use the route
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Clients in these networks get 403 Forbidden, even if `allow` lists them
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// If set, requests with other methods get 405 Method Not Allowed
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    /// Requests matching any of these rules are rejected
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// Credentials clients must present to use the route
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Copies some of the route's requests to a shadow pool
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// Sends some of the route's requests to a canary pool instead of `pool`
+    #[serde(default)]
+    pub canary: Option<CanaryConfig>,
+    /// Overrides --max-body-size for requests on this route
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    /// Error pages for the route, used in preference to the global ones
+    #[serde(default)]
+    pub error_pages: HashMap<String, ErrorPageConfig>,
+}
+
+/// Files to serve a path_prefix route from. The rest of the request path after the prefix is
+/// looked up under `root`; requests for a directory get the first of its `index` files that
+/// exists. Responses are read into memory, so none may carry more than `max_size` bytes (a
+/// client can still fetch a larger file a range at a time).
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFilesConfig {
+    pub root: String,
+    #[serde(default = "default_index")]
+    pub index: Vec<String>,
+    #[serde(default = "default_max_file_size")]
+    pub max_size: usize,
+}
+
+/// Template files for an error page, in either or both of HTML and JSON (clients that accept
+/// JSON but not HTML get the latter). `{{status}}`, `{{reason}}` and `{{request_id}}` in them are
+/// replaced with the response's status code, reason phrase and X-Request-Id.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPageConfig {
+    #[serde(default)]
+    pub html: Option<String>,
+    #[serde(default)]
+    pub json: Option<String>,
+}
+
+/// Shifts traffic between two versions of a service. `percent` of the route's requests (which
+/// can be changed at runtime with the admin interface), plus any carrying the given header or
+/// cookie, go to the canary pool.
+///
+/// ```yaml
+/// canary:
+///   pool: api-v2
+///   percent: 5
+///   header: { name: X-Canary, value: always }
+///   cookie: { name: canary, value: always }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryConfig {
+    pub pool: String,
+    #[serde(default)]
+    pub percent: f64,
+    #[serde(default)]
+    pub header: Option<CanaryMatchConfig>,
+    #[serde(default)]
+    pub cookie: Option<CanaryMatchConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryMatchConfig {
+    pub name: String,
+    pub value: String,
+}
+
+/// Sends a copy of a share of a route's requests to another pool (e.g. a new version of a
+/// backend that isn't live yet). Its responses are discarded, but how their status and latency
+/// compare to the real responses is reported on the admin interface. Copies go out without the
+/// client's credentials unless `forward_credentials` is set.
+///
+/// ```yaml
+/// mirror: { pool: api-next, percent: 10 }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub pool: String,
+    #[serde(default = "default_mirror_percent")]
+    pub percent: f64,
+    #[serde(default)]
+    pub forward_credentials: bool,
+}
+
+/// How clients authenticate to a route. Whoever they authenticate as is forwarded to the
+/// upstream in X-Authenticated-User.
+///
+/// ```yaml
+/// auth: { type: basic, htpasswd: /etc/balancebeam/htpasswd }
+/// auth: { type: api_key, keys: { ci: "0123456789abcdef" } }
+/// auth:
+///   type: jwt
+///   jwks: /etc/balancebeam/jwks.json
+///   audience: https://api.example.com
+///   claims: { email: X-User-Email }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AuthConfig {
+    /// HTTP Basic, checked against an htpasswd file (bcrypt, apr1, SHA-1 or crypt hashes)
+    Basic {
+        htpasswd: String,
+        #[serde(default = "default_realm")]
+        realm: String,
+    },
+    /// A static key sent in a header
+    ApiKey {
+        #[serde(default = "default_api_key_header")]
+        header: String,
+        /// Keys by the name of whoever they were issued to
+        keys: HashMap<String, String>,
+    },
+    /// Bearer tokens signed by a key in a JWKS file, using RS256, ES256 or HS256
+    Jwt {
+        jwks: String,
+        /// If set, the token's aud claim must include it
+        #[serde(default)]
+        audience: Option<String>,
+        /// If set, the token's iss claim must match it
+        #[serde(default)]
+        issuer: Option<String>,
+        /// Seconds of clock skew to tolerate when checking exp and nbf
+        #[serde(default = "default_leeway")]
+        leeway: u64,
+        /// Claims to forward to the upstream, and the header to forward each one in
+        #[serde(default)]
+        claims: HashMap<String, String>,
+    },
+}
+
+/// Rejects requests whose target (path and query, percent-decoded) or headers match regular
+/// expressions.
+///
+/// ```yaml
+/// rules:
+///   - { path: "\\.\\./", status: 400 }
+///   - { header: { name: User-Agent, pattern: "(?i)sqlmap" } }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub header: Option<HeaderRuleConfig>,
+    /// Status to reject matching requests with
+    #[serde(default = "default_rule_status")]
+    pub status: u16,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRuleConfig {
+    pub name: String,
+    pub pattern: String,
+}
+
+fn default_protocol() -> Protocol {
+    Protocol::Http1
+}
+
+fn default_discovery_interval() -> u64 {
+    10
+}
+
+fn default_mirror_percent() -> f64 {
+    100.0
+}
+
+fn default_rule_status() -> u16 {
+    403
+}
+
+fn default_realm() -> String {
+    "balancebeam".to_string()
+}
+
+fn default_api_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+fn default_index() -> Vec<String> {
+    vec!["index.html".to_string()]
+}
+
+fn default_max_file_size() -> usize {
+    100000000
+}
+
+pub fn load(path: &str) -> Result<Config, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    serde_yaml::from_str(&contents).map_err(|err| format!("could not parse {}: {}", path, err))
+}
@@ -0,0 +1,263 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::Watcher;
+use serde::Deserialize;
+
+use crate::config::DiscoveryConfig;
+use crate::pool::{Endpoint, Pool};
+use crate::{socket, upstream};
+
+/// Editors often write a file in several steps, so we wait this long after a change for the
+/// rest of them before reloading it.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// How long to wait for a discovery endpoint to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One upstream in a discovered list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Address(String),
+    Detailed {
+        address: String,
+        #[serde(default = "default_weight")]
+        weight: u16,
+        #[serde(default)]
+        priority: u16,
+    },
+}
+
+fn default_weight() -> u16 {
+    1
+}
+
+/// Where a pool's upstreams come from, if they can change while we run.
+pub enum Source {
+    File(PathBuf),
+    Http {
+        /// host:port to connect to
+        address: String,
+        /// What to send as the Host header
+        host: String,
+        path_and_query: String,
+        interval: Duration,
+    },
+}
+
+impl Source {
+    pub fn new(config: &DiscoveryConfig) -> Result<Source, String> {
+        match config {
+            DiscoveryConfig::File { path } => Ok(Source::File(PathBuf::from(path))),
+            DiscoveryConfig::Http { url, interval } => {
+                let uri: http::Uri = url
+                    .parse()
+                    .map_err(|_| format!("invalid discovery URL \"{}\"", url))?;
+                if uri.scheme_str() != Some("http") {
+                    return Err(format!("discovery URL \"{}\" must use http://", url));
+                }
+                let authority = uri
+                    .authority()
+                    .ok_or_else(|| format!("discovery URL \"{}\" has no host", url))?;
+                Ok(Source::Http {
+                    address: format!(
+                        "{}:{}",
+                        authority.host(),
+                        authority.port_u16().unwrap_or(80)
+                    ),
+                    host: authority.to_string(),
+                    path_and_query: uri
+                        .path_and_query()
+                        .map_or("/", |path_and_query| path_and_query.as_str())
+                        .to_string(),
+                    interval: Duration::from_secs((*interval).max(1)),
+                })
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Source::File(path) => path.display().to_string(),
+            Source::Http {
+                host,
+                path_and_query,
+                ..
+            } => format!("http://{}{}", host, path_and_query),
+        }
+    }
+
+    /// Reads the current list of upstreams from the source.
+    async fn load(&self) -> Result<Vec<Endpoint>, String> {
+        let contents = match self {
+            Source::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .map_err(|err| err.to_string())?,
+            Source::Http {
+                address,
+                host,
+                path_and_query,
+                ..
+            } => tokio::time::timeout(FETCH_TIMEOUT, fetch(address, host, path_and_query))
+                .await
+                .map_err(|_| "timed out".to_string())??,
+        };
+        parse(&contents)
+    }
+}
+
+async fn fetch(address: &str, host: &str, path_and_query: &str) -> Result<String, String> {
+    let mut connection = upstream::Connection::connect(address, upstream::Protocol::Http1)
+        .await
+        .map_err(|err| format!("could not connect: {}", err))?;
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path_and_query)
+        .header("Host", host)
+        .header("Accept", "application/json")
+        .body(Vec::new())
+        .unwrap();
+    let response = connection
+        .send(request, address)
+        .await
+        .map_err(|err| format!("could not read the response: {:?}", err))?;
+    if !response.status().is_success() {
+        return Err(format!("got {}", response.status()));
+    }
+    Ok(String::from_utf8_lossy(response.body()).into_owned())
+}
+
+/// Parses a JSON or YAML list of upstreams.
+fn parse(contents: &str) -> Result<Vec<Endpoint>, String> {
+    let entries: Vec<Entry> = serde_yaml::from_str(contents).map_err(|err| err.to_string())?;
+    let mut endpoints: Vec<Endpoint> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (address, weight, priority) = match entry {
+            Entry::Address(address) => (address, 1, 0),
+            Entry::Detailed {
+                address,
+                weight,
+                priority,
+            } => (address, weight, priority),
+        };
+        // Normalize the address, so that the same upstream always goes by the same name
+        let address = if socket::unix_path(&address).is_some() {
+            address
+        } else {
+            address
+                .parse::<SocketAddr>()
+                .map_err(|_| format!("\"{}\" is not an IP address and port", address))?
+                .to_string()
+        };
+        if endpoints.iter().any(|endpoint| endpoint.address == address) {
+            continue;
+        }
+        endpoints.push(Endpoint {
+            address,
+            priority,
+            weight,
+        });
+    }
+    Ok(endpoints)
+}
+
+/// Loads a pool's upstreams from its source and applies them, keeping the ones it has if that
+/// fails.
+async fn refresh(pool: &Pool, source: &Source) {
+    match source.load().await {
+        Ok(endpoints) => pool.set_endpoints(endpoints).await,
+        Err(err) => log::warn!(
+            "Keeping the upstreams of pool {}: could not load {}: {}",
+            pool.name,
+            source.describe(),
+            err
+        ),
+    }
+}
+
+/// Reloads a pool's upstreams whenever the file they are listed in changes.
+fn watch_file(pool: Arc<Pool>, path: &Path) -> Result<(), String> {
+    // NOTE: we watch the directory rather than the file itself, because many tools replace a
+    // file by renaming a new one over it, which a watch on the old file would never see
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let (changed_tx, mut changed_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                log::warn!("Error watching for discovery file changes: {}", err);
+                return;
+            }
+        };
+        // Reading the file ourselves shows up as an access; don't let that trigger a reload
+        let relevant = event.kind.is_create()
+            || event.kind.is_modify()
+            || event.kind.is_remove()
+            || matches!(
+                event.kind,
+                notify::EventKind::Access(notify::event::AccessKind::Close(
+                    notify::event::AccessMode::Write
+                ))
+            );
+        if relevant
+            && event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == file_name.as_deref())
+        {
+            let _ = changed_tx.send(());
+        }
+    })
+    .map_err(|err| format!("could not watch {}: {}", path.display(), err))?;
+    watcher
+        .watch(&directory, notify::RecursiveMode::NonRecursive)
+        .map_err(|err| format!("could not watch {}: {}", directory.display(), err))?;
+
+    let source = Source::File(path.to_path_buf());
+    tokio::spawn(async move {
+        // Dropping the watcher would stop the notifications
+        let _watcher = watcher;
+        while changed_rx.recv().await.is_some() {
+            tokio::time::sleep(SETTLE_TIME).await;
+            while changed_rx.try_recv().is_ok() {}
+            log::info!("{} changed; reloading it", source.describe());
+            refresh(&pool, &source).await;
+        }
+    });
+    Ok(())
+}
+
+/// Loads the upstreams of every pool that discovers them from a file or HTTP endpoint, then
+/// keeps them up to date as the file changes or on every poll of the endpoint.
+pub async fn start(pools: &[Arc<Pool>]) -> Result<(), String> {
+    for pool in pools {
+        let source = match &pool.discovery {
+            Some(source) => source,
+            None => continue,
+        };
+        // Load once before we start accepting requests, so that they have somewhere to go
+        refresh(pool, source).await;
+        match source {
+            Source::File(path) => watch_file(pool.clone(), path)?,
+            Source::Http { interval, .. } => {
+                let pool = pool.clone();
+                let interval = *interval;
+                tokio::spawn(async move {
+                    let source = pool.discovery.as_ref().expect("checked above");
+                    loop {
+                        tokio::time::sleep(interval).await;
+                        refresh(&pool, source).await;
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig};
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::pool::{Endpoint, Pool};
+use crate::socket;
+
+/// Upstreams written as "srv+<name>" are discovered through SRV records, e.g.
+/// "srv+_http._tcp.api.internal".
+const SRV_PREFIX: &str = "srv+";
+
+/// Don't re-resolve more often than this, however short the TTLs are.
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+/// How soon to try again after a lookup fails. Until then, the pool keeps the upstreams it had.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One entry of a pool's upstream list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// An IP address and port, used as-is
+    Address(SocketAddr),
+    /// A Unix domain socket ("unix:/path"), used as-is
+    Unix(String),
+    /// Expands to one upstream per A/AAAA record for the host, all on the same port
+    Host { host: String, port: u16 },
+    /// Expands to one upstream per address of every target of the name's SRV records
+    Srv(String),
+}
+
+impl Target {
+    pub fn parse(upstream: &str) -> Result<Target, String> {
+        if let Some(name) = upstream.strip_prefix(SRV_PREFIX) {
+            if name.is_empty() {
+                return Err(format!("upstream \"{}\" is missing a name", upstream));
+            }
+            return Ok(Target::Srv(name.to_string()));
+        }
+        if let Some(path) = socket::unix_path(upstream) {
+            if path.is_empty() {
+                return Err(format!("upstream \"{}\" is missing a path", upstream));
+            }
+            return Ok(Target::Unix(upstream.to_string()));
+        }
+        if let Ok(address) = upstream.parse::<SocketAddr>() {
+            return Ok(Target::Address(address));
+        }
+        match upstream.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !host.contains(':') => Ok(Target::Host {
+                host: host.to_string(),
+                port: port
+                    .parse()
+                    .map_err(|_| format!("invalid port in upstream \"{}\"", upstream))?,
+            }),
+            _ => Err(format!(
+                "upstream \"{}\" should be host:port, {}<path> or {}<name>",
+                upstream,
+                socket::UNIX_PREFIX,
+                SRV_PREFIX
+            )),
+        }
+    }
+
+    pub fn is_address(&self) -> bool {
+        matches!(self, Target::Address(_) | Target::Unix(_))
+    }
+}
+
+/// Looks up the hostnames and SRV names in pools' upstream lists.
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl Resolver {
+    /// Uses the system's resolver configuration, or just `server` (an IP address, with an
+    /// optional port) if given.
+    pub fn new(server: Option<&str>) -> Result<Resolver, String> {
+        let (config, mut options) = match server {
+            Some(server) => {
+                let address = server
+                    .parse::<SocketAddr>()
+                    .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .map_err(|_| format!("invalid DNS server \"{}\"", server))?;
+                let name_servers =
+                    NameServerConfigGroup::from_ips_clear(&[address.ip()], address.port(), true);
+                (
+                    ResolverConfig::from_parts(None, Vec::new(), name_servers),
+                    Default::default(),
+                )
+            }
+            None => hickory_resolver::system_conf::read_system_conf()
+                .map_err(|err| format!("could not read the system DNS configuration: {}", err))?,
+        };
+        // Every address is a separate upstream, whichever family it is in
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Ok(Resolver {
+            resolver: TokioAsyncResolver::tokio(config, options),
+        })
+    }
+
+    /// Expands targets into endpoints, returning them along with when the answers expire.
+    async fn resolve(&self, targets: &[Target]) -> Result<(Vec<Endpoint>, Instant), String> {
+        let mut endpoints = Vec::new();
+        let mut valid_until = Instant::now() + Duration::from_secs(24 * 60 * 60);
+        for target in targets {
+            match target {
+                Target::Address(address) => endpoints.push(Endpoint::new(address.to_string())),
+                Target::Unix(address) => endpoints.push(Endpoint::new(address.clone())),
+                Target::Host { host, port } => {
+                    let lookup = self
+                        .resolver
+                        .lookup_ip(host.as_str())
+                        .await
+                        .map_err(|err| format!("could not resolve {}: {}", host, err))?;
+                    valid_until = valid_until.min(lookup.valid_until());
+                    endpoints.extend(
+                        lookup
+                            .iter()
+                            .map(|ip| Endpoint::new(SocketAddr::new(ip, *port).to_string())),
+                    );
+                }
+                Target::Srv(name) => {
+                    let lookup = self
+                        .resolver
+                        .srv_lookup(name.as_str())
+                        .await
+                        .map_err(|err| {
+                            format!("could not look up SRV records for {}: {}", name, err)
+                        })?;
+                    valid_until = valid_until.min(lookup.as_lookup().valid_until());
+                    for srv in lookup.iter() {
+                        let host = srv.target().to_utf8();
+                        let addresses = self
+                            .resolver
+                            .lookup_ip(host.as_str())
+                            .await
+                            .map_err(|err| format!("could not resolve {}: {}", host, err))?;
+                        valid_until = valid_until.min(addresses.valid_until());
+                        endpoints.extend(addresses.iter().map(|ip| Endpoint {
+                            address: SocketAddr::new(ip, srv.port()).to_string(),
+                            priority: srv.priority(),
+                            weight: srv.weight(),
+                        }));
+                    }
+                }
+            }
+        }
+        // The same address may be reachable through more than one target
+        let mut seen = std::collections::HashSet::new();
+        endpoints.retain(|endpoint| seen.insert(endpoint.address.clone()));
+        Ok((endpoints, valid_until))
+    }
+
+    /// Resolves the upstreams of a pool and applies the result, returning when to do it again.
+    async fn refresh(&self, pool: &Pool) -> Instant {
+        match self.resolve(pool.targets()).await {
+            Ok((endpoints, valid_until)) => {
+                pool.set_endpoints(endpoints).await;
+                valid_until.max(Instant::now() + MIN_REFRESH)
+            }
+            Err(err) => {
+                log::warn!("Keeping the upstreams of pool {}: {}", pool.name, err);
+                Instant::now() + RETRY_INTERVAL
+            }
+        }
+    }
+}
+
+/// Resolves the upstreams of every pool that names them by hostname or SRV record, then keeps
+/// re-resolving each one as its records expire.
+pub async fn start(pools: &[Arc<Pool>], server: Option<&str>) -> Result<(), String> {
+    let pools: Vec<Arc<Pool>> = pools
+        .iter()
+        .filter(|pool| !pool.targets().iter().all(Target::is_address))
+        .cloned()
+        .collect();
+    if pools.is_empty() {
+        return Ok(());
+    }
+    let resolver = Arc::new(Resolver::new(server)?);
+    for pool in pools {
+        // Resolve once before we start accepting requests, so that they have somewhere to go
+        let mut next_refresh = resolver.refresh(&pool).await;
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep_until(next_refresh.into()).await;
+                next_refresh = resolver.refresh(&pool).await;
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use crate::config::ErrorPageConfig;
+use crate::response;
+
+/// A page template, with `{{status}}`, `{{reason}}` and `{{request_id}}` placeholders.
+struct Template {
+    text: String,
+    content_type: &'static str,
+}
+
+impl Template {
+    fn load(path: &str, content_type: &'static str) -> Result<Template, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("could not read error page {}: {}", path, err))?;
+        Ok(Template { text, content_type })
+    }
+
+    fn render(&self, status: http::StatusCode, request_id: &str) -> String {
+        let escape = if self.content_type.starts_with("application/json") {
+            escape_json
+        } else {
+            escape_html
+        };
+        self.text
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+            .replace("{{request_id}}", &escape(request_id))
+    }
+}
+
+/// The pages for one status code (or class of them): HTML, JSON, or both, in which case the
+/// client's Accept header decides.
+struct Page {
+    html: Option<Template>,
+    json: Option<Template>,
+}
+
+impl Page {
+    fn choose(&self, accept: Option<&http::HeaderValue>) -> Option<&Template> {
+        match (&self.html, &self.json) {
+            (Some(html), Some(json)) => {
+                let accept = accept
+                    .and_then(|accept| accept.to_str().ok())
+                    .unwrap_or("")
+                    .to_ascii_lowercase();
+                if accept.contains("json") && !accept.contains("text/html") {
+                    Some(json)
+                } else {
+                    Some(html)
+                }
+            }
+            (html, json) => html.as_ref().or(json.as_ref()),
+        }
+    }
+}
+
+/// Error pages by status code ("502") or class ("5xx"), from the config file's `error_pages`
+/// (or a route's).
+///
+/// ```yaml
+/// error_pages:
+///   502: { html: /etc/balancebeam/502.html, json: /etc/balancebeam/502.json }
+///   4xx: { html: /etc/balancebeam/client-error.html }
+/// ```
+#[derive(Default)]
+pub struct ErrorPages {
+    pages: HashMap<String, Page>,
+}
+
+impl ErrorPages {
+    pub fn load(config: &HashMap<String, ErrorPageConfig>) -> Result<ErrorPages, String> {
+        let mut pages = HashMap::new();
+        for (key, page) in config {
+            let key = key.to_ascii_lowercase();
+            let valid = key.len() == 3
+                && matches!(key.as_bytes()[0], b'1'..=b'5')
+                && (key[1..] == *"xx" || key.bytes().all(|byte| byte.is_ascii_digit()));
+            if !valid {
+                return Err(format!(
+                    "error page key \"{}\" must be a status code (e.g. 502) or class (e.g. 5xx)",
+                    key
+                ));
+            }
+            if page.html.is_none() && page.json.is_none() {
+                return Err(format!("error page {} needs html, json or both", key));
+            }
+            let page = Page {
+                html: page
+                    .html
+                    .as_deref()
+                    .map(|path| Template::load(path, "text/html; charset=utf-8"))
+                    .transpose()?,
+                json: page
+                    .json
+                    .as_deref()
+                    .map(|path| Template::load(path, "application/json"))
+                    .transpose()?,
+            };
+            pages.insert(key, page);
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    fn find(&self, status: http::StatusCode) -> Option<&Page> {
+        self.pages
+            .get(status.as_str())
+            .or_else(|| self.pages.get(&format!("{}xx", status.as_u16() / 100)))
+    }
+}
+
+/// Replaces the body of an error response we generated with the first of `pages` that has a
+/// page for its status. Other responses (including errors from upstreams) are left alone.
+pub fn apply(
+    pages: &[&ErrorPages],
+    accept: Option<&http::HeaderValue>,
+    request_id: Option<&http::HeaderValue>,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    if response.extensions().get::<response::Generated>().is_none() {
+        return;
+    }
+    let status = response.status();
+    let template = match pages
+        .iter()
+        .find_map(|pages| pages.find(status))
+        .and_then(|page| page.choose(accept))
+    {
+        Some(template) => template,
+        None => return,
+    };
+    let request_id = request_id
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    let body = template.render(status, request_id).into_bytes();
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(template.content_type),
+    );
+    headers.insert(
+        http::header::CONTENT_LENGTH,
+        http::HeaderValue::from(body.len()),
+    );
+    *response.body_mut() = body;
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a value for use inside a JSON string (the template supplies the quotes).
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::Value::String(value.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
@@ -0,0 +1,218 @@
+use std::net::IpAddr;
+
+use crate::config::{RouteConfig, RuleConfig};
+use crate::response;
+
+/// An IP network such as 10.0.0.0/8 or 2001:db8::/32. A bare address matches only itself.
+struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    fn parse(cidr: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid IP address in \"{}\"", cidr))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in \"{}\"", cidr))?,
+            None => max_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Clients connecting over IPv6 to a dual-stack socket show up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Rejects requests whose target or headers match patterns, with a chosen status.
+struct Rule {
+    path: Option<regex::Regex>,
+    header: Option<(http::HeaderName, regex::Regex)>,
+    status: http::StatusCode,
+}
+
+impl Rule {
+    fn new(config: &RuleConfig) -> Result<Rule, String> {
+        let compile = |pattern: &str| {
+            regex::Regex::new(pattern).map_err(|err| format!("invalid rule pattern: {}", err))
+        };
+        let path = config.path.as_deref().map(compile).transpose()?;
+        let header = match &config.header {
+            Some(header) => Some((
+                http::HeaderName::from_bytes(header.name.as_bytes())
+                    .map_err(|_| format!("invalid header name \"{}\" in rule", header.name))?,
+                compile(&header.pattern)?,
+            )),
+            None => None,
+        };
+        if path.is_none() && header.is_none() {
+            return Err("a rule needs a path or header pattern".to_string());
+        }
+        let status = http::StatusCode::from_u16(config.status)
+            .ok()
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(|| format!("rule status {} is not an error status", config.status))?;
+        Ok(Rule {
+            path,
+            header,
+            status,
+        })
+    }
+
+    /// A rule matches if all of the patterns it has match.
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        self.path.as_ref().is_none_or(|path| {
+            let target = request
+                .uri()
+                .path_and_query()
+                .map_or("", |path_and_query| path_and_query.as_str());
+            path.is_match(&percent_decode(target))
+        }) && self.header.as_ref().is_none_or(|(name, pattern)| {
+            request
+                .headers()
+                .get_all(name)
+                .iter()
+                .any(|value| pattern.is_match(&String::from_utf8_lossy(value.as_bytes())))
+        })
+    }
+}
+
+/// Undoes %XX escapes, so that rules can't be dodged by encoding what they look for.
+pub fn percent_decode(target: &str) -> String {
+    let bytes = target.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decides which requests a route accepts, before they count against any rate limit or reach an
+/// upstream.
+#[derive(Default)]
+pub struct Filter {
+    /// If non-empty, only clients in these networks are let through
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    /// If set, other methods get 405 Method Not Allowed
+    methods: Option<Vec<http::Method>>,
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn new(config: &RouteConfig) -> Result<Filter, String> {
+        let parse_cidrs = |cidrs: &[String]| {
+            cidrs
+                .iter()
+                .map(|cidr| Cidr::parse(cidr))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let methods = match &config.methods {
+            Some(methods) => Some(
+                methods
+                    .iter()
+                    .map(|method| {
+                        http::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                            .map_err(|_| format!("invalid method \"{}\"", method))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        Ok(Filter {
+            allow: parse_cidrs(&config.allow)?,
+            deny: parse_cidrs(&config.deny)?,
+            methods,
+            rules: config
+                .rules
+                .iter()
+                .map(Rule::new)
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+
+    /// Returns the response to reject the request with, or None if it may go through.
+    pub fn check(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        client_ip: &str,
+    ) -> Option<http::Response<Vec<u8>>> {
+        if !self.allow.is_empty() || !self.deny.is_empty() {
+            let allowed = match client_ip.parse::<IpAddr>() {
+                Ok(ip) => {
+                    !self.deny.iter().any(|cidr| cidr.contains(ip))
+                        && (self.allow.is_empty()
+                            || self.allow.iter().any(|cidr| cidr.contains(ip)))
+                }
+                // We can't vouch for a client we can't place
+                Err(_) => false,
+            };
+            if !allowed {
+                log::debug!("Refusing request from {}: address not allowed", client_ip);
+                return Some(response::make_http_error(http::StatusCode::FORBIDDEN));
+            }
+        }
+
+        if let Some(methods) = &self.methods {
+            if !methods.contains(request.method()) {
+                let mut response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+                let allow = methods
+                    .iter()
+                    .map(http::Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                response
+                    .headers_mut()
+                    .insert(http::header::ALLOW, allow.parse().unwrap());
+                return Some(response);
+            }
+        }
+
+        let rule = self.rules.iter().find(|rule| rule.matches(request))?;
+        log::debug!(
+            "Refusing request from {} with {}: it matches a filter rule",
+            client_ip,
+            rule.status
+        );
+        Some(response::make_http_error(rule.status))
+    }
+}
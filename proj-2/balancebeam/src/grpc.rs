@@ -0,0 +1,180 @@
+use crate::http2::Trailers;
+
+/// gRPC status codes we care about (see grpc/doc/statuscodes.md)
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_UNAVAILABLE: u32 = 14;
+
+/// Path of the standard health checking RPC (grpc/doc/health-checking.md)
+const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+/// HealthCheckResponse.ServingStatus.SERVING
+const SERVING: u64 = 1;
+
+/// Returns true if the request is a gRPC call.
+pub fn is_grpc(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// Splits a gRPC request path ("/package.Service/Method") into service and method.
+pub fn service_and_method(path: &str) -> Option<(&str, &str)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    Some((service, method))
+}
+
+/// Extracts the grpc-status of a response. Servers normally send it in the trailers, but
+/// "trailers-only" responses (usually errors) carry it in the headers instead.
+pub fn status(response: &http::Response<Vec<u8>>) -> Option<u32> {
+    let trailers = response.extensions().get::<Trailers>().map(|t| &t.0);
+    trailers
+        .and_then(|trailers| trailers.get("grpc-status"))
+        .or_else(|| response.headers().get("grpc-status"))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Frames a serialized protobuf message the way gRPC puts it on the wire: a compressed flag
+/// byte, a 4-byte big-endian length and the message itself.
+pub fn encode_message(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// Returns the first (uncompressed) message in a gRPC body.
+pub fn decode_message(body: &[u8]) -> Option<&[u8]> {
+    if body.len() < 5 || body[0] != 0 {
+        return None;
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    body.get(5..5 + len)
+}
+
+fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Builds a grpc.health.v1.Health/Check call asking about `service`.
+pub fn health_check_request(service: &str, authority: &str) -> http::Request<Vec<u8>> {
+    // HealthCheckRequest { string service = 1; }
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.push(0x0a);
+        encode_varint(service.len() as u64, &mut message);
+        message.extend_from_slice(service.as_bytes());
+    }
+    http::Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{}{}", authority, HEALTH_CHECK_PATH))
+        .version(http::Version::HTTP_2)
+        .header(http::header::CONTENT_TYPE, "application/grpc")
+        .header(http::header::TE, "trailers")
+        .body(encode_message(&message))
+        .unwrap()
+}
+
+/// Returns true if a health check response reports the service as SERVING.
+pub fn is_serving(response: &http::Response<Vec<u8>>) -> bool {
+    if response.status() != http::StatusCode::OK || status(response) != Some(STATUS_OK) {
+        return false;
+    }
+    // HealthCheckResponse { ServingStatus status = 1; }
+    let mut message = match decode_message(response.body()) {
+        Some(message) => message,
+        None => return false,
+    };
+    let mut serving_status = 0;
+    while !message.is_empty() {
+        let (key, len) = match decode_varint(message) {
+            Some(key) => key,
+            None => return false,
+        };
+        message = &message[len..];
+        let (field, wire_type) = (key >> 3, key & 0x7);
+        match wire_type {
+            0 => {
+                let (value, len) = match decode_varint(message) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                message = &message[len..];
+                if field == 1 {
+                    serving_status = value;
+                }
+            }
+            2 => {
+                let (value_len, len) = match decode_varint(message) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                // NOTE: the length comes from the upstream, so it may not fit in the message (or
+                // even in a usize)
+                let end = match usize::try_from(value_len)
+                    .ok()
+                    .and_then(|value_len| len.checked_add(value_len))
+                {
+                    Some(end) => end,
+                    None => return false,
+                };
+                message = match message.get(end..) {
+                    Some(rest) => rest,
+                    None => return false,
+                };
+            }
+            _ => return false,
+        }
+    }
+    serving_status == SERVING
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a health check response carrying `message`.
+    fn health_response(message: &[u8]) -> http::Response<Vec<u8>> {
+        http::Response::builder()
+            .header("grpc-status", "0")
+            .body(encode_message(message))
+            .unwrap()
+    }
+
+    #[test]
+    fn is_serving_reads_status() {
+        assert!(is_serving(&health_response(&[0x08, SERVING as u8])));
+        assert!(!is_serving(&health_response(&[0x08, 2])));
+    }
+
+    #[test]
+    fn is_serving_rejects_huge_field_length() {
+        // An unknown length-delimited field (2) claiming to be close to u64::MAX bytes long
+        for length in [u64::MAX, u64::MAX - 1, usize::MAX as u64 - 1] {
+            let mut message = vec![0x12];
+            encode_varint(length, &mut message);
+            message.extend_from_slice(&[0x08, SERVING as u8]);
+            assert!(!is_serving(&health_response(&message)));
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::response;
+
+/// Every HTTP/2 connection starts with this sequence. Cleartext clients that speak HTTP/2 "with
+/// prior knowledge" (h2c) send it immediately instead of an HTTP/1.1 request line.
+pub const CONNECTION_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Hop-by-hop headers that only make sense on a single HTTP/1.1 connection. HTTP/2 forbids them
+/// (RFC 9113 section 8.2.2), so they are stripped whenever a message crosses between protocols.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Largest response body we accept from an upstream
+const MAX_BODY_SIZE: usize = 10000000;
+
+/// Trailer fields that followed a message body. They are stored in the message's extensions so
+/// that they can be forwarded along with the buffered body (gRPC reports call status this way).
+#[derive(Clone, Debug)]
+pub struct Trailers(pub http::HeaderMap);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The peer sent a bigger body than we accept
+    BodyTooLarge,
+    /// The HTTP/2 connection or stream failed
+    Protocol(#[allow(dead_code)] h2::Error),
+}
+
+impl From<h2::Error> for Error {
+    fn from(err: h2::Error) -> Self {
+        Error::Protocol(err)
+    }
+}
+
+/// Returns true if `prefix` (the first bytes a client sent) could still be the HTTP/2 connection
+/// preface. Once this returns false the client is definitely speaking HTTP/1.x.
+pub fn could_be_preface(prefix: &[u8]) -> bool {
+    let len = prefix.len().min(CONNECTION_PREFACE.len());
+    prefix[..len] == CONNECTION_PREFACE[..len]
+}
+
+/// Reads a whole message body (and any trailers) from an HTTP/2 stream, releasing flow-control
+/// capacity as data arrives so the peer can keep sending.
+pub async fn read_body(
+    mut body: h2::RecvStream,
+    max_body_size: usize,
+) -> Result<(Vec<u8>, Option<http::HeaderMap>), Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if data.len() + chunk.len() > max_body_size {
+            return Err(Error::BodyTooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    let trailers = body.trailers().await?;
+    Ok((data, trailers))
+}
+
+/// Sends a body, followed by trailers if there are any, on a stream whose headers have already
+/// been sent without END_STREAM.
+fn send_body(
+    stream: &mut h2::SendStream<Bytes>,
+    body: Vec<u8>,
+    trailers: Option<Trailers>,
+) -> Result<(), Error> {
+    if !body.is_empty() || trailers.is_none() {
+        stream.send_data(Bytes::from(body), trailers.is_none())?;
+    }
+    if let Some(Trailers(trailers)) = trailers {
+        stream.send_trailers(trailers)?;
+    }
+    Ok(())
+}
+
+/// Sends a fully-buffered response on a server stream.
+pub fn send_response(
+    mut respond: h2::server::SendResponse<Bytes>,
+    response: http::Response<Vec<u8>>,
+) -> Result<(), Error> {
+    let (mut parts, body) = response.into_parts();
+    let trailers = parts.extensions.remove::<Trailers>();
+    let head = http::Response::from_parts(parts, ());
+    let end_of_stream = body.is_empty() && trailers.is_none();
+    let mut stream = respond.send_response(head, end_of_stream)?;
+    if !end_of_stream {
+        send_body(&mut stream, body, trailers)?;
+    }
+    Ok(())
+}
+
+/// Performs the client side of the HTTP/2 handshake on an upstream connection. The connection
+/// itself is driven by a background task; the returned handle can be cloned to open any number
+/// of concurrent streams on it.
+pub async fn connect<S>(stream: S) -> Result<h2::client::SendRequest<Bytes>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send_request, connection) = h2::client::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            log::debug!("Upstream HTTP/2 connection closed: {}", err);
+        }
+    });
+    Ok(send_request)
+}
+
+/// Sends a request on an upstream HTTP/2 connection and waits for the complete response.
+pub async fn send_request(
+    send_request: h2::client::SendRequest<Bytes>,
+    request: http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let mut send_request = send_request.ready().await?;
+    let (mut parts, body) = request.into_parts();
+    let trailers = parts.extensions.remove::<Trailers>();
+    let head = http::Request::from_parts(parts, ());
+    let end_of_stream = body.is_empty() && trailers.is_none();
+    let (response, mut stream) = send_request.send_request(head, end_of_stream)?;
+    if !end_of_stream {
+        send_body(&mut stream, body, trailers)?;
+    }
+    let (mut parts, body) = response.await?.into_parts();
+    let (body, trailers) = read_body(body, MAX_BODY_SIZE).await?;
+    if let Some(trailers) = trailers {
+        parts.extensions.insert(Trailers(trailers));
+    }
+    Ok(http::Response::from_parts(parts, body))
+}
+
+fn strip_connection_headers(headers: &mut http::HeaderMap) {
+    for name in CONNECTION_HEADERS {
+        headers.remove(name);
+    }
+    // "te" is allowed in HTTP/2, but only with the value "trailers"
+    if headers
+        .get(http::header::TE)
+        .is_some_and(|value| value != "trailers")
+    {
+        headers.remove(http::header::TE);
+    }
+}
+
+/// Rewrites a request so that it can be sent to an HTTP/2 upstream: the URI becomes absolute
+/// (HTTP/2 carries the host in the :authority pseudo-header rather than a Host header) and
+/// connection-specific headers are dropped. Requests without a Host get `default_authority`.
+pub fn upgrade_request(request: &mut http::Request<Vec<u8>>, default_authority: &str) {
+    if request.version() != http::Version::HTTP_2 {
+        let authority = request
+            .headers_mut()
+            .remove(http::header::HOST)
+            .and_then(|host| host.to_str().ok().map(str::to_string))
+            .unwrap_or_else(|| default_authority.to_string());
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_string();
+        if let Ok(uri) = format!("http://{}{}", authority, path).parse() {
+            *request.uri_mut() = uri;
+        }
+        *request.version_mut() = http::Version::HTTP_2;
+    }
+    strip_connection_headers(request.headers_mut());
+}
+
+/// Rewrites a request received over HTTP/2 so that it can be written to an HTTP/1.1 upstream.
+pub fn downgrade_request(request: &mut http::Request<Vec<u8>>) {
+    if request.version() != http::Version::HTTP_2 {
+        return;
+    }
+    if let Some(authority) = request.uri().authority() {
+        if !request.headers().contains_key(http::header::HOST) {
+            let host = http::HeaderValue::from_str(authority.as_str()).unwrap();
+            request.headers_mut().insert(http::header::HOST, host);
+        }
+    }
+    if let Some(path) = request.uri().path_and_query() {
+        *request.uri_mut() = path.as_str().parse().unwrap();
+    }
+    // HTTP/2 clients may omit Content-Length because frames delimit the body
+    if !request.body().is_empty() {
+        let length = http::HeaderValue::from(request.body().len());
+        request
+            .headers_mut()
+            .insert(http::header::CONTENT_LENGTH, length);
+    }
+    *request.version_mut() = http::Version::HTTP_11;
+}
+
+/// Rewrites a response from an HTTP/1.1 upstream so that it can be sent on an HTTP/2 stream.
+pub fn upgrade_response(response: &mut http::Response<Vec<u8>>) {
+    strip_connection_headers(response.headers_mut());
+    *response.version_mut() = http::Version::HTTP_2;
+}
+
+/// Rewrites a response from an HTTP/2 upstream so that it can be written to an HTTP/1.1 client.
+/// The body is fully buffered, so it is framed with Content-Length.
+pub fn downgrade_response(response: &mut http::Response<Vec<u8>>) {
+    if response.version() != http::Version::HTTP_2 {
+        return;
+    }
+    if response::has_body(response.status())
+        && !response
+            .headers()
+            .contains_key(http::header::CONTENT_LENGTH)
+    {
+        let length = http::HeaderValue::from(response.body().len());
+        response
+            .headers_mut()
+            .insert(http::header::CONTENT_LENGTH, length);
+    }
+    *response.version_mut() = http::Version::HTTP_11;
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::overload::ConnectionLimits;
+use crate::rate_limiter::RateLimiter;
+use crate::request;
+use crate::route::{self, Route};
+
+/// One of the addresses we accept clients on, with the routes and limits its clients get. Pools
+/// (and their health) are shared by every listener.
+pub struct Listener {
+    /// What we're listening on, for logs
+    pub address: String,
+    /// Routes for this listener's requests, followed by a catch-all route to the default pool
+    pub routes: Vec<Route>,
+    /// Terminates TLS on accepted connections, if a certificate was configured
+    pub tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    /// Caps on how many client connections we serve at once
+    pub connection_limits: Arc<ConnectionLimits>,
+    /// How big a request may be
+    pub request_limits: request::Limits,
+    /// How many requests a client may send on one HTTP/1.x connection (0 = unlimited)
+    pub max_requests_per_connection: usize,
+    /// Maximum number of requests an individual IP can make in a minute (0 = unlimited)
+    max_requests_per_minute: usize,
+    // NOTE: limiter
+    limiter_map: tokio::sync::RwLock<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl Listener {
+    pub fn new(
+        address: String,
+        routes: Vec<Route>,
+        tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+        connection_limits: ConnectionLimits,
+        request_limits: request::Limits,
+        max_requests_per_connection: usize,
+        max_requests_per_minute: usize,
+    ) -> Listener {
+        Listener {
+            address,
+            routes,
+            tls_acceptor,
+            connection_limits: Arc::new(connection_limits),
+            request_limits,
+            max_requests_per_connection,
+            max_requests_per_minute,
+            limiter_map: tokio::sync::RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The largest body the route a request is for accepts.
+    pub fn max_body_size(&self, request: &http::Request<Vec<u8>>) -> usize {
+        route::find(&self.routes, request)
+            .and_then(|route| route.max_body_size)
+            .unwrap_or(self.request_limits.max_body_size)
+    }
+
+    pub async fn get_limiter(&self, addr: &str) -> Option<Arc<RateLimiter>> {
+        if self.max_requests_per_minute == 0 {
+            return None;
+        }
+        let mut write = self.limiter_map.write().await;
+        let limiter = write
+            .entry(addr.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(self.max_requests_per_minute as u32)));
+        Some(limiter.clone())
+    }
+}
@@ -1,37 +1,217 @@
+mod access_log;
+mod admin;
+mod auth;
+mod balancer;
+mod cache;
+mod canary;
+mod circuit_breaker;
+mod compression;
+mod config;
+mod discovery;
+mod dns;
+mod error_pages;
+mod filter;
+mod grpc;
+mod http2;
+mod listener;
+mod mirror;
+mod overload;
+mod pool;
 mod rate_limiter;
 mod request;
 mod response;
+mod route;
+mod shutdown;
+mod socket;
+mod static_files;
+mod tls;
+mod trace;
+mod upstream;
+mod workers;
 
-use std::{
-    collections::HashMap,
-    sync::{atomic, Arc},
-    time,
-};
+use std::{collections::HashMap, net::IpAddr, os::fd::AsRawFd, sync::Arc, time};
 
 use clap::Parser;
-use rand::Rng;
+use listener::Listener;
+use pool::Pool;
 use rate_limiter::RateLimiter;
+use route::Route;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Where we listen if neither --bind nor the config file says
+const DEFAULT_BIND: &str = "0.0.0.0:1100";
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
-    /// "IP/port to bind to"
-    #[arg(short, long, default_value = "0.0.0.0:1100")]
-    bind: String,
-    /// "Upstream host to forward requests to"
+    /// "IP/port to bind to, or unix:<path> to listen on a Unix domain socket (default
+    /// 0.0.0.0:1100, unless the config file defines listeners)"
+    #[arg(short, long)]
+    bind: Option<String>,
+    /// "Upstream host to forward requests to, or unix:<path> for one on a Unix domain socket. A
+    /// hostname expands to an upstream per address it resolves to, and srv+<name> to an upstream
+    /// per address of each of its SRV records' targets; both are re-resolved as their records
+    /// expire"
     #[arg(short, long)]
     upstream: Vec<String>,
+    /// "DNS server (IP, with an optional port) to resolve upstream hostnames with, instead of the
+    /// ones configured for the system"
+    #[arg(long)]
+    dns_server: Option<String>,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "Probe --upstream servers with the gRPC health checking protocol for this service instead
+    /// of an HTTP GET (an empty name checks the server as a whole)"
+    #[arg(long)]
+    active_health_check_grpc_service: Option<String>,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Protocol to use when talking to upstream servers"
+    #[arg(long, value_enum, default_value = "http1")]
+    upstream_protocol: upstream::Protocol,
+    /// "PEM certificate chain to serve TLS with (HTTP/2 is negotiated via ALPN)"
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// "PEM private key matching --tls-cert"
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// "YAML or JSON file defining upstream pools and the routes that lead to them"
+    #[arg(long)]
+    config: Option<String>,
+    /// "Cache responses that allow it, using up to this many bytes (0 = no caching)"
+    #[arg(long, default_value = "0")]
+    cache_size: usize,
+    /// "Largest single response to cache, in bytes"
+    #[arg(long, default_value = "1048576")]
+    cache_max_object_size: usize,
+    /// "Keep cached response bodies in this directory instead of in memory"
+    #[arg(long)]
+    cache_dir: Option<std::path::PathBuf>,
+    /// "IP/port (or unix:<path>) to serve the admin interface on (disabled if not set)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "Compress responses with these codings, most preferred first (none = no compression)"
+    #[arg(long, value_enum)]
+    compression: Vec<compression::Encoding>,
+    /// "Content types to compress (a trailing * matches any subtype, e.g. text/*)"
+    #[arg(
+        long,
+        default_values = [
+            "text/*",
+            "application/json",
+            "application/javascript",
+            "application/xml",
+            "image/svg+xml",
+        ]
+    )]
+    compression_mime_type: Vec<String>,
+    /// "Don't compress bodies smaller than this many bytes"
+    #[arg(long, default_value = "1024")]
+    compression_min_size: usize,
+    /// "Decompress gzip, br and zstd request bodies before passing them to upstreams"
+    #[arg(long)]
+    decompress_requests: bool,
+    /// "Write an access log line for every request to this file (- for stdout). Send SIGUSR1 to
+    /// reopen the file after rotating it"
+    #[arg(long)]
+    access_log: Option<String>,
+    /// "Layout of access log lines"
+    #[arg(long, value_enum, default_value = "combined")]
+    access_log_format: access_log::Format,
+    /// "OpenTelemetry collector (host:port) to export a span per request to, using OTLP/HTTP"
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// "service.name to report exported spans under"
+    #[arg(long, default_value = "balancebeam")]
+    otlp_service_name: String,
+    /// "On SIGTERM, or after handing our sockets to a new process on SIGUSR2, wait this many
+    /// seconds for in-flight requests to finish before exiting"
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: u64,
+    /// "Write our process ID to this file (rewritten by the new process after a SIGUSR2 restart)"
+    #[arg(long)]
+    pid_file: Option<std::path::PathBuf>,
+    /// "Maximum number of client connections to serve at once (0 = unlimited); clients beyond it
+    /// get 503 Service Unavailable"
+    #[arg(long, default_value = "0")]
+    max_connections: usize,
+    /// "Maximum number of connections to serve at once from a single client IP (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections_per_ip: usize,
+    /// "Maximum number of requests in flight to any one upstream (0 = unlimited); requests beyond
+    /// it get 503 Service Unavailable"
+    #[arg(long, default_value = "0")]
+    max_upstream_requests: usize,
+    /// "Adjust each upstream's in-flight request limit to its latency, backing off as it slows
+    /// down (up to --max-upstream-requests, if set)"
+    #[arg(long)]
+    adaptive_concurrency: bool,
+    /// "Stop sending requests to an upstream once too many of its recent requests fail (or are
+    /// slow), then let a few probe requests through after a while to see if it has recovered"
+    #[arg(long)]
+    circuit_breaker: bool,
+    /// "Seconds of recent requests the circuit breaker bases its failure rate on"
+    #[arg(long, default_value = "10")]
+    circuit_breaker_window: u64,
+    /// "Minimum number of recent requests before the circuit breaker judges an upstream"
+    #[arg(long, default_value = "10")]
+    circuit_breaker_min_requests: usize,
+    /// "Percentage of recent requests that must fail to open an upstream's circuit (1-100)"
+    #[arg(long, default_value = "50")]
+    circuit_breaker_failure_rate: u32,
+    /// "Count requests that take longer than this many milliseconds as failures (0 = never)"
+    #[arg(long, default_value = "0")]
+    circuit_breaker_slow_call_ms: u64,
+    /// "Seconds an open circuit keeps requests away from its upstream before probing it"
+    #[arg(long, default_value = "30")]
+    circuit_breaker_open_time: u64,
+    /// "Number of probe requests that must succeed to close a circuit again"
+    #[arg(long, default_value = "3")]
+    circuit_breaker_probes: usize,
+    /// "Ramp up the share of requests an upstream gets over this many seconds after it comes
+    /// back or is added (0 = give it its full share right away)"
+    #[arg(long, default_value = "0")]
+    slow_start: u64,
+    /// "How to choose between a pool's upstreams: at random by weight, or by latency (peak EWMA)
+    /// and requests in flight"
+    #[arg(long, value_enum, default_value = "random")]
+    balancer: balancer::Algorithm,
+    /// "Time constant in milliseconds of the latency averages --balancer ewma keeps; the higher
+    /// it is, the longer a slow response weighs against an upstream"
+    #[arg(long, default_value = "10000")]
+    ewma_decay_ms: u64,
+    /// "Maximum size in bytes of a request's request line and headers; larger requests get 431
+    /// Request Header Fields Too Large"
+    #[arg(long, default_value_t = request::DEFAULT_MAX_HEADERS_SIZE)]
+    max_header_size: usize,
+    /// "Maximum number of headers in a request"
+    #[arg(long, default_value_t = request::DEFAULT_MAX_NUM_HEADERS)]
+    max_headers: usize,
+    /// "Maximum length in bytes of a request's path and query; longer ones get 414 URI Too Long"
+    #[arg(long, default_value_t = request::DEFAULT_MAX_URI_LENGTH)]
+    max_uri_length: usize,
+    /// "Maximum size in bytes of a request body, unless its route sets its own; larger ones get
+    /// 413 Payload Too Large, before the body is read if its Content-Length gives it away"
+    #[arg(long, default_value_t = request::DEFAULT_MAX_BODY_SIZE)]
+    max_body_size: usize,
+    /// "Close a client's HTTP/1.x connection after serving this many requests on it (0 =
+    /// unlimited)"
+    #[arg(long, default_value = "0")]
+    max_requests_per_connection: usize,
+    /// "Accept TCP connections on this many SO_REUSEPORT sockets per listener, each served by a
+    /// single-threaded runtime on a thread of its own (0 = accept on the main runtime)"
+    #[arg(long, default_value = "0")]
+    reuseport_workers: usize,
+    /// "Pin each --reuseport-workers thread to a CPU core of its own"
+    #[arg(long, requires = "reuseport_workers")]
+    pin_workers: bool,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -40,74 +220,63 @@ struct CmdOptions {
 /// You should add fields to this struct in later milestones.
 struct ProxyState {
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
     active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Arc<tokio::sync::RwLock<Vec<String>>>,
-    // NOTE: originally upstream_addresses
-    original_upstream_addresses: Vec<String>,
-    // NOTE: limiter
-    limiter_map: Arc<tokio::sync::RwLock<HashMap<String, Arc<RateLimiter>>>>,
+    /// Every pool of upstream servers, including the "default" pool built from --upstream
+    pools: Vec<Arc<Pool>>,
+    /// What we listen on (--bind, then the config file's listeners), each with its own routes
+    listeners: Vec<Arc<Listener>>,
+    /// Pages for the errors we generate, unless a route has its own
+    error_pages: error_pages::ErrorPages,
+    /// Shared HTTP cache, if enabled with --cache-size
+    cache: Option<cache::Cache>,
+    /// Compresses responses for clients that accept it (and decompresses requests)
+    compressor: compression::Compressor,
+    /// Where to record every request, if --access-log was given
+    access_log: Option<access_log::AccessLog>,
+    /// Exports request spans, if --otlp-endpoint was given
+    tracer: Option<trace::Exporter>,
+    /// Lets shutdown wait for client connections to finish
+    shutdown: Arc<shutdown::Shutdown>,
+    /// How many clients each --reuseport-workers worker has accepted
+    workers_accepted: Vec<std::sync::atomic::AtomicU64>,
 }
 
 impl ProxyState {
-    pub async fn get_upstream_addresse(&self) -> Option<String> {
-        let addrs = self.upstream_addresses.read().await.clone();
-        if addrs.len() == 0 {
-            return None;
-        }
-        let upstream_idx = rand::thread_rng().gen_range(0..addrs.len());
-        let upstream_ip = &addrs[upstream_idx];
-        return Some(upstream_ip.clone());
+    /// Every listener's routes.
+    fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.listeners
+            .iter()
+            .flat_map(|listener| listener.routes.iter())
+    }
+
+    /// Puts the error page configured for an error we generated (the route's, or else the
+    /// global one) in its body.
+    fn apply_error_page(
+        &self,
+        route: Option<&Route>,
+        accept: Option<&http::HeaderValue>,
+        request_id: Option<&http::HeaderValue>,
+        response: &mut http::Response<Vec<u8>>,
+    ) {
+        let pages: Vec<_> = route
+            .map(|route| &route.error_pages)
+            .into_iter()
+            .chain([&self.error_pages])
+            .collect();
+        error_pages::apply(&pages, accept, request_id, response);
     }
 
-    pub async fn remove_upstream_address(&self, address: &str) {
-        let mut write = self.upstream_addresses.write().await;
-        write.retain(|x| x != address);
+    /// The error response for a request we couldn't accept.
+    fn request_error_response(&self, error: &request::Error) -> http::Response<Vec<u8>> {
+        let mut response = request_error_response(error);
+        self.apply_error_page(None, None, None, &mut response);
+        response
     }
 
     async fn health_check(&self) {
-        let mut addrs = Vec::new();
-        for i in &self.original_upstream_addresses {
-            let conn = tokio::net::TcpStream::connect(i.clone()).await;
-            if let Err(err) = conn {
-                log::error!("Failed to connect to upstream {}: {}", i, err);
-                continue;
-            }
-            let mut conn = conn.unwrap();
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&self.active_health_check_path)
-                .header("Host", i)
-                .body(Vec::<u8>::new())
-                .unwrap();
-            let r = request::write_to_stream(&request, &mut conn).await;
-            if let Err(err) = r {
-                log::error!("Failed to send health check request: {}", err);
-                continue;
-            }
-            let response = response::read_from_stream(&mut conn, request.method()).await;
-            if let Err(err) = response {
-                log::error!("Error reading response from server: {:?}", err);
-                continue;
-            }
-            let response = response.unwrap();
-            if response.status() == 500 {
-                log::error!("Server {} is down", i);
-                continue;
-            }
-            addrs.push(i.clone());
-        }
-        {
-            let mut write = self.upstream_addresses.write().await;
-            *write = addrs;
-        }
+        // A probe that outlasts the interval would only overlap with the next round's
+        let timeout = time::Duration::from_secs(self.active_health_check_interval as u64);
+        futures::future::join_all(self.pools.iter().map(|pool| pool.health_check(timeout))).await;
     }
 
     pub fn start_health_check(thiz: &Arc<ProxyState>) {
@@ -123,127 +292,1071 @@ impl ProxyState {
             }
         });
     }
+}
 
-    pub async fn get_limiter(&self, addr: &str) -> Option<Arc<RateLimiter>> {
-        if self.max_requests_per_minute == 0 {
-            return None;
+/// Per-connection state for one client. An HTTP/2 client shares it between all of its streams.
+struct ClientSession {
+    /// The listener the client connected to, whose routes and limits apply
+    listener: Arc<Listener>,
+    client_ip: String,
+    limit: Option<Arc<RateLimiter>>,
+    /// The upstream this client was sent to for each pool (by pool name). Routes that balance
+    /// per connection keep using it for later requests.
+    sticky_upstreams: parking_lot::Mutex<HashMap<String, String>>,
+    /// Connections this client has open to each upstream (by address)
+    connections: parking_lot::Mutex<HashMap<String, Arc<upstream::ConnectionPool>>>,
+}
+
+impl ClientSession {
+    async fn new(listener: Arc<Listener>, client_ip: String) -> ClientSession {
+        let mut session = ClientSession::without_limit(listener, client_ip);
+        session.limit = session.listener.get_limiter(&session.client_ip).await;
+        session
+    }
+
+    /// A session for requests balancebeam makes on a client's behalf (e.g. refreshing a cache
+    /// entry), which should not count against the client's rate limit.
+    fn without_limit(listener: Arc<Listener>, client_ip: String) -> ClientSession {
+        ClientSession {
+            listener,
+            client_ip,
+            limit: None,
+            sticky_upstreams: parking_lot::Mutex::new(HashMap::new()),
+            connections: parking_lot::Mutex::new(HashMap::new()),
         }
-        let mut write = self.limiter_map.write().await;
-        let mut result = None;
-        let limiter = write.get(addr);
-        if let None = limiter {
-            let limiter = Arc::new(RateLimiter::new(self.max_requests_per_minute as u32));
-            result = Some(limiter.clone());
-            write.insert(addr.to_string(), limiter);
-        } else {
-            result = Some(limiter.unwrap().clone());
+    }
+
+    /// Picks the upstream in `pool` that a request on this route should go to.
+    async fn choose_upstream(&self, route: &Route, pool: &Pool) -> Option<String> {
+        let sticky = !route.balance_per_request();
+        if sticky {
+            let address = self.sticky_upstreams.lock().get(&pool.name).cloned();
+            if let Some(address) = address {
+                if pool.knows(&address) && pool.breakers.admit(&address) {
+                    return Some(address);
+                }
+                // It has gone away or its circuit is open; move this client to another upstream
+                self.sticky_upstreams.lock().remove(&pool.name);
+            }
+        }
+        let address = pool.get_upstream_addresse().await?;
+        if sticky {
+            self.sticky_upstreams
+                .lock()
+                .insert(pool.name.clone(), address.clone());
+        }
+        Some(address)
+    }
+
+    /// Stops sending this client's requests to an upstream that has failed.
+    fn forget_upstream(&self, pool: &Pool, address: &str) {
+        let mut sticky_upstreams = self.sticky_upstreams.lock();
+        if sticky_upstreams.get(&pool.name).map(String::as_str) == Some(address) {
+            sticky_upstreams.remove(&pool.name);
         }
-        return result;
+        self.connections.lock().remove(address);
+    }
+
+    fn connections_to(
+        &self,
+        address: &str,
+        protocol: upstream::Protocol,
+    ) -> Arc<upstream::ConnectionPool> {
+        self.connections
+            .lock()
+            .entry(address.to_string())
+            .or_insert_with(|| {
+                Arc::new(upstream::ConnectionPool::new(address.to_string(), protocol))
+            })
+            .clone()
     }
 }
 
+/// Builds the upstream pools and listeners from the command line and the optional config file.
+#[allow(clippy::type_complexity)]
+fn build_listeners(
+    options: &CmdOptions,
+) -> Result<(Vec<Arc<Pool>>, Vec<Listener>, error_pages::ErrorPages), String> {
+    let config = match &options.config {
+        Some(path) => config::load(path)?,
+        None => config::Config::default(),
+    };
+    // NOTE: a rate of 0 would open every circuit, and one over 100 none
+    if !(1..=100).contains(&options.circuit_breaker_failure_rate) {
+        return Err(format!(
+            "--circuit-breaker-failure-rate must be a percentage from 1 to 100, not {}",
+            options.circuit_breaker_failure_rate
+        ));
+    }
+    let circuit_breaker = circuit_breaker::Config {
+        window: time::Duration::from_secs(options.circuit_breaker_window),
+        min_requests: options.circuit_breaker_min_requests.max(1),
+        failure_rate: options.circuit_breaker_failure_rate,
+        slow_call: (options.circuit_breaker_slow_call_ms > 0)
+            .then(|| time::Duration::from_millis(options.circuit_breaker_slow_call_ms)),
+        open_time: time::Duration::from_secs(options.circuit_breaker_open_time),
+        probes: options.circuit_breaker_probes.max(1),
+    };
+    let ewma_decay = time::Duration::from_millis(options.ewma_decay_ms);
+
+    let mut pools = HashMap::new();
+    if !options.upstream.is_empty() {
+        let health_check = match &options.active_health_check_grpc_service {
+            Some(service) => config::HealthCheckConfig::Grpc {
+                service: service.clone(),
+            },
+            None => config::HealthCheckConfig::Http {
+                path: options.active_health_check_path.clone(),
+            },
+        };
+        let pool = Pool::new(
+            "default".to_string(),
+            options.upstream.clone(),
+            options.upstream_protocol,
+            health_check,
+            overload::Concurrency {
+                max: options.max_upstream_requests,
+                adaptive: options.adaptive_concurrency,
+            },
+            options.circuit_breaker.then(|| circuit_breaker.clone()),
+            None,
+        )?
+        .with_slow_start(time::Duration::from_secs(options.slow_start))
+        .with_balancer(options.balancer, ewma_decay);
+        pools.insert(pool.name.clone(), Arc::new(pool));
+    }
+    for (name, pool_config) in config.pools {
+        if pools.contains_key(&name) {
+            return Err(format!("pool \"{}\" is defined more than once", name));
+        }
+        let health_check =
+            pool_config
+                .health_check
+                .unwrap_or_else(|| config::HealthCheckConfig::Http {
+                    path: options.active_health_check_path.clone(),
+                });
+        let pool = Pool::new(
+            name.clone(),
+            pool_config.upstreams,
+            pool_config.protocol,
+            health_check,
+            overload::Concurrency {
+                max: pool_config
+                    .max_upstream_requests
+                    .unwrap_or(options.max_upstream_requests),
+                adaptive: pool_config
+                    .adaptive_concurrency
+                    .unwrap_or(options.adaptive_concurrency),
+            },
+            pool_config
+                .circuit_breaker
+                .unwrap_or(options.circuit_breaker)
+                .then(|| circuit_breaker.clone()),
+            pool_config
+                .discovery
+                .as_ref()
+                .map(discovery::Source::new)
+                .transpose()
+                .map_err(|err| format!("pool \"{}\": {}", name, err))?,
+        )
+        .map_err(|err| format!("pool \"{}\": {}", name, err))?
+        .with_slow_start(time::Duration::from_secs(
+            pool_config.slow_start.unwrap_or(options.slow_start),
+        ))
+        .with_balancer(pool_config.balancer.unwrap_or(options.balancer), ewma_decay);
+        pools.insert(name, Arc::new(pool));
+    }
+    // A config whose routes all serve files needs no upstreams
+    if pools.is_empty()
+        && config
+            .routes
+            .iter()
+            .chain(
+                config
+                    .listeners
+                    .iter()
+                    .flat_map(|listener| listener.routes.iter().flatten()),
+            )
+            .all(|route| route.static_files.is_none())
+    {
+        return Err(
+            "at least one upstream server must be specified using the --upstream \
+            option or in a config file"
+                .to_string(),
+        );
+    }
+
+    let build_routes = |configs: &[config::RouteConfig]| {
+        let mut routes = configs
+            .iter()
+            .map(|route| Route::new(route, &pools))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(pool) = pools.get("default") {
+            routes.push(Route::catch_all(pool.clone()));
+        }
+        Ok::<_, String>(routes)
+    };
+    let request_limits = request::Limits {
+        max_headers_size: options.max_header_size,
+        max_num_headers: options.max_headers,
+        max_uri_length: options.max_uri_length,
+        max_body_size: options.max_body_size,
+    };
+    let mut listeners = Vec::new();
+    if options.bind.is_some() || config.listeners.is_empty() {
+        let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key)?),
+            _ => None,
+        };
+        listeners.push(Listener::new(
+            options
+                .bind
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BIND.to_string()),
+            build_routes(&config.routes)?,
+            tls_acceptor,
+            overload::ConnectionLimits::new(
+                options.max_connections,
+                options.max_connections_per_ip,
+            ),
+            request_limits,
+            options.max_requests_per_connection,
+            options.max_requests_per_minute,
+        ));
+    }
+    for listener in &config.listeners {
+        let in_listener = |err: String| format!("listener {}: {}", listener.bind, err);
+        if listeners.iter().any(|other| other.address == listener.bind) {
+            return Err(in_listener("address is bound more than once".to_string()));
+        }
+        let tls_acceptor = listener
+            .tls
+            .as_ref()
+            .map(|tls| tls::load_acceptor(&tls.cert, &tls.key))
+            .transpose()
+            .map_err(in_listener)?;
+        let routes = build_routes(listener.routes.as_ref().unwrap_or(&config.routes))
+            .map_err(in_listener)?;
+        listeners.push(Listener::new(
+            listener.bind.clone(),
+            routes,
+            tls_acceptor,
+            overload::ConnectionLimits::new(
+                listener.max_connections.unwrap_or(options.max_connections),
+                listener
+                    .max_connections_per_ip
+                    .unwrap_or(options.max_connections_per_ip),
+            ),
+            request::Limits {
+                max_body_size: listener.max_body_size.unwrap_or(options.max_body_size),
+                ..request_limits
+            },
+            listener
+                .max_requests_per_connection
+                .unwrap_or(options.max_requests_per_connection),
+            listener
+                .max_requests_per_minute
+                .unwrap_or(options.max_requests_per_minute),
+        ));
+    }
+    let error_pages = error_pages::ErrorPages::load(&config.error_pages)?;
+    Ok((pools.into_values().collect(), listeners, error_pages))
+}
+
 #[tokio::main]
 async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
-
-    // Start listening for connections
-    let listener = match tokio::net::TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
+    let (pools, listeners, error_pages) = match build_listeners(&options) {
+        Ok(built) => built,
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            log::error!("Invalid configuration: {}", err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    let listeners: Vec<Arc<Listener>> = listeners.into_iter().map(Arc::new).collect();
+
+    let cache = if options.cache_size > 0 {
+        match cache::Cache::new(
+            options.cache_size,
+            options.cache_max_object_size,
+            options.cache_dir.clone(),
+        ) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                log::error!("Could not set up the cache directory: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let access_log = match &options.access_log {
+        Some(destination) => {
+            match access_log::AccessLog::new(destination, options.access_log_format) {
+                Ok(access_log) => Some(access_log),
+                Err(err) => {
+                    log::error!("Could not open access log {}: {}", destination, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Start listening for connections (on the sockets of the process we are replacing, if we
+    // were started by a SIGUSR2 restart)
+    let inherited = shutdown::Inherited::from_env();
+    let mut sockets = Vec::new();
+    let mut listener_fds = Vec::new();
+    for listener in &listeners {
+        let socket = match inherited
+            .bind(&listener.address, options.reuseport_workers > 0)
+            .await
+        {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", listener.address, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Listening for requests on {}", listener.address);
+        listener_fds.push((listener.address.clone(), socket.as_raw_fd()));
+        sockets.push((socket, listener.clone()));
+    }
 
     // Handle incoming connections
     let state = Arc::new(ProxyState {
-        upstream_addresses: Arc::new(tokio::sync::RwLock::new(options.upstream.clone())),
         active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        original_upstream_addresses: options.upstream,
-        limiter_map: Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+        pools,
+        listeners,
+        error_pages,
+        cache,
+        compressor: compression::Compressor::new(
+            options.compression.clone(),
+            options.compression_mime_type.clone(),
+            options.compression_min_size,
+            options.decompress_requests,
+        ),
+        access_log,
+        tracer: options
+            .otlp_endpoint
+            .clone()
+            .map(|endpoint| trace::Exporter::new(endpoint, options.otlp_service_name.clone())),
+        shutdown: Arc::new(shutdown::Shutdown::new()),
+        workers_accepted: (0..options.reuseport_workers)
+            .map(|_| std::sync::atomic::AtomicU64::new(0))
+            .collect(),
     });
+    if let Err(err) = dns::start(&state.pools, options.dns_server.as_deref()).await {
+        log::error!("Could not set up upstream discovery: {}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = discovery::start(&state.pools).await {
+        log::error!("Could not set up upstream discovery: {}", err);
+        std::process::exit(1);
+    }
     ProxyState::start_health_check(&state);
-    loop {
+    if state.tracer.is_some() {
         let state = state.clone();
-        let (stream, _) = listener.accept().await.unwrap();
-        tokio::spawn(async move {
-            handle_connection(stream, &state).await;
-        });
+        tokio::spawn(async move { state.tracer.as_ref().unwrap().run().await });
+    }
+    if state.access_log.is_some() {
+        reopen_access_log_on_sigusr1(state.clone());
     }
+    if let Some(admin_bind) = &options.admin_bind {
+        match inherited.bind(admin_bind, false).await {
+            Ok(listener) => {
+                log::info!("Serving the admin interface on {}", admin_bind);
+                listener_fds.push((admin_bind.clone(), listener.as_raw_fd()));
+                tokio::spawn(admin::serve(listener, state.clone()));
+            }
+            Err(err) => {
+                log::error!("Could not bind admin interface to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(pid_file) = &options.pid_file {
+        if let Err(err) = std::fs::write(pid_file, format!("{}\n", std::process::id())) {
+            log::error!("Could not write pid file {:?}: {}", pid_file, err);
+            std::process::exit(1);
+        }
+    }
+
+    // Unix sockets have no SO_REUSEPORT to share connections with; they stay on the main runtime
+    let workers = if options.reuseport_workers > 0 {
+        let (tcp, unix) = sockets
+            .into_iter()
+            .partition(|(socket, _)| matches!(socket, socket::Listener::Tcp(_)));
+        sockets = unix;
+        match workers::Workers::start(
+            options.reuseport_workers,
+            options.pin_workers,
+            tcp,
+            &inherited,
+            &state,
+        )
+        .await
+        {
+            Ok(workers) => {
+                listener_fds.extend_from_slice(workers.fds());
+                Some(workers)
+            }
+            Err(err) => {
+                log::error!("Could not start workers: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    inherited.close_unused();
+
+    accept_until_shutdown(sockets, workers, &listener_fds, &state).await;
+    state
+        .shutdown
+        .drain(time::Duration::from_secs(options.shutdown_timeout))
+        .await;
+    log::info!("Exiting");
+    std::process::exit(0);
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<tokio::net::TcpStream, std::io::Error> {
+/// Accepts and serves clients on every listener (but those left to `workers`) until we receive
+/// SIGTERM or SIGINT, or hand our sockets over to a successor process on SIGUSR2.
+async fn accept_until_shutdown(
+    sockets: Vec<(socket::Listener, Arc<Listener>)>,
+    workers: Option<workers::Workers>,
+    listener_fds: &[(String, std::os::fd::RawFd)],
+    state: &Arc<ProxyState>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut sigterm, mut sigint, mut sigusr2) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::user_defined2()),
+    ) {
+        (Ok(sigterm), Ok(sigint), Ok(sigusr2)) => (sigterm, sigint, sigusr2),
+        _ => {
+            log::error!("Could not install signal handlers");
+            std::process::exit(1);
+        }
+    };
+    let accepting: Vec<_> = sockets
+        .into_iter()
+        .map(|(socket, listener)| {
+            tokio::spawn(accept_clients(socket, listener, None, state.clone()))
+        })
+        .collect();
     loop {
-        let addr = state.get_upstream_addresse().await;
-        if let None = addr {
-            continue;
+        tokio::select! {
+            _ = sigterm.recv() => {
+                log::info!("Received SIGTERM; shutting down");
+                break;
+            }
+            _ = sigint.recv() => {
+                log::info!("Received SIGINT; shutting down");
+                break;
+            }
+            _ = sigusr2.recv() => match shutdown::spawn_successor(listener_fds) {
+                Ok(pid) => {
+                    log::info!("Started successor process {}; handing over and shutting down", pid);
+                    break;
+                }
+                Err(err) => log::error!("Could not start successor process: {}", err),
+            },
         }
-        let addr = addr.unwrap();
-        let conn = tokio::net::TcpStream::connect(addr.clone()).await;
-        if let Err(err) = conn {
-            log::error!("Failed to connect to upstream {}: {}", addr, err);
-            state.remove_upstream_address(&addr).await;
-            continue;
+    }
+    // Stop accepting: from here on, connection attempts queue up for our successor (if any).
+    // Waiting for the tasks makes sure they have closed their sockets.
+    for task in accepting {
+        task.abort();
+        let _ = task.await;
+    }
+    if let Some(workers) = workers {
+        workers.stop().await;
+    }
+}
+
+/// Accepts clients on one listener, serving each one in a task of its own. `worker` is the
+/// index of the worker doing the accepting, if it isn't the main runtime.
+async fn accept_clients(
+    socket: socket::Listener,
+    listener: Arc<Listener>,
+    worker: Option<usize>,
+    state: Arc<ProxyState>,
+) {
+    loop {
+        let (stream, client_ip) = match socket.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::warn!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        if let Some(worker) = worker {
+            state.workers_accepted[worker].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        let state = state.clone();
+        let listener = listener.clone();
+        let guard = state.shutdown.track();
+        match listener.connection_limits.try_acquire(client_ip) {
+            Some(permit) => {
+                tokio::spawn(async move {
+                    serve_client(stream, client_ip, listener, &state).await;
+                    drop(permit);
+                    drop(guard);
+                });
+            }
+            None => {
+                log::warn!("Too many connections; turning away {}", client_ip);
+                tokio::spawn(async move {
+                    refuse_client(stream, &listener, &state).await;
+                    drop(guard);
+                });
+            }
         }
-        let conn = conn.unwrap();
-        return Ok(conn);
     }
 }
 
-async fn send_response(
-    client_conn: &mut tokio::net::TcpStream,
-    response: &http::Response<Vec<u8>>,
+/// Works out which protocol a freshly accepted client speaks (terminating TLS first, if
+/// configured) and hands the connection to the matching handler.
+async fn serve_client(
+    stream: socket::Stream,
+    client_ip: IpAddr,
+    listener: Arc<Listener>,
+    state: &Arc<ProxyState>,
 ) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
+    let client_ip = client_ip.to_string();
+    log::info!("Connection received from {}", client_ip);
+
+    if let Some(acceptor) = listener.tls_acceptor.as_ref() {
+        // NOTE: a client that never finishes the handshake would otherwise hold its connection
+        // (and its place under the connection limits) forever
+        let handshake = tokio::time::timeout(socket::HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+        let stream = match handshake.await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                log::info!("TLS handshake with {} failed: {}", client_ip, err);
+                return;
+            }
+            Err(_) => {
+                log::info!("TLS handshake with {} timed out", client_ip);
+                return;
+            }
+        };
+        if tls::negotiated_h2(&stream) {
+            handle_http2_connection(stream, client_ip, listener, state).await;
+        } else {
+            handle_connection(stream, client_ip, listener, state).await;
+        }
+    } else if stream.is_prior_knowledge().await {
+        handle_http2_connection(stream, client_ip, listener, state).await;
+    } else {
+        handle_connection(stream, client_ip, listener, state).await;
+    }
+}
+
+/// Answers a client we have no room for with 503 Service Unavailable and hangs up.
+async fn refuse_client(mut stream: socket::Stream, listener: &Listener, state: &ProxyState) {
+    // There's no cheap way to tell a TLS client why; just close the connection
+    if listener.tls_acceptor.is_some() {
+        return;
+    }
+    // Read the request first: if we hung up with it unread, the client could see a reset
+    // instead of our response
+    let mut buffered = Vec::new();
+    let read = request::read_from_stream(&mut stream, &mut buffered, &listener.request_limits);
+    if tokio::time::timeout(time::Duration::from_secs(1), read)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let mut response = overloaded_response();
+    state.apply_error_page(None, None, None, &mut response);
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+    let _ = response::write_to_stream(&response, &mut stream).await;
+}
+
+/// The error response for a request we couldn't accept.
+fn request_error_response(error: &request::Error) -> http::Response<Vec<u8>> {
+    response::make_http_error(match error {
+        request::Error::IncompleteRequest(_)
+        | request::Error::MalformedRequest(_)
+        | request::Error::InvalidContentLength
+        | request::Error::ContentLengthMismatch
+        | request::Error::Rejected(_) => http::StatusCode::BAD_REQUEST,
+        request::Error::UnsupportedTransferEncoding => http::StatusCode::NOT_IMPLEMENTED,
+        request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+        request::Error::ExpectationFailed => http::StatusCode::EXPECTATION_FAILED,
+        request::Error::HeadersTooLarge | request::Error::TooManyHeaders => {
+            http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        }
+        request::Error::UriTooLong => http::StatusCode::URI_TOO_LONG,
+        request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+    })
+}
+
+/// The response we shed load with.
+fn overloaded_response() -> http::Response<Vec<u8>> {
+    let mut response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+    response.headers_mut().insert(
+        http::header::RETRY_AFTER,
+        http::HeaderValue::from_static("1"),
+    );
+    response
+}
+
+async fn send_response<S>(client_conn: &mut S, client_ip: &str, response: &http::Response<Vec<u8>>)
+where
+    S: AsyncWrite + Unpin,
+{
+    log::debug!(
         "{} <- {}",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Reopens the access log file whenever we receive SIGUSR1 (e.g. from logrotate's postrotate).
+fn reopen_access_log_on_sigusr1(state: Arc<ProxyState>) {
+    let mut signals =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(err) => {
+                log::error!("Could not listen for SIGUSR1: {}", err);
+                return;
+            }
+        };
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if let Some(access_log) = &state.access_log {
+                access_log.reopen();
+            }
+        }
+    });
+}
+
+/// Returns an error response if the client has exceeded its rate limit.
+async fn check_rate_limit(session: &ClientSession) -> Option<http::Response<Vec<u8>>> {
+    let limit = session.limit.as_ref()?;
+    if limit.acquire().await {
+        return None;
+    }
+    log::warn!(
+        "Rate limit exceeded for {} rate limit {}",
+        session.client_ip,
+        limit.rate()
+    );
+    // NOTE: hint limit
+    let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+    response.extensions_mut().insert(access_log::RateLimited);
+    Some(response)
+}
+
+/// Routes a request to an upstream server and returns the response that should be sent back to
+/// the client (which is an error response if the request couldn't be proxied).
+async fn proxy_request(
+    state: &Arc<ProxyState>,
+    session: &ClientSession,
+    mut request: http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    // Tag the request so it can be correlated with the upstream's logs (and ours)
+    let request_id = trace::ensure_request_id(&mut request);
+    let span = state
+        .tracer
+        .as_ref()
+        .map(|_| trace::Span::start(&mut request, &session.client_ip, &request_id));
+    let info = state.access_log.as_ref().map(|_| {
+        access_log::RequestInfo::new(&request, &session.client_ip, session.limit.is_some())
+    });
+    let accept = request.headers().get(http::header::ACCEPT).cloned();
+    let route = route::find(&session.listener.routes, &request);
+
+    let mut response = route_request(state, session, request).await;
+    state.apply_error_page(route, accept.as_ref(), Some(&request_id), &mut response);
+    response
+        .headers_mut()
+        .insert(trace::REQUEST_ID_HEADER, request_id);
+
+    if let (Some(tracer), Some(span)) = (&state.tracer, span) {
+        tracer.end(span, &response);
+    }
+    if let (Some(access_log), Some(info)) = (&state.access_log, info) {
+        access_log.record(&info, &response);
+    }
+    response
+}
+
+async fn route_request(
+    state: &Arc<ProxyState>,
+    session: &ClientSession,
+    mut request: http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let route = match route::find(&session.listener.routes, &request) {
+        Some(route) => route,
+        None => {
+            log::debug!(
+                "{} -> no route: {}",
+                session.client_ip,
+                request::format_request_line(&request)
+            );
+            return response::make_http_error(http::StatusCode::NOT_FOUND);
+        }
+    };
+
+    if let Some(response) = route.filter.check(&request, &session.client_ip) {
+        return response;
+    }
+    if let Some(response) = check_rate_limit(session).await {
+        return response;
+    }
+    // After the rate limit, so that guessing passwords counts against it
+    if let Some(auth) = &route.auth {
+        if let Err(response) = auth.authenticate(&mut request).await {
+            return response;
+        }
+    }
+
+    // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+    // (We're the ones connecting directly to the upstream server, so without this header, the
+    // upstream server will only know our IP, not the client's.)
+    request::extend_header_value(&mut request, "x-forwarded-for", &session.client_ip);
+
+    match state.compressor.decompress_request(&mut request) {
+        Ok(()) => {}
+        Err(compression::Error::TooLarge) => {
+            return response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE)
+        }
+        Err(compression::Error::Malformed(_)) => {
+            return response::make_http_error(http::StatusCode::BAD_REQUEST)
+        }
+    }
+
+    let method = request.method().clone();
+    let accept_encoding = request
+        .headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .cloned();
+    let pool = match route.choose_pool(&request) {
+        Some(pool) => pool,
+        None => {
+            let response = match &route.files {
+                Some(files) => files.serve(&request).await,
+                None => response::make_http_error(http::StatusCode::NOT_FOUND),
+            };
+            return state
+                .compressor
+                .compress_response(&method, accept_encoding.as_ref(), response)
+                .await;
+        }
+    };
+    let mirrored = route
+        .mirror
+        .as_ref()
+        .and_then(|mirror| mirror.mirror(&request));
+    // The cache holds the route's own pool's responses; canary requests bypass it, so that
+    // the canary sees (and its stats reflect) the same traffic the route's pool would have
+    let cache = state.cache.as_ref().filter(|_| {
+        route
+            .pool
+            .as_ref()
+            .is_some_and(|own| Arc::ptr_eq(pool, own))
+    });
+    let scope = cache_scope(&session.listener, pool);
+    let started = time::Instant::now();
+    let response = match cache {
+        Some(cache) if cache::is_cacheable_request(&request) => {
+            fetch_through_cache(state, cache, &scope, session, route, pool, request).await
+        }
+        Some(cache) if cache::invalidates(&request) => {
+            let invalidated = request::clone_request(&request);
+            let response = forward_request(session, route, pool, request).await;
+            if response.status().is_success() || response.status().is_redirection() {
+                cache.invalidate(&scope, &invalidated);
+            }
+            response
+        }
+        _ => forward_request(session, route, pool, request).await,
+    };
+    if let Some(mirrored) = mirrored {
+        let _ = mirrored.send(mirror::Outcome {
+            status: response.status(),
+            latency: started.elapsed(),
+        });
+    }
+    state
+        .compressor
+        .compress_response(&method, accept_encoding.as_ref(), response)
+        .await
+}
+
+/// Where a request was sent, as far as the cache is concerned: the same URL may be served by
+/// different upstreams on another listener, or on a route to another pool.
+fn cache_scope(listener: &Listener, pool: &Pool) -> String {
+    format!("{} {}", listener.address, pool.name)
+}
+
+/// Answers a GET or HEAD request from the cache if possible, going to the upstream (and storing
+/// what it returns) otherwise.
+async fn fetch_through_cache(
+    state: &Arc<ProxyState>,
+    cache: &cache::Cache,
+    scope: &str,
+    session: &ClientSession,
+    route: &Route,
+    pool: &Pool,
+    request: http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    match cache.lookup(scope, &request) {
+        cache::Lookup::Fresh(stored) => {
+            if let Some(response) = stored.into_response(&request, "HIT").await {
+                log::debug!(
+                    "{} -> cache: {}",
+                    session.client_ip,
+                    request::format_request_line(&request)
+                );
+                return response;
+            }
+        }
+        cache::Lookup::StaleWhileRevalidate { stored, revalidate } => {
+            if let Some(conditional) = revalidate {
+                let state = state.clone();
+                let listener = session.listener.clone();
+                let client_ip = session.client_ip.clone();
+                let request = request::clone_request(&request);
+                tokio::spawn(async move {
+                    revalidate_in_background(state, listener, client_ip, request, conditional)
+                        .await;
+                });
+            }
+            if let Some(response) = stored.into_response(&request, "STALE").await {
+                log::debug!(
+                    "{} -> cache (stale): {}",
+                    session.client_ip,
+                    request::format_request_line(&request)
+                );
+                return response;
+            }
+        }
+        cache::Lookup::Revalidate(conditional) => {
+            let mut conditional_request = request::clone_request(&request);
+            conditional_request.headers_mut().extend(conditional);
+            let response = forward_request(session, route, pool, conditional_request).await;
+            if response.status() != http::StatusCode::NOT_MODIFIED {
+                cache.store(scope, &request, &response).await;
+                return response;
+            }
+            if let Some(stored) = cache.freshen(scope, &request, &response) {
+                if let Some(response) = stored.into_response(&request, "REVALIDATED").await {
+                    return response;
+                }
+            }
+            // The entry disappeared while we were revalidating it; fetch it afresh
+        }
+        cache::Lookup::Miss => {}
+    }
+    let store_request = request::clone_request(&request);
+    let response = forward_request(session, route, pool, request).await;
+    cache.store(scope, &store_request, &response).await;
+    response
+}
+
+/// Refreshes a stale cache entry that was just served under stale-while-revalidate.
+async fn revalidate_in_background(
+    state: Arc<ProxyState>,
+    listener: Arc<Listener>,
+    client_ip: String,
+    request: http::Request<Vec<u8>>,
+    conditional: http::HeaderMap,
+) {
+    let (cache, route, pool) = match (&state.cache, route::find(&listener.routes, &request)) {
+        (
+            Some(cache),
+            Some(
+                route @ Route {
+                    pool: Some(pool), ..
+                },
+            ),
+        ) => (cache, route, pool),
+        _ => return,
+    };
+    let scope = cache_scope(&listener, pool);
+    let session = ClientSession::without_limit(listener.clone(), client_ip);
+    let mut conditional_request = request::clone_request(&request);
+    conditional_request.headers_mut().extend(conditional);
+    let response = forward_request(&session, route, pool, conditional_request).await;
+    if response.status() == http::StatusCode::NOT_MODIFIED {
+        cache.freshen(&scope, &request, &response);
         return;
     }
+    if !response.status().is_server_error() {
+        cache.store(&scope, &request, &response).await;
+    }
+    // If nothing replaced the entry, let a later request try refreshing it again
+    cache.revalidation_failed(&scope, &request);
 }
 
-async fn handle_connection(mut client_conn: tokio::net::TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    let client_port = client_conn.peer_addr().unwrap().port();
-    log::info!("Connection received from {}", client_ip);
+/// Sends a request to an upstream in `pool` (the route's own, or its canary's) and returns its
+/// response (or an error response if no upstream could be reached).
+async fn forward_request(
+    session: &ClientSession,
+    route: &Route,
+    pool: &Pool,
+    request: http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    // Find a live upstream to send the request to, failing over if we can't connect
+    let (upstream_ip, connections, mut upstream_conn, permit) = loop {
+        let address = match session.choose_upstream(route, pool).await {
+            Some(address) => address,
+            None => {
+                log::error!("No live upstream servers in pool {}", pool.name);
+                pool.stats.record(time::Duration::ZERO, false);
+                return response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            }
+        };
+        let permit = match pool.limits.try_acquire(&address) {
+            Some(permit) => permit,
+            None => {
+                log::warn!(
+                    "Upstream {} has too many requests in flight; shedding",
+                    address
+                );
+                return overloaded_response();
+            }
+        };
+        let connections = session.connections_to(&address, pool.protocol);
+        match connections.get().await {
+            Ok(conn) => break (address, connections, conn, permit),
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", address, err);
+                pool.breakers.record(&address, time::Duration::ZERO, false);
+                pool.remove_upstream_address(&address).await;
+                session.forget_upstream(pool, &address);
+            }
+        }
+    };
+    log::debug!(
+        "{} -> {}: {}",
+        session.client_ip,
+        upstream_ip,
+        request::format_request_line(&request)
+    );
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+    let is_grpc = grpc::is_grpc(&request);
+    let method = request.method().clone();
+    let pending = pool.track(&upstream_ip);
+    let sent_at = time::Instant::now();
+    let result = upstream_conn.send(request, &upstream_ip).await;
+    let timing = access_log::UpstreamTiming {
+        address: upstream_ip.clone(),
+        latency: sent_at.elapsed(),
+    };
+    let succeeded = result.as_ref().is_ok_and(|response| {
+        let unavailable = is_grpc && grpc::status(response) == Some(grpc::STATUS_UNAVAILABLE);
+        !response.status().is_server_error() && !unavailable
+    });
+    permit.finish(timing.latency, succeeded);
+    pending.finish(timing.latency, succeeded);
+    pool.breakers
+        .record(&upstream_ip, timing.latency, succeeded);
+    pool.stats.record(timing.latency, succeeded);
+    let mut response = match result {
+        Ok(response) => response,
+        Err(error) => {
+            log::error!("Error forwarding request to {}: {:?}", upstream_ip, error);
+            session.forget_upstream(pool, &upstream_ip);
+            let mut response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            response.extensions_mut().insert(timing);
+            return response;
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
-    let limit = state.get_limiter(&client_ip).await;
+    // NOTE: a connection that is closing, or whose end marked the end of the body, has nothing
+    // more to give
+    if !response::is_close_delimited(&response, &method) && !response::closes_connection(&response)
+    {
+        connections.put(upstream_conn);
+    }
+    response.extensions_mut().insert(timing);
+
+    // gRPC servers answer with HTTP 200 even when they fail; UNAVAILABLE in grpc-status is how
+    // they say they can't serve right now, so treat it like a failed connection.
+    if is_grpc && grpc::status(&response) == Some(grpc::STATUS_UNAVAILABLE) {
+        log::warn!(
+            "Upstream {} reported gRPC status UNAVAILABLE; removing it from pool {}",
+            upstream_ip,
+            pool.name
+        );
+        pool.remove_upstream_address(&upstream_ip).await;
+        session.forget_upstream(pool, &upstream_ip);
+    }
+    response
+}
+
+async fn handle_connection<S>(
+    mut client_conn: S,
+    client_ip: String,
+    listener: Arc<Listener>,
+    state: &Arc<ProxyState>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let session = ClientSession::new(listener, client_ip).await;
+    let listener = &session.listener;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    let mut idle_keep_alive = false;
+    let mut requests_served = 0;
+    // Bytes the client sent past the end of the request we last read (e.g. a pipelined request)
+    let mut buffered = Vec::new();
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request from the client. If we start shutting down while a kept-alive connection
+        // is waiting for its next request, close it; a brand new connection still gets its first
+        // request served.
+        let request = tokio::select! {
+            request = request::read_headers(
+                &mut client_conn,
+                &mut buffered,
+                &listener.request_limits,
+            ) => request,
+            _ = state.shutdown.draining(), if idle_keep_alive => {
+                log::debug!("Closing idle connection from {} for shutdown", session.client_ip);
+                return;
+            }
+        };
+        // Read the body once we know which route's limit applies to it
+        let request = match request {
+            Ok(mut request) => {
+                let max_body_size = listener.max_body_size(&request);
+                request::read_body_from_stream(
+                    &mut client_conn,
+                    &mut buffered,
+                    &mut request,
+                    max_body_size,
+                )
+                .await
+                .map(|()| request)
+            }
+            Err(error) => Err(error),
+        };
+        let request = match request {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -256,71 +1369,175 @@ async fn handle_connection(mut client_conn: tokio::net::TcpStream, state: &Proxy
                 return;
             }
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
+                match &error {
+                    request::Error::Rejected(reason) => {
+                        log::info!("Rejected request from {}: {}", session.client_ip, reason)
+                    }
+                    _ => log::debug!("Error parsing request: {:?}", error),
+                }
+                // NOTE: we stopped reading partway through a request we refused, so we can't
+                // tell where the next one starts. Guessing is how requests get smuggled past
+                // us, so hang up instead.
+                let mut response = state.request_error_response(&error);
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+                send_response(&mut client_conn, &session.client_ip, &response).await;
+                return;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
 
-        if let Some(limit) = limit.as_ref() {
-            if !limit.acquire().await {
-                log::warn!(
-                    "Rate limit exceeded for {} rate limit {}",
-                    client_ip,
-                    limit.rate()
-                );
-                // NOTE: hint limit
-                let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                send_response(&mut client_conn, &response).await;
-                continue;
-            }
+        let method = request.method().clone();
+        let version = request.version();
+        let keep_alive = request::keeps_alive(version, request.headers());
+        requests_served += 1;
+        let mut response = proxy_request(state, &session, request).await;
+        http2::downgrade_response(&mut response);
+        // The upstream's Connection header was about its connection to us, not this one
+        request::remove_hop_by_hop_headers(response.headers_mut());
+        *response.version_mut() = http::Version::HTTP_11;
+        // This is the connection's last response if the client asked for that, if it has used
+        // up its requests, or if we're shutting down. So is a response whose body only ends when
+        // the connection does.
+        let last_response = !keep_alive
+            || requests_served == listener.max_requests_per_connection
+            || state.shutdown.is_draining()
+            || response::is_close_delimited(&response, &method);
+        if last_response {
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
+            );
+        } else if version == http::Version::HTTP_10 {
+            // HTTP/1.0 clients assume we'll hang up unless we say otherwise
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("keep-alive"),
+            );
         }
+        // Forward the response to the client
+        send_response(&mut client_conn, &session.client_ip, &response).await;
+        log::debug!("Forwarded response to client");
+        if last_response {
+            return;
+        }
+        idle_keep_alive = true;
+    }
+}
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
-
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+/// Serves an HTTP/2 client connection. Every stream the client opens is proxied concurrently,
+/// sharing upstream connections: one HTTP/2 connection per upstream, or a pool of HTTP/1.1 ones.
+async fn handle_http2_connection<S>(
+    client_conn: S,
+    client_ip: String,
+    listener: Arc<Listener>,
+    state: &Arc<ProxyState>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = match h2::server::Builder::new()
+        .max_header_list_size(listener.request_limits.max_headers_size as u32)
+        .handshake(client_conn)
+        .await
+    {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client_ip, err);
             return;
         }
-        log::debug!("Forwarded request to server");
+    };
+    let session = Arc::new(ClientSession::new(listener, client_ip).await);
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+    let mut going_away = false;
+    loop {
+        // On shutdown, send GOAWAY but keep driving the connection until the streams the client
+        // already opened are done
+        let result = tokio::select! {
+            result = connection.accept() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = state.shutdown.draining(), if !going_away => {
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            }
+        };
+        let (request, respond) = match result {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::info!(
+                    "Error reading from HTTP/2 client {}: {}",
+                    session.client_ip,
+                    err
+                );
                 return;
             }
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        let state = state.clone();
+        let session = session.clone();
+        let guard = state.shutdown.track();
+        tokio::spawn(async move {
+            let response = handle_http2_stream(&state, &session, request).await;
+            if let Some(mut response) = response {
+                http2::upgrade_response(&mut response);
+                log::debug!(
+                    "{} <- {}",
+                    session.client_ip,
+                    response::format_response_line(&response)
+                );
+                if let Err(err) = http2::send_response(respond, response) {
+                    log::warn!("Failed to send response to client: {:?}", err);
+                }
+            }
+            drop(guard);
+        });
+    }
+    log::debug!("Client finished sending requests. Shutting down connection");
+}
+
+/// Proxies a single HTTP/2 stream, returning the response to send back (or None if the client
+/// reset the stream).
+async fn handle_http2_stream(
+    state: &Arc<ProxyState>,
+    session: &ClientSession,
+    request: http::Request<h2::RecvStream>,
+) -> Option<http::Response<Vec<u8>>> {
+    let (mut parts, body) = request.into_parts();
+    // Routes match (and upstreams get) the normalized path, as for HTTP/1 requests
+    if let Err(reason) = request::normalize_path(&mut parts.uri) {
+        log::info!("Rejected request from {}: {}", session.client_ip, reason);
+        return Some(state.request_error_response(&request::Error::Rejected(reason)));
+    }
+    // Refuse what we can before the client sends the body. (We don't send 100 Continue: the
+    // SendResponse of h2 0.3 only sends a stream's final response, with no way to send a 1xx
+    // before it, so a client waiting for one sends the body once it gives up waiting.)
+    let head = http::Request::from_parts(parts, Vec::new());
+    let max_body_size = session.listener.max_body_size(&head);
+    if let Err(error) = request::check_before_body(&head, max_body_size) {
+        return Some(state.request_error_response(&error));
+    }
+    let (mut parts, _) = head.into_parts();
+    parts.headers.remove(http::header::EXPECT);
+    let body = match http2::read_body(body, max_body_size).await {
+        Ok((body, trailers)) => {
+            if let Some(trailers) = trailers {
+                parts.extensions.insert(http2::Trailers(trailers));
+            }
+            body
+        }
+        Err(http2::Error::BodyTooLarge) => {
+            return Some(state.request_error_response(&request::Error::RequestBodyTooLarge))
+        }
+        Err(error) => {
+            log::info!("Error reading request from client stream: {:?}", error);
+            return None;
+        }
+    };
+    let request = http::Request::from_parts(parts, body);
+    if let Err(error) = session.listener.request_limits.check(&request) {
+        return Some(state.request_error_response(&error));
     }
+    Some(proxy_request(state, session, request).await)
 }
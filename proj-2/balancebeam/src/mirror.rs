@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::sync::oneshot;
+
+use crate::config::MirrorConfig;
+use crate::pool::Pool;
+use crate::{auth, request, upstream};
+
+/// Give up on a shadow request after this long.
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that carry who the client is, which a shadow pool (often a build that isn't trusted
+/// with production credentials yet) doesn't get unless the route says so.
+const CREDENTIAL_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    auth::USER_HEADER,
+];
+
+/// Don't let more than this many shadow requests pile up on a slow shadow pool; requests beyond
+/// it aren't mirrored.
+const MAX_IN_FLIGHT: usize = 100;
+
+/// How mirrored requests have compared to the requests they copied.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Requests copied to the shadow pool
+    pub mirrored: u64,
+    /// Requests that were sampled but not copied, because too many copies were in flight
+    pub skipped: u64,
+    /// Copies the shadow pool didn't answer (no upstream, connection failure or timeout)
+    pub failed: u64,
+    /// Copies the shadow pool answered with a different status than the primary response
+    pub status_mismatches: u64,
+    /// Total time the primary and shadow responses took, over the copies that got one
+    pub primary_latency: Duration,
+    pub shadow_latency: Duration,
+}
+
+impl Stats {
+    pub fn add(&mut self, other: &Stats) {
+        self.mirrored += other.mirrored;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.status_mismatches += other.status_mismatches;
+        self.primary_latency += other.primary_latency;
+        self.shadow_latency += other.shadow_latency;
+    }
+}
+
+/// Sends copies of some of a route's requests to a shadow pool, discarding its responses.
+pub struct Mirror {
+    pub pool: Arc<Pool>,
+    /// Percentage of requests to copy
+    percent: f64,
+    /// Whether copies keep the client's credentials
+    forward_credentials: bool,
+    in_flight: parking_lot::Mutex<usize>,
+    stats: parking_lot::Mutex<Stats>,
+}
+
+/// How the primary request went, for comparing the shadow request against.
+pub struct Outcome {
+    pub status: http::StatusCode,
+    pub latency: Duration,
+}
+
+impl Mirror {
+    pub fn new(
+        config: &MirrorConfig,
+        pools: &HashMap<String, Arc<Pool>>,
+    ) -> Result<Mirror, String> {
+        let pool = pools
+            .get(&config.pool)
+            .ok_or_else(|| format!("route mirrors to unknown pool \"{}\"", config.pool))?
+            .clone();
+        if !(0.0..=100.0).contains(&config.percent) {
+            return Err(format!(
+                "mirror percentage {} is not between 0 and 100",
+                config.percent
+            ));
+        }
+        Ok(Mirror {
+            pool,
+            percent: config.percent,
+            forward_credentials: config.forward_credentials,
+            in_flight: parking_lot::Mutex::new(0),
+            stats: parking_lot::Mutex::new(Stats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().clone()
+    }
+
+    /// Decides whether to copy a request and, if so, sends the copy in the background. The
+    /// returned sender takes the primary request's outcome once it is known; dropping it instead
+    /// still lets the copy go through, just without a comparison.
+    pub fn mirror(
+        self: &Arc<Self>,
+        request: &http::Request<Vec<u8>>,
+    ) -> Option<oneshot::Sender<Outcome>> {
+        if !rand::thread_rng().gen_bool(self.percent / 100.0) {
+            return None;
+        }
+        {
+            let mut in_flight = self.in_flight.lock();
+            if *in_flight >= MAX_IN_FLIGHT {
+                self.stats.lock().skipped += 1;
+                return None;
+            }
+            *in_flight += 1;
+        }
+        let mut copy = request::clone_request(request);
+        if !self.forward_credentials {
+            for name in CREDENTIAL_HEADERS {
+                copy.headers_mut().remove(*name);
+            }
+        }
+        let (outcome_tx, outcome_rx) = oneshot::channel::<Outcome>();
+        let mirror = self.clone();
+        tokio::spawn(async move {
+            let request_line = request::format_request_line(&copy);
+            let shadow = tokio::time::timeout(SHADOW_TIMEOUT, mirror.send(copy)).await;
+            *mirror.in_flight.lock() -= 1;
+            let shadow = match shadow {
+                Ok(Ok(shadow)) => shadow,
+                Ok(Err(err)) => {
+                    log::warn!(
+                        "mirror: {} to pool {} failed: {}",
+                        request_line,
+                        mirror.pool.name,
+                        err
+                    );
+                    mirror.record_failure();
+                    return;
+                }
+                Err(_) => {
+                    log::warn!(
+                        "mirror: {} to pool {} timed out",
+                        request_line,
+                        mirror.pool.name
+                    );
+                    mirror.record_failure();
+                    return;
+                }
+            };
+            let primary = outcome_rx.await.ok();
+            let mut stats = mirror.stats.lock();
+            stats.mirrored += 1;
+            if let Some(primary) = primary {
+                stats.primary_latency += primary.latency;
+                stats.shadow_latency += shadow.latency;
+                if primary.status != shadow.status {
+                    stats.status_mismatches += 1;
+                    log::info!(
+                        "mirror: {} got {} from the primary but {} from pool {}",
+                        request_line,
+                        primary.status,
+                        shadow.status,
+                        mirror.pool.name
+                    );
+                } else {
+                    log::debug!(
+                        "mirror: {} took {:?} from the primary and {:?} from pool {}",
+                        request_line,
+                        primary.latency,
+                        shadow.latency,
+                        mirror.pool.name
+                    );
+                }
+            }
+        });
+        Some(outcome_tx)
+    }
+
+    fn record_failure(&self) {
+        let mut stats = self.stats.lock();
+        stats.mirrored += 1;
+        stats.failed += 1;
+    }
+
+    /// Sends a copy to an upstream of the shadow pool on a connection of its own, so that it
+    /// can't hold up any client's requests.
+    async fn send(&self, request: http::Request<Vec<u8>>) -> Result<Outcome, String> {
+        let address = self
+            .pool
+            .get_upstream_addresse()
+            .await
+            .ok_or_else(|| "no live upstreams".to_string())?;
+        let started = Instant::now();
+        let mut connection = upstream::Connection::connect(&address, self.pool.protocol)
+            .await
+            .map_err(|err| format!("could not connect to {}: {}", address, err))?;
+        let response = connection
+            .send(request, &address)
+            .await
+            .map_err(|err| format!("error from {}: {:?}", address, err))?;
+        Ok(Outcome {
+            status: response.status(),
+            latency: started.elapsed(),
+        })
+    }
+}
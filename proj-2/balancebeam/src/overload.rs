@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Limit we start an adaptive upstream at, before we know anything about its latency
+const INITIAL_ADAPTIVE_LIMIT: f64 = 20.0;
+/// Ceiling for adaptive limits when --max-upstream-requests doesn't set one
+const MAX_ADAPTIVE_LIMIT: f64 = 1000.0;
+/// How many samples the long-term latency average roughly covers
+const LONG_WINDOW: f64 = 600.0;
+/// How much slower than its long-term average an upstream may get before we back off
+const LATENCY_TOLERANCE: f64 = 1.5;
+/// Weight of each new estimate in the limit, so that a single slow request can't halve it
+const SMOOTHING: f64 = 0.2;
+/// Applied to the limit whenever an upstream fails a request
+const BACKOFF: f64 = 0.9;
+
+/// Caps how many client connections we serve at once, overall and from any one IP.
+pub struct ConnectionLimits {
+    /// 0 means unlimited (as do the other limits below)
+    max_total: usize,
+    max_per_ip: usize,
+    /// Open connections: (total, by client IP)
+    open: parking_lot::Mutex<(usize, HashMap<IpAddr, usize>)>,
+}
+
+/// Held for as long as a client connection is open.
+pub struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock();
+        open.0 -= 1;
+        if let Some(count) = open.1.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.1.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(max_total: usize, max_per_ip: usize) -> ConnectionLimits {
+        ConnectionLimits {
+            max_total,
+            max_per_ip,
+            open: parking_lot::Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Admits a new connection from `ip`, or returns None if that would exceed a limit.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionPermit> {
+        let mut open = self.open.lock();
+        let from_ip = open.1.get(&ip).copied().unwrap_or(0);
+        if (self.max_total > 0 && open.0 >= self.max_total)
+            || (self.max_per_ip > 0 && from_ip >= self.max_per_ip)
+        {
+            return None;
+        }
+        open.0 += 1;
+        open.1.insert(ip, from_ip + 1);
+        Some(ConnectionPermit {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// How many requests may be in flight to each upstream of a pool.
+#[derive(Clone, Copy, Debug)]
+pub struct Concurrency {
+    /// Fixed limit (or, if adaptive, the most the limit may grow to); 0 means unlimited
+    pub max: usize,
+    /// Adjust each upstream's limit to its latency, using the gradient algorithm
+    pub adaptive: bool,
+}
+
+/// Per-upstream in-flight request limits for one pool.
+pub struct UpstreamLimits {
+    concurrency: Concurrency,
+    limiters: parking_lot::Mutex<HashMap<String, Arc<Limiter>>>,
+}
+
+struct Limiter {
+    state: parking_lot::Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    in_flight: usize,
+    limit: f64,
+    /// Exponential moving average of latency, in seconds (None until the first sample)
+    long_latency: Option<f64>,
+}
+
+/// Held while a request is in flight to an upstream. Call `finish` once the upstream has
+/// answered, so that adaptive limits can learn from the latency.
+pub struct RequestPermit {
+    limiter: Option<Arc<Limiter>>,
+    ceiling: f64,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.state.lock().in_flight -= 1;
+        }
+    }
+}
+
+impl RequestPermit {
+    /// Records how long the upstream took to answer, and whether it succeeded.
+    pub fn finish(self, latency: Duration, succeeded: bool) {
+        if self.ceiling == 0.0 {
+            return;
+        }
+        let limiter = match &self.limiter {
+            Some(limiter) => limiter,
+            None => return,
+        };
+        let mut state = limiter.state.lock();
+        if !succeeded {
+            state.limit = (state.limit * BACKOFF).max(1.0);
+            return;
+        }
+        let sample = latency.as_secs_f64().max(1e-6);
+        let long = match state.long_latency {
+            Some(long) => long + (sample - long) / LONG_WINDOW,
+            None => sample,
+        };
+        state.long_latency = Some(long);
+
+        // Gradient below 1 means the upstream is slowing down (queueing), so shrink the limit;
+        // the sqrt term leaves headroom to probe for more capacity when it isn't
+        let gradient = (LATENCY_TOLERANCE * long / sample).clamp(0.5, 1.0);
+        let estimate = state.limit * gradient + state.limit.sqrt();
+        // NOTE: don't grow while the upstream isn't even half busy, since we'd be guessing
+        if estimate > state.limit && (state.in_flight as f64) < state.limit / 2.0 {
+            return;
+        }
+        state.limit =
+            (state.limit * (1.0 - SMOOTHING) + estimate * SMOOTHING).clamp(1.0, self.ceiling);
+    }
+}
+
+impl UpstreamLimits {
+    pub fn new(concurrency: Concurrency) -> UpstreamLimits {
+        UpstreamLimits {
+            concurrency,
+            limiters: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ceiling(&self) -> f64 {
+        match (self.concurrency.max, self.concurrency.adaptive) {
+            (0, true) => MAX_ADAPTIVE_LIMIT,
+            (max, true) => max as f64,
+            // Fixed limits never change, so there's nothing to learn
+            (_, false) => 0.0,
+        }
+    }
+
+    /// Admits a request to `address`, or returns None if it already has as many in flight as
+    /// it is allowed.
+    pub fn try_acquire(&self, address: &str) -> Option<RequestPermit> {
+        if self.concurrency.max == 0 && !self.concurrency.adaptive {
+            return Some(RequestPermit {
+                limiter: None,
+                ceiling: 0.0,
+            });
+        }
+        let ceiling = self.ceiling();
+        let limiter = self
+            .limiters
+            .lock()
+            .entry(address.to_string())
+            .or_insert_with(|| {
+                let limit = if self.concurrency.adaptive {
+                    INITIAL_ADAPTIVE_LIMIT.min(ceiling)
+                } else {
+                    self.concurrency.max as f64
+                };
+                Arc::new(Limiter {
+                    state: parking_lot::Mutex::new(LimiterState {
+                        in_flight: 0,
+                        limit,
+                        long_latency: None,
+                    }),
+                })
+            })
+            .clone();
+        {
+            let mut state = limiter.state.lock();
+            if state.in_flight as f64 >= state.limit.floor() {
+                return None;
+            }
+            state.in_flight += 1;
+        }
+        Some(RequestPermit {
+            limiter: Some(limiter),
+            ceiling,
+        })
+    }
+
+    /// Current (limit, in flight) for every upstream that has received a request.
+    pub fn snapshot(&self) -> HashMap<String, (usize, usize)> {
+        self.limiters
+            .lock()
+            .iter()
+            .map(|(address, limiter)| {
+                let state = limiter.state.lock();
+                (address.clone(), (state.limit as usize, state.in_flight))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::config::HealthCheckConfig;
+use crate::{balancer, circuit_breaker, discovery, dns, grpc, overload, socket, upstream};
+
+/// A single upstream server of a pool, as an IP address and port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: String,
+    /// Upstreams with a lower priority are used as long as any of them are alive (from SRV
+    /// records; 0 otherwise)
+    pub priority: u16,
+    /// Share of requests relative to the other upstreams of the same priority (from SRV records;
+    /// 1 otherwise)
+    pub weight: u16,
+}
+
+impl Endpoint {
+    pub fn new(address: String) -> Endpoint {
+        Endpoint {
+            address,
+            priority: 0,
+            weight: 1,
+        }
+    }
+}
+
+/// Share of its weight an upstream starts slow start with.
+const SLOW_START_MIN_SHARE: f64 = 0.1;
+
+/// Counts of how a pool's requests have gone, for comparing pools (e.g. a canary against the
+/// version it is meant to replace).
+#[derive(Default)]
+pub struct Stats {
+    requests: AtomicU64,
+    /// Requests that failed or got a 5xx response
+    errors: AtomicU64,
+    /// Total time upstreams took to respond, in microseconds
+    latency_us: AtomicU64,
+}
+
+impl Stats {
+    pub fn record(&self, latency: Duration, succeeded: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns (requests, errors, total latency).
+    pub fn snapshot(&self) -> (u64, u64, Duration) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            Duration::from_micros(self.latency_us.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// A named group of interchangeable upstream servers. Requests routed to a pool are balanced
+/// across whichever of its upstreams are currently believed to be alive.
+pub struct Pool {
+    pub name: String,
+    /// Protocol spoken to every upstream in this pool
+    pub protocol: upstream::Protocol,
+    /// How active health checks probe this pool's upstreams
+    health_check: HealthCheckConfig,
+    /// Addresses of servers that we are proxying to
+    upstream_addresses: tokio::sync::RwLock<Vec<String>>,
+    /// The upstreams as configured, which may name hosts or SRV records rather than addresses
+    targets: Vec<dns::Target>,
+    /// Every upstream the targets currently expand to (or that discovery found), alive or not
+    endpoints: parking_lot::RwLock<Vec<Endpoint>>,
+    /// Where to discover upstreams from, for pools that don't list them
+    pub discovery: Option<discovery::Source>,
+    /// Caps on how many requests may be in flight to each upstream
+    pub limits: overload::UpstreamLimits,
+    /// Stops traffic to upstreams that keep failing or responding slowly
+    pub breakers: circuit_breaker::Breakers,
+    pub stats: Stats,
+    /// How long an upstream that comes back (or is added) takes to ramp up to its full weight
+    slow_start: Duration,
+    /// When each upstream still ramping up came back
+    revived: parking_lot::Mutex<HashMap<String, Instant>>,
+    /// Latency estimates, for pools that balance by them
+    ewma: Option<balancer::PeakEwma>,
+}
+
+impl Pool {
+    pub fn new(
+        name: String,
+        upstreams: Vec<String>,
+        protocol: upstream::Protocol,
+        health_check: HealthCheckConfig,
+        concurrency: overload::Concurrency,
+        circuit_breaker: Option<circuit_breaker::Config>,
+        discovery: Option<discovery::Source>,
+    ) -> Result<Pool, String> {
+        match (upstreams.is_empty(), &discovery) {
+            (true, None) => return Err("no upstreams are listed or discovered".to_string()),
+            (false, Some(_)) => {
+                return Err("upstreams can be listed or discovered, but not both".to_string())
+            }
+            _ => {}
+        }
+        let targets = upstreams
+            .iter()
+            .map(|upstream| dns::Target::parse(upstream))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Hostnames and SRV records are filled in once dns::start resolves them
+        let endpoints: Vec<Endpoint> = targets
+            .iter()
+            .filter_map(|target| match target {
+                dns::Target::Address(address) => Some(Endpoint::new(address.to_string())),
+                dns::Target::Unix(address) => Some(Endpoint::new(address.clone())),
+                _ => None,
+            })
+            .collect();
+        Ok(Pool {
+            name,
+            protocol,
+            health_check,
+            upstream_addresses: tokio::sync::RwLock::new(
+                endpoints
+                    .iter()
+                    .map(|endpoint| endpoint.address.clone())
+                    .collect(),
+            ),
+            targets,
+            endpoints: parking_lot::RwLock::new(endpoints),
+            discovery,
+            limits: overload::UpstreamLimits::new(concurrency),
+            breakers: circuit_breaker::Breakers::new(circuit_breaker),
+            stats: Stats::default(),
+            slow_start: Duration::ZERO,
+            revived: parking_lot::Mutex::new(HashMap::new()),
+            ewma: None,
+        })
+    }
+
+    /// Sets how the pool chooses between upstreams; `decay` is the time constant of the latency
+    /// averages the EWMA balancer keeps.
+    pub fn with_balancer(mut self, algorithm: balancer::Algorithm, decay: Duration) -> Pool {
+        self.ewma = match algorithm {
+            balancer::Algorithm::Random => None,
+            balancer::Algorithm::Ewma => Some(balancer::PeakEwma::new(decay)),
+        };
+        self
+    }
+
+    /// Counts a request as in flight to `address`, for the EWMA balancer.
+    pub fn track(&self, address: &str) -> balancer::Pending<'_> {
+        match &self.ewma {
+            Some(ewma) => ewma.start(address),
+            None => balancer::Pending::untracked(address),
+        }
+    }
+
+    /// Makes upstreams that come back (or are added) start out with a small share of their
+    /// weight, growing linearly to all of it over `slow_start`, so that they can warm up.
+    pub fn with_slow_start(mut self, slow_start: Duration) -> Pool {
+        self.slow_start = slow_start;
+        self
+    }
+
+    /// Starts ramping up upstreams that have just come back.
+    fn revive(&self, addresses: &[String]) {
+        if self.slow_start.is_zero() || addresses.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut revived = self.revived.lock();
+        for address in addresses {
+            revived.insert(address.clone(), now);
+        }
+    }
+
+    /// The share of its weight an upstream gets right now.
+    fn ramp(&self, address: &str) -> f64 {
+        let mut revived = self.revived.lock();
+        let since = match revived.get(address) {
+            Some(since) => since.elapsed(),
+            None => return 1.0,
+        };
+        if since >= self.slow_start {
+            revived.remove(address);
+            return 1.0;
+        }
+        (since.as_secs_f64() / self.slow_start.as_secs_f64()).max(SLOW_START_MIN_SHARE)
+    }
+
+    /// An upstream's weight, scaled down while it is ramping up.
+    fn effective_weight(&self, endpoint: &Endpoint) -> f64 {
+        // Weight 0 means "only if nothing else will do", which a tiny weight approximates
+        let weight = if endpoint.weight == 0 {
+            0.01
+        } else {
+            endpoint.weight as f64
+        };
+        weight * self.ramp(&endpoint.address)
+    }
+
+    pub fn targets(&self) -> &[dns::Target] {
+        &self.targets
+    }
+
+    /// Picks a live upstream whose circuit breaker lets the request through, from the
+    /// lowest priority that has one, at random in proportion to the upstreams' weights. With the
+    /// EWMA balancer, the less loaded of the first two picks goes first.
+    pub async fn get_upstream_addresse(&self) -> Option<String> {
+        let addrs = self.upstream_addresses.read().await.clone();
+        let mut rng = rand::thread_rng();
+        let mut candidates: Vec<(u16, f64, String)> = {
+            let endpoints = self.endpoints.read();
+            addrs
+                .into_iter()
+                .map(|address| {
+                    let (priority, weight) = endpoints
+                        .iter()
+                        .find(|endpoint| endpoint.address == address)
+                        .map_or((0, 1.0), |endpoint| {
+                            (endpoint.priority, self.effective_weight(endpoint))
+                        });
+                    // NOTE: sorting by u^(1/weight) gives a weighted random order
+                    (priority, rng.gen::<f64>().powf(1.0 / weight), address)
+                })
+                .collect()
+        };
+        candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)));
+        if let Some(ewma) = &self.ewma {
+            // NOTE: power of two choices; comparing only two random picks keeps the slower
+            // upstreams getting a little traffic, so that we notice when they speed up
+            if candidates.len() >= 2
+                && candidates[0].0 == candidates[1].0
+                && ewma.load(&candidates[1].2) < ewma.load(&candidates[0].2)
+            {
+                candidates.swap(0, 1);
+            }
+        }
+        candidates
+            .into_iter()
+            .map(|(_, _, address)| address)
+            .find(|address| self.breakers.admit(address))
+    }
+
+    /// Whether `address` is still one of the pool's upstreams (alive or not).
+    pub fn knows(&self, address: &str) -> bool {
+        self.endpoints
+            .read()
+            .iter()
+            .any(|endpoint| endpoint.address == address)
+    }
+
+    /// Every upstream of the pool, whether it is currently believed to be alive, its weight
+    /// after slow start and (with the EWMA balancer) its latency estimate.
+    pub async fn upstreams(&self) -> Vec<(Endpoint, bool, f64, Option<Duration>)> {
+        let live = self.upstream_addresses.read().await;
+        self.endpoints
+            .read()
+            .iter()
+            .map(|endpoint| {
+                let weight = self.effective_weight(endpoint);
+                let latency = self
+                    .ewma
+                    .as_ref()
+                    .and_then(|ewma| ewma.latency(&endpoint.address));
+                (
+                    endpoint.clone(),
+                    live.contains(&endpoint.address),
+                    weight,
+                    latency,
+                )
+            })
+            .collect()
+    }
+
+    /// Replaces the pool's upstreams with a freshly discovered set. Upstreams we already knew
+    /// keep their health state; new ones are assumed alive until a health check says otherwise.
+    pub async fn set_endpoints(&self, endpoints: Vec<Endpoint>) {
+        let mut live = self.upstream_addresses.write().await;
+        let mut current = self.endpoints.write();
+        let known = |endpoints: &[Endpoint], address: &str| {
+            endpoints.iter().any(|endpoint| endpoint.address == address)
+        };
+        let added: Vec<String> = endpoints
+            .iter()
+            .filter(|endpoint| !known(&current, &endpoint.address))
+            .map(|endpoint| endpoint.address.clone())
+            .collect();
+        let removed: Vec<String> = current
+            .iter()
+            .filter(|endpoint| !known(&endpoints, &endpoint.address))
+            .map(|endpoint| endpoint.address.clone())
+            .collect();
+        if !added.is_empty() || !removed.is_empty() {
+            log::info!(
+                "Upstreams of pool {} changed: added {:?}, removed {:?}",
+                self.name,
+                added,
+                removed
+            );
+        }
+        live.retain(|address| !removed.contains(address));
+        // There's nothing to ramp up against when these are the first upstreams we learn of
+        if !current.is_empty() {
+            self.revive(&added);
+        }
+        live.extend(added);
+        for address in &removed {
+            self.breakers.forget(address);
+            if let Some(ewma) = &self.ewma {
+                ewma.forget(address);
+            }
+        }
+        *current = endpoints;
+    }
+
+    pub async fn remove_upstream_address(&self, address: &str) {
+        let mut write = self.upstream_addresses.write().await;
+        write.retain(|x| x != address);
+    }
+
+    /// Probes every known upstream at once and replaces the set of live upstreams with the ones
+    /// that passed. A probe that takes longer than `timeout` fails.
+    pub async fn health_check(&self, timeout: Duration) {
+        let endpoints: Vec<String> = self
+            .endpoints
+            .read()
+            .iter()
+            .map(|endpoint| endpoint.address.clone())
+            .collect();
+        // NOTE: an upstream that accepts connections but never answers would otherwise hold up
+        // this round, and every round after it, for all the other upstreams too
+        let probes = endpoints.iter().map(|address| async move {
+            match tokio::time::timeout(timeout, self.check_upstream(address)).await {
+                Ok(healthy) => healthy,
+                Err(_) => {
+                    log::error!("Health check of upstream {} timed out", address);
+                    false
+                }
+            }
+        });
+        let results = futures::future::join_all(probes).await;
+        let mut addrs: Vec<String> = endpoints
+            .into_iter()
+            .zip(results)
+            .filter_map(|(address, healthy)| healthy.then_some(address))
+            .collect();
+        {
+            let mut write = self.upstream_addresses.write().await;
+            // Discovery may have dropped some of them while we were probing
+            let endpoints = self.endpoints.read();
+            addrs.retain(|address| {
+                endpoints
+                    .iter()
+                    .any(|endpoint| &endpoint.address == address)
+            });
+            let revived: Vec<String> = addrs
+                .iter()
+                .filter(|address| !write.contains(address))
+                .cloned()
+                .collect();
+            if !revived.is_empty() {
+                log::info!("Upstreams {:?} of pool {} are back", revived, self.name);
+            }
+            self.revive(&revived);
+            *write = addrs;
+        }
+    }
+
+    async fn check_upstream(&self, address: &str) -> bool {
+        let (protocol, request) = match &self.health_check {
+            HealthCheckConfig::Http { path } => {
+                let request = http::Request::builder()
+                    .method(http::Method::GET)
+                    .uri(path)
+                    .header("Host", socket::authority(address))
+                    .body(Vec::<u8>::new())
+                    .unwrap();
+                (self.protocol, request)
+            }
+            // gRPC always runs over HTTP/2, whatever the pool is configured with
+            HealthCheckConfig::Grpc { service } => (
+                upstream::Protocol::Http2,
+                grpc::health_check_request(service, socket::authority(address)),
+            ),
+        };
+        let conn = upstream::Connection::connect(address, protocol).await;
+        if let Err(err) = conn {
+            log::error!("Failed to connect to upstream {}: {}", address, err);
+            return false;
+        }
+        let response = conn.unwrap().send(request, address).await;
+        if let Err(err) = response {
+            log::error!("Error reading response from server: {:?}", err);
+            return false;
+        }
+        let response = response.unwrap();
+        let healthy = match self.health_check {
+            HealthCheckConfig::Http { .. } => response.status() != 500,
+            HealthCheckConfig::Grpc { .. } => grpc::is_serving(&response),
+        };
+        if !healthy {
+            log::error!("Server {} is down", address);
+        }
+        healthy
+    }
+}
@@ -22,20 +22,20 @@ impl RateLimiter {
             inner.last_acquired = std::time::Instant::now();
             return true;
         }
-        return false;
+        false
     }
 
     pub fn new(rate: u32) -> RateLimiter {
-        return RateLimiter {
-            rate: rate,
+        RateLimiter {
+            rate,
             inner: tokio::sync::RwLock::new(RateLimiterInner {
                 token: rate,
                 last_acquired: std::time::Instant::now(),
             }),
-        };
+        }
     }
 
     pub fn rate(&self) -> u32 {
-        return self.rate;
+        self.rate
     }
 }
@@ -1,24 +1,80 @@
 use std::cmp::min;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+pub const DEFAULT_MAX_HEADERS_SIZE: usize = 8000;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10000000;
+pub const DEFAULT_MAX_NUM_HEADERS: usize = 32;
+pub const DEFAULT_MAX_URI_LENGTH: usize = 4096;
+
+/// How big a request may be. Anything bigger is rejected before we buffer any more of it.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of the request line and headers together, in bytes
+    pub max_headers_size: usize,
+    pub max_num_headers: usize,
+    /// Maximum length of the request target (path and query), in bytes
+    pub max_uri_length: usize,
+    /// Maximum size of the body, in bytes, for requests whose route doesn't set its own
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_headers_size: DEFAULT_MAX_HEADERS_SIZE,
+            max_num_headers: DEFAULT_MAX_NUM_HEADERS,
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks a request that was parsed elsewhere (e.g. by h2) against these limits.
+    pub fn check(&self, request: &http::Request<Vec<u8>>) -> Result<(), Error> {
+        let uri_length = request
+            .uri()
+            .path_and_query()
+            .map_or(0, |path_and_query| path_and_query.as_str().len());
+        if uri_length > self.max_uri_length {
+            return Err(Error::UriTooLong);
+        }
+        if request.headers().len() > self.max_num_headers {
+            return Err(Error::TooManyHeaders);
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedRequest(httparse::Error),
+    MalformedRequest(#[allow(dead_code)] httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than the route (or Limits::max_body_size) allows
     RequestBodyTooLarge,
+    /// The Expect header asks for something other than 100-continue
+    ExpectationFailed,
+    /// Client sent a request that other HTTP implementations could read differently than we do
+    /// (the stuff of request smuggling), or that uses syntax httparse tolerates but HTTP
+    /// forbids. The string says what was wrong
+    Rejected(&'static str),
+    /// The request has a Transfer-Encoding, and we only read bodies delimited by Content-Length
+    UnsupportedTransferEncoding,
+    /// The request line and headers don't fit in Limits::max_headers_size
+    HeadersTooLarge,
+    /// The request has more headers than Limits::max_num_headers
+    TooManyHeaders,
+    /// The request target is longer than Limits::max_uri_length
+    UriTooLong,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
@@ -26,18 +82,12 @@ pub enum Error {
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
 fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
     // Look for content-length header
     if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
+        // If it exists, parse it (or return InvalidContentLength if it isn't a number)
         Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+            parse_content_length(header_value.as_bytes()).ok_or(Error::InvalidContentLength)?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -45,6 +95,118 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
     }
 }
 
+/// Parses a Content-Length value, which must be nothing but digits. (`usize::from_str` would
+/// also take a leading "+", which other servers may not.)
+pub fn parse_content_length(value: &[u8]) -> Option<usize> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// Checks the raw bytes of a message head for line breaks that other HTTP implementations might
+/// read differently than httparse does: a CR or LF on its own, or a header continued onto the
+/// next line (obsolete line folding).
+pub fn check_line_endings(head: &[u8]) -> Result<(), &'static str> {
+    for (i, &byte) in head.iter().enumerate() {
+        match byte {
+            b'\r' if head.get(i + 1) != Some(&b'\n') => return Err("bare CR"),
+            b'\n' if i == 0 || head[i - 1] != b'\r' => return Err("bare LF"),
+            b'\n' if matches!(head.get(i + 1), Some(b' ' | b'\t')) => {
+                return Err("obsolete line folding")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks a header value for bytes HTTP doesn't allow in one (control characters other than tab,
+/// and DEL), which some servers strip or treat as separators.
+pub fn check_header_value(value: &[u8]) -> Result<(), &'static str> {
+    if value
+        .iter()
+        .all(|&byte| byte == b'\t' || (byte >= 0x20 && byte != 0x7f))
+    {
+        Ok(())
+    } else {
+        Err("invalid character in header value")
+    }
+}
+
+/// Makes sure a message's length can only be read one way. Content-Length may be repeated (or
+/// list the same length several times) as long as every value agrees, in which case the
+/// duplicates are folded into one header; Content-Length alongside Transfer-Encoding is
+/// rejected, as different servers honor different ones.
+pub fn check_framing(headers: &mut http::HeaderMap) -> Result<(), &'static str> {
+    let mut length = None;
+    for value in headers.get_all(http::header::CONTENT_LENGTH) {
+        for item in value.as_bytes().split(|&byte| byte == b',') {
+            let item = parse_content_length(item.trim_ascii()).ok_or("invalid Content-Length")?;
+            if length.is_some_and(|length| length != item) {
+                return Err("conflicting Content-Length headers");
+            }
+            length = Some(item);
+        }
+    }
+    if let Some(length) = length {
+        if headers.contains_key(http::header::TRANSFER_ENCODING) {
+            return Err("Content-Length with Transfer-Encoding");
+        }
+        headers.insert(
+            http::header::CONTENT_LENGTH,
+            http::HeaderValue::from(length),
+        );
+    }
+    Ok(())
+}
+
+/// The options a message's Connection headers list (e.g. close, keep-alive, or the names of
+/// other hop-by-hop headers), lowercased.
+fn connection_options(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|&byte| byte == b','))
+        .map(|option| String::from_utf8_lossy(option.trim_ascii()).to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// Whether a message's Connection headers list `option`.
+fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    connection_options(headers)
+        .iter()
+        .any(|listed| listed == option)
+}
+
+/// Whether the connection a message arrived on stays open after it: HTTP/1.1 connections persist
+/// unless the message says close, HTTP/1.0 ones only if it asks for keep-alive.
+pub fn keeps_alive(version: http::Version, headers: &http::HeaderMap) -> bool {
+    if has_connection_option(headers, "close") {
+        false
+    } else if version == http::Version::HTTP_10 {
+        has_connection_option(headers, "keep-alive")
+    } else {
+        true
+    }
+}
+
+/// Removes the headers that only describe the connection a message arrived on (RFC 9110 section
+/// 7.6.1): Connection itself, the headers it names, and the well-known ones like Keep-Alive.
+pub fn remove_hop_by_hop_headers(headers: &mut http::HeaderMap) {
+    for option in connection_options(headers) {
+        // NOTE: listing a header in Connection mustn't make us drop the ones that say where the
+        // message goes or where it ends
+        if option != "host" && option != "content-length" && option != "transfer-encoding" {
+            headers.remove(option.as_str());
+        }
+    }
+    for name in ["connection", "keep-alive", "proxy-connection"] {
+        headers.remove(name);
+    }
+}
+
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
 /// or to add a new X-Forwarded-For header if one is not already present.
@@ -66,30 +228,125 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Rewrites the path of a request target into the one form routes are matched against (and the
+/// upstream is sent): percent-encoded unreserved characters decoded, repeated slashes collapsed
+/// and "." and ".." segments resolved, so that e.g. `/%61dmin`, `//admin` and `/x/../admin` are
+/// all `/admin`. Rejects malformed percent-encoding. Targets that aren't paths (`*`, or the
+/// authority of a CONNECT) are left alone.
+pub fn normalize_path(uri: &mut http::Uri) -> Result<(), &'static str> {
+    let path = uri.path();
+    if !path.starts_with('/') {
+        return Ok(());
+    }
+    // Decode what never needs encoding; anything else (e.g. %2F) stays encoded, so it can't
+    // turn into a separator
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .ok_or("malformed percent-encoding")?;
+        let hex = std::str::from_utf8(hex).map_err(|_| "malformed percent-encoding")?;
+        let byte = u8::from_str_radix(hex, 16).map_err(|_| "malformed percent-encoding")?;
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            decoded.push(byte);
+        } else {
+            decoded.push(b'%');
+            decoded.extend_from_slice(hex.to_ascii_uppercase().as_bytes());
+        }
+        i += 3;
+    }
+    // Only ASCII was decoded, so this is still the UTF-8 the path was
+    let decoded = String::from_utf8(decoded).map_err(|_| "invalid request target")?;
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in decoded.split('/').skip(1) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    if normalized == path {
+        return Ok(());
+    }
+    let mut parts = std::mem::take(uri).into_parts();
+    let path_and_query = match parts.path_and_query.as_ref().and_then(|path| path.query()) {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .map_err(|_| "invalid request target")?,
+    );
+    *uri = http::Uri::from_parts(parts).map_err(|_| "invalid request target")?;
+    Ok(())
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
 /// * If there is a complete and valid request in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
-/// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
-///
-/// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+/// * If there is data in the buffer that is definitely not a valid HTTP request, or one we won't
+///   accept because it could be read more than one way, returns Err(Error)
+#[allow(clippy::type_complexity)]
+pub fn parse_request(
+    buffer: &[u8],
+    limits: &Limits,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_num_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|error| match error {
+        httparse::Error::TooManyHeaders => Error::TooManyHeaders,
+        error => Error::MalformedRequest(error),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
+        if req.path.unwrap().len() > limits.max_uri_length {
+            return Err(Error::UriTooLong);
+        }
+        check_line_endings(&buffer[..len]).map_err(Error::Rejected)?;
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
-        for header in req.headers {
+            .version(match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
+        for header in req.headers.iter() {
+            check_header_value(header.value).map_err(Error::Rejected)?;
             request = request.header(header.name, header.value);
         }
-        let request = request.body(Vec::new()).unwrap();
+        let mut request = request
+            .body(Vec::new())
+            .map_err(|_| Error::Rejected("invalid request target"))?;
+        // NOTE: which Host a request is for decides where some servers route it
+        if request.headers().get_all(http::header::HOST).iter().count() > 1 {
+            return Err(Error::Rejected("multiple Host headers"));
+        }
+        check_framing(request.headers_mut()).map_err(Error::Rejected)?;
+        normalize_path(request.uri_mut()).map_err(Error::Rejected)?;
+        if request
+            .headers()
+            .contains_key(http::header::TRANSFER_ENCODING)
+        {
+            return Err(Error::UnsupportedTransferEncoding);
+        }
         Ok(Some((request, len)))
     } else {
         Ok(None)
@@ -97,63 +354,92 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 }
 
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
-/// This function only reads the request line and headers; the read_body function can subsequently
+/// This function only reads the request line and headers; read_body_from_stream can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// `buffered` holds bytes already read from the connection that haven't been used yet (e.g. a
+/// pipelined request, read along with the one before it). The request is parsed from those
+/// first, and whatever we read past the end of its head is left there for whoever reads next.
 ///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut tokio::net::TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+pub async fn read_headers<S>(
+    stream: &mut S,
+    buffered: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error>
+where
+    S: AsyncRead + Unpin,
+{
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = vec![0_u8; limits.max_headers_size];
+    let mut bytes_read = buffered.len().min(request_buffer.len());
+    request_buffer[..bytes_read].copy_from_slice(&buffered[..bytes_read]);
+    buffered.drain(..bytes_read);
     loop {
+        // See if we've read a valid request so far
+        if bytes_read > 0 {
+            if let Some((request, headers_len)) =
+                parse_request(&request_buffer[..bytes_read], limits)?
+            {
+                // NOTE: what follows the head is the body (if there is one) and then the next
+                // request. It's never part of this request's body beyond Content-Length: that
+                // would forward a request we never looked at.
+                buffered.splice(
+                    0..0,
+                    request_buffer[headers_len..bytes_read].iter().copied(),
+                );
+                return Ok(request);
+            }
+        }
+
+        if bytes_read == request_buffer.len() {
+            // If the request line alone filled the buffer, it's the URI that is too long
+            return Err(if request_buffer.contains(&b'\n') {
+                Error::HeadersTooLarge
+            } else {
+                Error::UriTooLong
+            });
+        }
+
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
         bytes_read += new_bytes;
-
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
-        }
     }
 }
 
 /// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut tokio::net::TcpStream,
+/// Content-Length header is present; this function takes that number of bytes from `buffered`
+/// (see read_headers) and then the stream, and no more. It returns Ok(()) if successful, or
+/// Err(Error) if Content-Length bytes couldn't be read.
+async fn read_body<S>(
+    stream: &mut S,
+    buffered: &mut Vec<u8>,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let from_buffer = buffered.len().min(content_length);
+    request.body_mut().extend(buffered.drain(..from_buffer));
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
+        // Read up to 512 bytes at a time, and never past the end of the body: anything after it
+        // belongs to the next request
+        let mut buffer = vec![0_u8; min(512, content_length - request.body().len())];
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -166,14 +452,6 @@ async fn read_body(
             return Err(Error::ContentLengthMismatch);
         }
 
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
-        }
-
         // Store the received bytes in the request body
         request.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
@@ -181,48 +459,109 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut tokio::net::TcpStream,
-) -> Result<http::Request<Vec<u8>>, Error> {
+/// closes the connection prematurely or sends an invalid request. `buffered` is as for
+/// read_headers.
+pub async fn read_from_stream<S>(
+    stream: &mut S,
+    buffered: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error>
+where
+    S: AsyncRead + Unpin,
+{
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, buffered, limits).await?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+        if content_length > limits.max_body_size {
             return Err(Error::RequestBodyTooLarge);
         } else {
-            read_body(stream, &mut request, content_length).await?;
+            read_body(stream, buffered, &mut request, content_length).await?;
         }
     }
     Ok(request)
 }
 
+/// Checks, before any more of the body is read, that a request doesn't expect anything we can't
+/// do and doesn't declare a body bigger than `max_body_size`. Returns whether the client is
+/// waiting for a 100 Continue before it sends the body.
+pub fn check_before_body(
+    request: &http::Request<Vec<u8>>,
+    max_body_size: usize,
+) -> Result<bool, Error> {
+    let expects_continue = match request.headers().get(http::header::EXPECT) {
+        Some(expect) if expect.as_bytes().eq_ignore_ascii_case(b"100-continue") => true,
+        Some(_) => return Err(Error::ExpectationFailed),
+        None => false,
+    };
+    let content_length = get_content_length(request)?.unwrap_or(0);
+    if content_length > max_body_size {
+        return Err(Error::RequestBodyTooLarge);
+    }
+    // NOTE: a client that expects 100 Continue but sends no body, or sent it anyway, isn't
+    // waiting for anything
+    Ok(expects_continue && content_length > request.body().len())
+}
+
+/// Reads the body of a request whose head read_headers returned, once we know which route it
+/// is for (routes may allow different body sizes). A client waiting on `Expect: 100-continue` is
+/// told to go ahead first; we buffer the whole body, so the upstream never sees the expectation.
+/// `buffered` is as for read_headers.
+pub async fn read_body_from_stream<S>(
+    stream: &mut S,
+    buffered: &mut Vec<u8>,
+    request: &mut http::Request<Vec<u8>>,
+    max_body_size: usize,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let expects_continue = check_before_body(request, max_body_size)?;
+    request.headers_mut().remove(http::header::EXPECT);
+    // A client that sent the body without waiting isn't waiting for anything
+    let content_length = get_content_length(request)?.unwrap_or(0);
+    if expects_continue && buffered.len() < content_length {
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .map_err(Error::ConnectionError)?;
+    }
+    read_body(stream, buffered, request, content_length).await
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut tokio::net::TcpStream,
-) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_request_line(request).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream: &mut S,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    // NOTE: one write for the whole message. Writing each line separately costs a syscall per
+    // header, and with Nagle's algorithm the peer's delayed ACK can hold up the last segment.
+    let mut message = format_request_line(request).into_bytes();
+    message.extend_from_slice(b"\r\n");
     for (header_name, header_value) in request.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+        message.extend_from_slice(header_name.as_str().as_bytes());
+        message.extend_from_slice(b": ");
+        message.extend_from_slice(header_value.as_bytes());
+        message.extend_from_slice(b"\r\n");
     }
-    Ok(())
+    message.extend_from_slice(b"\r\n");
+    message.extend_from_slice(request.body());
+    stream.write_all(&message).await
+}
+
+/// Copies a request's method, URI, version, headers and body (but not its extensions).
+pub fn clone_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -1,40 +1,45 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{http2, request};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
+/// Longest chunk-size line (or trailer line) we'll read in a chunked body
+const MAX_CHUNK_LINE: usize = 4096;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
     /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedResponse(httparse::Error),
+    MalformedResponse(#[allow(dead_code)] httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
+    /// The upstream sent a response that could be read more than one way (e.g. conflicting
+    /// lengths), which could desynchronize the connection. The string says what was wrong
+    Rejected(#[allow(dead_code)] &'static str),
     /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    ConnectionError(#[allow(dead_code)] std::io::Error),
+    /// The upstream HTTP/2 connection or stream failed
+    StreamError(#[allow(dead_code)] crate::http2::Error),
 }
 
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
 fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Error> {
     // Look for content-length header
     if let Some(header_value) = response.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
+        // If it exists, parse it (or return InvalidContentLength if it isn't a number)
         Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+            request::parse_content_length(header_value.as_bytes())
+                .ok_or(Error::InvalidContentLength)?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -47,25 +52,30 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///
 /// * If there is a complete and valid response in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far response in the buffer, returns Ok(None)
-/// * If there is data in the buffer that is definitely not a valid HTTP response, returns
-///   Err(Error)
-///
-/// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+/// * If there is data in the buffer that is definitely not a valid HTTP response, or one we
+///   won't accept because it could be read more than one way, returns Err(Error)
+#[allow(clippy::type_complexity)]
+pub fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
+        request::check_line_endings(&buffer[..len]).map_err(Error::Rejected)?;
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
-        for header in resp.headers {
+            .version(match resp.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
+        for header in resp.headers.iter() {
+            request::check_header_value(header.value).map_err(Error::Rejected)?;
             response = response.header(header.name, header.value);
         }
-        let response = response.body(Vec::new()).unwrap();
+        let mut response = response
+            .body(Vec::new())
+            .map_err(|_| Error::Rejected("invalid status line or header"))?;
+        request::check_framing(response.headers_mut()).map_err(Error::Rejected)?;
         Ok(Some((response, len)))
     } else {
         Ok(None)
@@ -76,23 +86,36 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// sent. This function only reads the response line and headers; the read_body function can
 /// subsequently be called in order to read the response body.
 ///
+/// `leftover` holds bytes already read from the stream that belong to this response (what followed
+/// an interim response's head).
+///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(
-    stream: &mut tokio::net::TcpStream,
-) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S>(stream: &mut S, leftover: &[u8]) -> Result<http::Response<Vec<u8>>, Error>
+where
+    S: AsyncRead + Unpin,
+{
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
     let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    if leftover.len() > MAX_HEADERS_SIZE {
+        return Err(Error::MalformedResponse(httparse::Error::TooManyHeaders));
+    }
+    response_buffer[..leftover.len()].copy_from_slice(leftover);
+    let mut bytes_read = leftover.len();
+    if bytes_read > 0 {
+        if let Some(response) = complete_head(&response_buffer, bytes_read)? {
+            return Ok(response);
+        }
+    }
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -100,15 +123,28 @@ async fn read_headers(
         bytes_read += new_bytes;
 
         // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+        if let Some(response) = complete_head(&response_buffer, bytes_read)? {
+            return Ok(response);
+        }
+    }
+}
+
+/// Parses the first `bytes_read` bytes of the buffer, if they hold a complete set of headers.
+fn complete_head(
+    response_buffer: &[u8],
+    bytes_read: usize,
+) -> Result<Option<http::Response<Vec<u8>>>, Error> {
+    match parse_response(&response_buffer[..bytes_read])? {
+        Some((mut response, headers_len)) => {
             // We've read a complete set of headers. We may have also read the first part of the
             // response body; take whatever is left over in the response buffer and save that as
             // the start of the response body.
             response
                 .body_mut()
                 .extend_from_slice(&response_buffer[headers_len..bytes_read]);
-            return Ok(response);
+            Ok(Some(response))
         }
+        None => Ok(None),
     }
 }
 
@@ -116,21 +152,28 @@ async fn read_headers(
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut tokio::net::TcpStream,
-    response: &mut http::Response<Vec<u8>>,
-) -> Result<(), Error> {
+async fn read_body<S>(stream: &mut S, response: &mut http::Response<Vec<u8>>) -> Result<(), Error>
+where
+    S: AsyncRead + Unpin,
+{
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
+    if let Some(content_length) = content_length {
+        discard_excess(response, content_length);
+    }
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
+        // Don't read past the end of the body
         let mut buffer = [0_u8; 512];
+        let wanted = content_length.map_or(buffer.len(), |content_length| {
+            buffer.len().min(content_length - response.body().len())
+        });
         let bytes_read = stream
-            .read(&mut buffer)
+            .read(&mut buffer[..wanted])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -143,12 +186,6 @@ async fn read_body(
             }
         }
 
-        // Make sure the server doesn't send more bytes than it promised to send
-        if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
-        {
-            return Err(Error::ContentLengthMismatch);
-        }
-
         // Make sure server doesn't send more bytes than we allow
         if response.body().len() + bytes_read > MAX_BODY_SIZE {
             return Err(Error::ResponseBodyTooLarge);
@@ -160,50 +197,266 @@ async fn read_body(
     Ok(())
 }
 
+/// Drops whatever was read along with the head beyond the `content_length` bytes of the body,
+/// and marks the response's connection as not to be reused: the upstream sent more than it said
+/// it would, so we can't tell where its next response starts.
+fn discard_excess(response: &mut http::Response<Vec<u8>>, content_length: usize) {
+    if response.body().len() <= content_length {
+        return;
+    }
+    log::warn!(
+        "Upstream sent {} bytes after a {}-byte body; not reusing its connection",
+        response.body().len() - content_length,
+        content_length
+    );
+    response.body_mut().truncate(content_length);
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+}
+
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
 /// closes the connection prematurely or sends an invalid response.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut tokio::net::TcpStream,
+pub async fn read_from_stream<S>(
+    stream: &mut S,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+) -> Result<http::Response<Vec<u8>>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut response = read_headers(stream, &[]).await?;
+    // Interim responses (e.g. 100 Continue, which we've already sent the client if it asked for
+    // one) are followed by the real one. 101 Switching Protocols is final.
+    while response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
     {
+        log::debug!(
+            "Skipping interim response {}",
+            format_response_line(&response)
+        );
+        response = read_headers(stream, response.body()).await?;
+    }
+    // A response may have a body as long as it is not responding to a HEAD request and as long as
+    // the response status code is not 1xx, 204 (no content), or 304 (not modified). Its
+    // Content-Length, if any, then describes the body it would have had.
+    if request_method == http::Method::HEAD || !has_body(response.status()) {
+        if !response.body().is_empty() {
+            log::warn!(
+                "Upstream sent {} bytes after a {} response, which can't have a body; not \
+                reusing its connection",
+                response.body().len(),
+                response.status()
+            );
+            response.body_mut().clear();
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
+            );
+        }
+    } else if is_chunked(&response) {
+        // We buffer the whole body anyway, so pass it on with a plain Content-Length
+        let trailers = read_chunked_body(stream, &mut response).await?;
+        let length = http::HeaderValue::from(response.body().len());
+        let headers = response.headers_mut();
+        headers.remove(http::header::TRANSFER_ENCODING);
+        headers.insert(http::header::CONTENT_LENGTH, length);
+        if !trailers.is_empty() {
+            response.extensions_mut().insert(http2::Trailers(trailers));
+        }
+    } else {
+        // With a Content-Length, reads that much; otherwise, until the upstream hangs up
         read_body(stream, &mut response).await?;
     }
     Ok(response)
 }
 
+/// Whether chunked is the last transfer coding applied to a response's body, which is the only
+/// case in which chunking (rather than the connection closing) marks the end of the body.
+fn is_chunked(response: &http::Response<Vec<u8>>) -> bool {
+    response
+        .headers()
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|&byte| byte == b','))
+        .last()
+        .is_some_and(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+}
+
+/// Whether the end of a response's body is only marked by the connection closing: it has a
+/// body, but neither a Content-Length nor chunking. Neither the upstream connection it came on
+/// nor the client connection it goes out on can carry anything after it.
+pub fn is_close_delimited(
+    response: &http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> bool {
+    request_method != http::Method::HEAD
+        && has_body(response.status())
+        && !response
+            .headers()
+            .contains_key(http::header::CONTENT_LENGTH)
+        && !is_chunked(response)
+}
+
+/// Whether the upstream closes the connection a response came on after it, as HTTP/1.0
+/// upstreams do unless they say keep-alive.
+pub fn closes_connection(response: &http::Response<Vec<u8>>) -> bool {
+    !request::keeps_alive(response.version(), response.headers())
+}
+
+/// Reads bytes from the stream until `buffer` holds at least `needed` of them.
+async fn fill<S>(stream: &mut S, buffer: &mut Vec<u8>, needed: usize) -> Result<(), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    while buffer.len() < needed {
+        let mut chunk = [0_u8; 4096];
+        let bytes_read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(Error::ConnectionError)?;
+        if bytes_read == 0 {
+            return Err(Error::IncompleteResponse);
+        }
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    }
+    Ok(())
+}
+
+/// Reads a CRLF-terminated line starting at `*position` in `buffer` (reading more from the
+/// stream as needed) and moves `*position` past it. Returns the line without its CRLF.
+async fn read_line<S>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    position: &mut usize,
+) -> Result<Vec<u8>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(end) = buffer[*position..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+        {
+            let line = buffer[*position..*position + end].to_vec();
+            *position += end + 2;
+            return Ok(line);
+        }
+        if buffer.len() - *position > MAX_CHUNK_LINE {
+            return Err(Error::Rejected("chunk line too long"));
+        }
+        let available = buffer.len();
+        fill(stream, buffer, available + 1).await?;
+    }
+}
+
+/// Reads a chunked body (RFC 9112 section 7.1), starting with whatever part of it was read along
+/// with the headers, and replaces the response's body with the decoded data. Returns the
+/// trailer fields that followed it.
+async fn read_chunked_body<S>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<http::HeaderMap, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut raw = std::mem::take(response.body_mut());
+    let mut position = 0;
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream, &mut raw, &mut position).await?;
+        // Chunk extensions (after a ';') don't mean anything to us
+        let size = line.split(|&byte| byte == b';').next().unwrap_or(&[]);
+        if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+            return Err(Error::Rejected("invalid chunk size"));
+        }
+        let size = std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .ok_or(Error::ResponseBodyTooLarge)?;
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > MAX_BODY_SIZE {
+            return Err(Error::ResponseBodyTooLarge);
+        }
+        fill(stream, &mut raw, position + size + 2).await?;
+        body.extend_from_slice(&raw[position..position + size]);
+        if &raw[position + size..position + size + 2] != b"\r\n" {
+            return Err(Error::Rejected("chunk data not followed by CRLF"));
+        }
+        position += size + 2;
+        // Don't keep data we've already decoded around
+        raw.drain(..position);
+        position = 0;
+    }
+    let mut trailers = http::HeaderMap::new();
+    loop {
+        let line = read_line(stream, &mut raw, &mut position).await?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .iter()
+            .position(|&byte| byte == b':')
+            .map(|colon| (&line[..colon], line[colon + 1..].trim_ascii()))
+            .ok_or(Error::Rejected("invalid trailer field"))?;
+        request::check_header_value(value).map_err(Error::Rejected)?;
+        let name = http::HeaderName::from_bytes(name)
+            .map_err(|_| Error::Rejected("invalid trailer field"))?;
+        let value = http::HeaderValue::from_bytes(value)
+            .map_err(|_| Error::Rejected("invalid trailer field"))?;
+        trailers.append(name, value);
+    }
+    // NOTE: like bytes past a Content-Length (see discard_excess), whatever follows the body
+    // can't be the start of the next response, so drop it along with the connection
+    if position != raw.len() {
+        log::warn!(
+            "Upstream sent {} bytes after a chunked body; not reusing its connection",
+            raw.len() - position
+        );
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    }
+    *response.body_mut() = body;
+    Ok(trailers)
+}
+
+/// Returns false for the status codes that never carry a response body: 1xx, 204 (no content)
+/// and 304 (not modified).
+pub fn has_body(status: http::StatusCode) -> bool {
+    !(status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED)
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut tokio::net::TcpStream,
-) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_response_line(response).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream: &mut S,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    // NOTE: one write for the whole message. Writing each line separately costs a syscall per
+    // header, and with Nagle's algorithm the peer's delayed ACK can hold up the last segment.
+    let mut message = format_response_line(response).into_bytes();
+    message.extend_from_slice(b"\r\n");
     for (header_name, header_value) in response.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+        message.extend_from_slice(header_name.as_str().as_bytes());
+        message.extend_from_slice(b": ");
+        message.extend_from_slice(header_value.as_bytes());
+        message.extend_from_slice(b"\r\n");
     }
-    Ok(())
+    message.extend_from_slice(b"\r\n");
+    message.extend_from_slice(response.body());
+    stream.write_all(&message).await
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -215,6 +468,11 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     )
 }
 
+/// Marks a response as an error balancebeam generated itself (rather than one an upstream sent),
+/// which a configured error page may replace.
+#[derive(Clone, Copy, Debug)]
+pub struct Generated;
+
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
@@ -229,6 +487,7 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         .header("Content-Type", "text/plain")
         .header("Content-Length", body.len().to_string())
         .version(http::Version::HTTP_11)
+        .extension(Generated)
         .body(body)
         .unwrap()
 }
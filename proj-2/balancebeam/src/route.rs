@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::auth::Authenticator;
+use crate::canary::Canary;
+use crate::config::RouteConfig;
+use crate::error_pages::ErrorPages;
+use crate::filter::Filter;
+use crate::grpc;
+use crate::mirror::Mirror;
+use crate::pool::Pool;
+use crate::static_files::StaticFiles;
+
+/// What part of a request a route looks at.
+enum Matcher {
+    PathPrefix(String),
+    Grpc {
+        service: String,
+        method: Option<String>,
+    },
+}
+
+/// Sends matching requests to a pool of upstreams, or serves them from a directory.
+pub struct Route {
+    matcher: Matcher,
+    /// Where the route's requests go; None if the route serves files instead
+    pub pool: Option<Arc<Pool>>,
+    /// Where the route's files are, if it serves files instead of using a pool
+    pub files: Option<StaticFiles>,
+    /// Which clients, methods and requests the route accepts
+    pub filter: Filter,
+    /// Credentials the route requires, if any
+    pub auth: Option<Authenticator>,
+    /// Where to copy some of the route's requests to, if anywhere
+    pub mirror: Option<Arc<Mirror>>,
+    /// Pool that takes some of the route's requests instead of `pool`, if any
+    pub canary: Option<Canary>,
+    /// Largest request body the route accepts, if it overrides the global limit
+    pub max_body_size: Option<usize>,
+    /// Error pages for the route, which take precedence over the global ones
+    pub error_pages: ErrorPages,
+}
+
+impl Route {
+    pub fn new(config: &RouteConfig, pools: &HashMap<String, Arc<Pool>>) -> Result<Route, String> {
+        let pool = config
+            .pool
+            .as_ref()
+            .map(|pool| {
+                pools
+                    .get(pool)
+                    .cloned()
+                    .ok_or_else(|| format!("route refers to unknown pool \"{}\"", pool))
+            })
+            .transpose()?;
+        // Name the route by whatever it leads to, for error messages
+        let target = match (&config.pool, &config.static_files) {
+            (Some(pool), None) => format!("pool \"{}\"", pool),
+            (None, Some(files)) => format!("static files in {}", files.root),
+            _ => return Err("a route needs either a pool or static_files".to_string()),
+        };
+        let matcher = match (&config.path_prefix, &config.grpc_service) {
+            (Some(prefix), None) if config.grpc_method.is_none() => {
+                Matcher::PathPrefix(prefix.clone())
+            }
+            (None, Some(service)) => Matcher::Grpc {
+                service: service.clone(),
+                method: config.grpc_method.clone(),
+            },
+            _ => {
+                return Err(format!(
+                    "route to {} needs either path_prefix or grpc_service (with an optional \
+                    grpc_method)",
+                    target
+                ))
+            }
+        };
+        let files = match (&config.static_files, &matcher) {
+            (Some(files), Matcher::PathPrefix(prefix)) => Some(StaticFiles::new(files, prefix)?),
+            (Some(_), Matcher::Grpc { .. }) => {
+                return Err(format!("route to {} needs a path_prefix", target))
+            }
+            (None, _) => None,
+        };
+        if pool.is_none() && (config.mirror.is_some() || config.canary.is_some()) {
+            return Err(format!(
+                "route to {} can't have a mirror or canary without a pool",
+                target
+            ));
+        }
+        Ok(Route {
+            matcher,
+            pool,
+            files,
+            filter: Filter::new(config)?,
+            auth: config.auth.as_ref().map(Authenticator::new).transpose()?,
+            mirror: config
+                .mirror
+                .as_ref()
+                .map(|mirror| Mirror::new(mirror, pools).map(Arc::new))
+                .transpose()?,
+            canary: config
+                .canary
+                .as_ref()
+                .map(|canary| Canary::new(canary, pools))
+                .transpose()?,
+            max_body_size: config.max_body_size,
+            error_pages: ErrorPages::load(&config.error_pages)
+                .map_err(|err| format!("route to {}: {}", target, err))?,
+        })
+    }
+
+    /// A route that matches every request.
+    pub fn catch_all(pool: Arc<Pool>) -> Route {
+        Route {
+            matcher: Matcher::PathPrefix("/".to_string()),
+            pool: Some(pool),
+            files: None,
+            filter: Filter::default(),
+            auth: None,
+            mirror: None,
+            canary: None,
+            max_body_size: None,
+            error_pages: ErrorPages::default(),
+        }
+    }
+
+    pub fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        match &self.matcher {
+            Matcher::PathPrefix(prefix) => is_under(request.uri().path(), prefix),
+            Matcher::Grpc { service, method } => {
+                match grpc::service_and_method(request.uri().path()) {
+                    Some((s, m)) => {
+                        s == service && method.as_ref().is_none_or(|method| m == method)
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    /// Picks the pool a request on this route goes to: the canary's, if it takes the request,
+    /// and the route's own otherwise (None if the route serves files).
+    pub fn choose_pool(&self, request: &http::Request<Vec<u8>>) -> Option<&Arc<Pool>> {
+        match &self.canary {
+            Some(canary) if canary.chooses(request) => Some(&canary.pool),
+            _ => self.pool.as_ref(),
+        }
+    }
+
+    /// Requests on most routes stick to the upstream their client connection was first sent to.
+    /// gRPC clients keep a single long-lived HTTP/2 connection open, though, so gRPC routes
+    /// balance every call individually instead.
+    pub fn balance_per_request(&self) -> bool {
+        matches!(self.matcher, Matcher::Grpc { .. })
+    }
+}
+
+/// Returns the first route that matches the request.
+pub fn find<'a>(routes: &'a [Route], request: &http::Request<Vec<u8>>) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(request))
+}
+
+/// Whether `path` is `prefix` or somewhere below it: `/admin` covers `/admin` and `/admin/users`
+/// but not `/administrator`. (A prefix ending in "/" covers everything that starts with it.)
+fn is_under(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::socket;
+
+/// Environment variable through which a process passes its listening sockets to the successor it
+/// execs on SIGUSR2, as comma-separated `address=fd` pairs
+const LISTEN_FDS_VAR: &str = "BALANCEBEAM_LISTEN_FDS";
+
+/// Tracks open client connections so that shutdown can wait for them to finish.
+pub struct Shutdown {
+    /// Flipped to true once we stop accepting connections
+    draining: tokio::sync::watch::Sender<bool>,
+    active: AtomicUsize,
+    /// Notified whenever the last active connection finishes
+    idle: tokio::sync::Notify,
+}
+
+/// Held for as long as a connection (or HTTP/2 stream) is being served.
+pub struct Guard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.shutdown.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            draining: tokio::sync::watch::Sender::new(false),
+            active: AtomicUsize::new(0),
+            idle: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Counts a connection as active until the returned guard is dropped. Take the guard before
+    /// spawning the task that serves the connection, or shutdown could miss it.
+    pub fn track(self: &Arc<Self>) -> Guard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Guard {
+            shutdown: self.clone(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Resolves once shutdown has begun. Connection handlers use this to close idle connections.
+    pub async fn draining(&self) {
+        let mut receiver = self.draining.subscribe();
+        let _ = receiver.wait_for(|draining| *draining).await;
+    }
+
+    /// Tells connection handlers to wrap up, then waits until they all have, or until `timeout`
+    /// passes (in which case whatever is still in flight gets cut off).
+    pub async fn drain(&self, timeout: Duration) {
+        self.draining.send_replace(true);
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            // Register for the notification before checking, so we can't miss it in between
+            idle.as_mut().enable();
+            let active = self.active.load(Ordering::SeqCst);
+            if active == 0 {
+                log::info!("All connections finished");
+                return;
+            }
+            log::info!("Waiting for {} active connections to finish", active);
+            tokio::select! {
+                _ = &mut idle => {}
+                _ = tokio::time::sleep_until(deadline) => {
+                    log::warn!(
+                        "Shutdown deadline passed; cutting off {} active connections",
+                        self.active.load(Ordering::SeqCst)
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Listening sockets handed down by the process we replaced, by the address they were bound to.
+/// There may be several for an address: one per --reuseport-workers worker.
+pub struct Inherited {
+    fds: parking_lot::Mutex<HashMap<String, Vec<RawFd>>>,
+}
+
+impl Inherited {
+    /// Collects (and clears) any sockets passed to us in the environment.
+    pub fn from_env() -> Inherited {
+        let mut fds: HashMap<String, Vec<RawFd>> = HashMap::new();
+        if let Ok(value) = std::env::var(LISTEN_FDS_VAR) {
+            for pair in value.split(',') {
+                match pair
+                    .rsplit_once('=')
+                    .map(|(address, fd)| (address, fd.parse()))
+                {
+                    Some((address, Ok(fd))) => fds.entry(address.to_string()).or_default().push(fd),
+                    _ => log::warn!("Ignoring malformed {} entry {:?}", LISTEN_FDS_VAR, pair),
+                }
+            }
+            std::env::remove_var(LISTEN_FDS_VAR);
+        }
+        Inherited {
+            fds: parking_lot::Mutex::new(fds),
+        }
+    }
+
+    /// Listens on `address` (see socket::Listener::bind), reusing the socket our predecessor was
+    /// listening on there if there is one, so that no connection attempt is refused during a
+    /// restart.
+    pub async fn bind(&self, address: &str, reuseport: bool) -> std::io::Result<socket::Listener> {
+        match self.take(address) {
+            Some(socket) => socket,
+            None => socket::Listener::bind(address, reuseport).await,
+        }
+    }
+
+    /// Takes over one of the sockets our predecessor was listening on at `address`, if it left
+    /// any we haven't taken yet.
+    pub fn take(&self, address: &str) -> Option<std::io::Result<socket::Listener>> {
+        let fd = {
+            let mut fds = self.fds.lock();
+            let remaining = fds.get_mut(address)?;
+            let fd = remaining.remove(0);
+            if remaining.is_empty() {
+                fds.remove(address);
+            }
+            fd
+        };
+        log::info!("Taking over inherited socket for {}", address);
+        // SAFETY: our predecessor passed us this fd as a socket it was listening on at `address`,
+        // and we take ownership of it exactly once (it was just removed from the map)
+        Some(unsafe { socket::Listener::from_raw_fd(address, fd) })
+    }
+
+    /// Closes the inherited sockets nothing took over (e.g. those of workers our predecessor
+    /// had more of than we do), so that clients aren't left queued on a socket no one accepts
+    /// on.
+    pub fn close_unused(&self) {
+        for (address, fds) in self.fds.lock().drain() {
+            for fd in fds {
+                log::info!("Closing unused inherited socket for {}", address);
+                // SAFETY: as in take; nothing else will use the fd, which is gone from the map
+                drop(unsafe { socket::Listener::from_raw_fd(&address, fd) });
+            }
+        }
+    }
+}
+
+/// Starts a fresh copy of our own binary (with the same arguments) that inherits our listening
+/// sockets, so that it can start accepting on them while we finish serving what we have.
+pub fn spawn_successor(listeners: &[(String, RawFd)]) -> std::io::Result<u32> {
+    let mut pairs = Vec::new();
+    for (address, fd) in listeners {
+        let fd = *fd;
+        // Sockets are opened close-on-exec; the successor needs this one to survive exec
+        // SAFETY: fcntl on an fd we own, changing nothing but its flags
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        pairs.push(format!("{}={}", address, fd));
+    }
+    let child = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FDS_VAR, pairs.join(","))
+        .spawn()?;
+    Ok(child.id())
+}
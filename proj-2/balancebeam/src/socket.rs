@@ -0,0 +1,248 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::http2;
+
+/// Prefix of addresses (to bind to, or of upstreams) that name a Unix domain socket, e.g.
+/// `unix:/run/app.sock`
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Connections the kernel queues for a SO_REUSEPORT socket until we accept them: as many as it
+/// allows (net.core.somaxconn caps larger values)
+const LISTEN_BACKLOG: u32 = libc::SOMAXCONN as u32;
+
+/// How long a new client has to set up its connection: to finish a TLS handshake, or the HTTP/2
+/// connection preface once it has sent part of it
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Clients on a Unix socket are on this host, so they count as coming from loopback (for
+/// filters, limits, X-Forwarded-For and logs)
+const UNIX_PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// The path of a `unix:` address, or None for a TCP one.
+pub fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_PREFIX)
+}
+
+/// What to call an upstream in the Host (or :authority) of requests that don't come with one.
+pub fn authority(address: &str) -> &str {
+    if unix_path(address).is_some() {
+        "localhost"
+    } else {
+        address
+    }
+}
+
+/// A listening socket: TCP, or a Unix domain socket for `unix:` addresses.
+pub enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Listens on `address`. With `reuseport`, a TCP socket is opened with SO_REUSEPORT, so that
+    /// more sockets can listen on the same address and the kernel spreads connections between
+    /// them (Unix sockets ignore it).
+    pub async fn bind(address: &str, reuseport: bool) -> io::Result<Listener> {
+        let path = match unix_path(address) {
+            Some(path) => path,
+            None if reuseport => return Ok(Listener::Tcp(bind_reuseport(address).await?)),
+            None => return Ok(Listener::Tcp(tokio::net::TcpListener::bind(address).await?)),
+        };
+        // A process that exits leaves its socket file behind, which would make binding fail.
+        // Only remove it if nothing is listening on it any more, though.
+        let is_socket =
+            std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if is_socket && tokio::net::UnixStream::connect(path).await.is_err() {
+            log::info!("Removing stale socket {}", path);
+            std::fs::remove_file(path)?;
+        }
+        Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?))
+    }
+
+    /// Takes ownership of a listening socket bound to `address`.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open listening socket of the kind `address` names (TCP or Unix), that
+    /// nothing else owns.
+    pub unsafe fn from_raw_fd(address: &str, fd: RawFd) -> io::Result<Listener> {
+        if unix_path(address).is_some() {
+            let listener = std::os::unix::net::UnixListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Unix(tokio::net::UnixListener::from_std(
+                listener,
+            )?))
+        } else {
+            let listener = std::net::TcpListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+        }
+    }
+
+    /// Accepts a connection, returning it along with the IP address of the client.
+    pub async fn accept(&self) -> io::Result<(Stream, IpAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), peer.ip()))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), UNIX_PEER))
+            }
+        }
+    }
+}
+
+/// Opens a TCP socket with SO_REUSEPORT set, listening on `address`.
+async fn bind_reuseport(address: &str) -> io::Result<tokio::net::TcpListener> {
+    let address = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to"))?;
+    let socket = if address.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    // Like TcpListener::bind, so that a restart doesn't have to wait out TIME_WAIT
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// A connection over TCP or a Unix domain socket.
+pub enum Stream {
+    Tcp(tokio::net::TcpStream),
+    Unix(tokio::net::UnixStream),
+}
+
+impl Stream {
+    pub async fn connect(address: &str) -> io::Result<Stream> {
+        match unix_path(address) {
+            Some(path) => Ok(Stream::Unix(tokio::net::UnixStream::connect(path).await?)),
+            None => Ok(Stream::Tcp(tokio::net::TcpStream::connect(address).await?)),
+        }
+    }
+
+    /// Reads what has arrived without consuming it.
+    async fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let stream = match self {
+            Stream::Tcp(stream) => return stream.peek(buffer).await,
+            Stream::Unix(stream) => stream,
+        };
+        // tokio has no peek for Unix streams; do it with recv(MSG_PEEK) once there's data
+        loop {
+            stream.readable().await?;
+            let peeked = stream.try_io(tokio::io::Interest::READABLE, || {
+                // SAFETY: recv writes at most buffer.len() bytes into buffer, which we own
+                let bytes_read = unsafe {
+                    libc::recv(
+                        stream.as_raw_fd(),
+                        buffer.as_mut_ptr().cast(),
+                        buffer.len(),
+                        libc::MSG_PEEK,
+                    )
+                };
+                if bytes_read < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(bytes_read as usize)
+                }
+            });
+            match peeked {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Peeks at the first bytes sent on a cleartext connection, without consuming them, to
+    /// decide whether the client is speaking HTTP/2 with prior knowledge. A client that stops
+    /// partway through the preface is given HANDSHAKE_TIMEOUT to finish it, and is otherwise
+    /// treated as speaking HTTP/1.1 (which the start of the preface is a malformed request in).
+    pub async fn is_prior_knowledge(&self) -> bool {
+        let mut buffer = [0_u8; http2::CONNECTION_PREFACE.len()];
+        let peek_preface = async {
+            loop {
+                let bytes_read = match self.peek(&mut buffer).await {
+                    Ok(bytes_read) => bytes_read,
+                    Err(_) => return false,
+                };
+                if bytes_read == 0 || !http2::could_be_preface(&buffer[..bytes_read]) {
+                    return false;
+                }
+                if bytes_read == http2::CONNECTION_PREFACE.len() {
+                    return true;
+                }
+                // Only part of the preface has arrived so far. peek() returns immediately while
+                // data is buffered, so back off briefly instead of spinning.
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        };
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, peek_preface).await {
+            Ok(is_preface) => is_preface,
+            Err(_) => {
+                log::debug!("Client didn't finish the HTTP/2 preface in time");
+                false
+            }
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use http::header;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::config::StaticFilesConfig;
+use crate::{cache, filter, response};
+
+/// Content types by file extension; anything else is served as application/octet-stream.
+const CONTENT_TYPES: [(&str, &str); 20] = [
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("ico", "image/x-icon"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("map", "application/json"),
+];
+
+/// What part of a file a request's Range header asks for.
+enum ByteRange {
+    /// No (usable) Range header: the whole file
+    Whole,
+    /// From the first byte to the second, inclusive
+    Part(usize, usize),
+    /// A range that starts past the end of the file
+    Unsatisfiable,
+}
+
+/// Serves a route's requests from files in a local directory instead of an upstream. The part
+/// of the path after the route's prefix names the file; a directory is served by its index
+/// file. Paths with `..` or hidden (dot) segments are refused, as are symlinks that lead out of
+/// the directory. A response that would carry more than `max_size` bytes of a file is refused.
+///
+/// ```yaml
+/// routes:
+///   - path_prefix: /assets/
+///     static_files: { root: /srv/www/assets, index: [index.html], max_size: 100000000 }
+/// ```
+pub struct StaticFiles {
+    /// The directory, canonicalized
+    root: PathBuf,
+    /// The route's path prefix, which isn't part of the file's path
+    prefix: String,
+    /// Files to try, in order, for a request for a directory
+    index: Vec<String>,
+    /// The most bytes of a file one response may carry
+    max_size: usize,
+}
+
+impl StaticFiles {
+    pub fn new(config: &StaticFilesConfig, prefix: &str) -> Result<StaticFiles, String> {
+        let root = std::fs::canonicalize(&config.root)
+            .map_err(|err| format!("static_files root {}: {}", config.root, err))?;
+        if !root.is_dir() {
+            return Err(format!(
+                "static_files root {} is not a directory",
+                config.root
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            prefix: prefix.to_string(),
+            index: config.index.clone(),
+            max_size: config.max_size,
+        })
+    }
+
+    /// Maps a request path to a path under the root, or None if it tries to leave the root (or
+    /// names a hidden file).
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = path.strip_prefix(self.prefix.as_str())?;
+        let mut resolved = self.root.clone();
+        for segment in filter::percent_decode(relative).split('/') {
+            match segment {
+                "" | "." => {}
+                _ if segment.starts_with('.') || segment.contains(['\\', '\0']) => return None,
+                segment => resolved.push(segment),
+            }
+        }
+        Some(resolved)
+    }
+
+    pub async fn serve(&self, request: &http::Request<Vec<u8>>) -> http::Response<Vec<u8>> {
+        let method = request.method();
+        if method != http::Method::GET && method != http::Method::HEAD {
+            let mut response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(header::ALLOW, http::HeaderValue::from_static("GET, HEAD"));
+            return response;
+        }
+        let mut path = match self.resolve(request.uri().path()) {
+            Some(path) => path,
+            None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+        };
+        let mut metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(_) => return response::make_http_error(http::StatusCode::NOT_FOUND),
+        };
+        if metadata.is_dir() {
+            // Relative links in an index page only work if the URL ends with a slash
+            if !request.uri().path().ends_with('/') {
+                let location = match request.uri().query() {
+                    Some(query) => format!("{}/?{}", request.uri().path(), query),
+                    None => format!("{}/", request.uri().path()),
+                };
+                let mut response = response::make_http_error(http::StatusCode::MOVED_PERMANENTLY);
+                if let Ok(location) = http::HeaderValue::from_str(&location) {
+                    response.headers_mut().insert(header::LOCATION, location);
+                }
+                return response;
+            }
+            let mut index = None;
+            for name in &self.index {
+                let candidate = path.join(name);
+                if let Ok(candidate_metadata) = tokio::fs::metadata(&candidate).await {
+                    if candidate_metadata.is_file() {
+                        index = Some((candidate, candidate_metadata));
+                        break;
+                    }
+                }
+            }
+            match index {
+                Some((index_path, index_metadata)) => {
+                    path = index_path;
+                    metadata = index_metadata;
+                }
+                None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+            }
+        }
+        // NOTE: a symlink inside the root could point anywhere
+        match tokio::fs::canonicalize(&path).await {
+            Ok(canonical) if canonical.starts_with(&self.root) && metadata.is_file() => {}
+            _ => return response::make_http_error(http::StatusCode::NOT_FOUND),
+        }
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        self.respond(request, &path, metadata.len() as usize, modified)
+            .await
+    }
+
+    /// Builds the response for a file of `length` bytes, honouring conditional and Range
+    /// requests. Only the bytes the response carries are read from disk (none for HEAD).
+    async fn respond(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        path: &std::path::Path,
+        length: usize,
+        modified: SystemTime,
+    ) -> http::Response<Vec<u8>> {
+        let seconds = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let etag = format!("\"{:x}-{:x}\"", seconds, length);
+        let last_modified = httpdate::fmt_http_date(modified);
+        let content_type = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| {
+                CONTENT_TYPES
+                    .iter()
+                    .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+            })
+            .map_or("application/octet-stream", |(_, content_type)| content_type);
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(header::ETAG, http::HeaderValue::from_str(&etag).unwrap());
+        headers.insert(
+            header::LAST_MODIFIED,
+            http::HeaderValue::from_str(&last_modified).unwrap(),
+        );
+        headers.insert(
+            header::ACCEPT_RANGES,
+            http::HeaderValue::from_static("bytes"),
+        );
+        if cache::client_has_current_copy(request, &headers) {
+            return build(http::StatusCode::NOT_MODIFIED, headers, Vec::new());
+        }
+        headers.insert(
+            header::CONTENT_TYPE,
+            http::HeaderValue::from_static(content_type),
+        );
+
+        // A Range only applies if the client's copy (per If-Range) is the one we have
+        let range = match request.headers().get(header::RANGE) {
+            Some(range) if if_range_matches(request, &etag, &last_modified) => {
+                byte_range(range.to_str().unwrap_or(""), length)
+            }
+            _ => ByteRange::Whole,
+        };
+        let (status, start, end) = match range {
+            ByteRange::Whole => (http::StatusCode::OK, 0, length),
+            ByteRange::Part(start, end) => {
+                let content_range = format!("bytes {}-{}/{}", start, end, length);
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    http::HeaderValue::from_str(&content_range).unwrap(),
+                );
+                (http::StatusCode::PARTIAL_CONTENT, start, end + 1)
+            }
+            ByteRange::Unsatisfiable => {
+                let mut response =
+                    response::make_http_error(http::StatusCode::RANGE_NOT_SATISFIABLE);
+                let content_range = format!("bytes */{}", length);
+                response.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    http::HeaderValue::from_str(&content_range).unwrap(),
+                );
+                return response;
+            }
+        };
+        // NOTE: bodies are buffered whole, so this bounds the memory one request can take up.
+        // HEAD gets the same answer a GET would.
+        if end - start > self.max_size {
+            log::warn!(
+                "Not serving {} bytes of {:?}: more than max_size ({})",
+                end - start,
+                path,
+                self.max_size
+            );
+            let mut response = response::make_http_error(http::StatusCode::INTERNAL_SERVER_ERROR);
+            // Ranges small enough are still served
+            response.headers_mut().insert(
+                header::ACCEPT_RANGES,
+                http::HeaderValue::from_static("bytes"),
+            );
+            return response;
+        }
+        headers.insert(header::CONTENT_LENGTH, http::HeaderValue::from(end - start));
+        if request.method() == http::Method::HEAD {
+            return build(status, headers, Vec::new());
+        }
+        match read_range(path, start, end - start).await {
+            Ok(body) => build(status, headers, body),
+            Err(err) => {
+                log::warn!("Could not read {:?}: {}", path, err);
+                // NOTE: the file may have gone (or shrunk) since we looked it up
+                let status = match err.kind() {
+                    std::io::ErrorKind::NotFound => http::StatusCode::NOT_FOUND,
+                    std::io::ErrorKind::PermissionDenied => http::StatusCode::FORBIDDEN,
+                    _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                response::make_http_error(status)
+            }
+        }
+    }
+}
+
+/// Reads `length` bytes of a file, starting `start` bytes in.
+async fn read_range(
+    path: &std::path::Path,
+    start: usize,
+    length: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(start as u64)).await?;
+    let mut contents = vec![0_u8; length];
+    file.read_exact(&mut contents).await?;
+    Ok(contents)
+}
+
+fn build(
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
+    let mut response = http::Response::builder()
+        .status(status)
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap();
+    *response.headers_mut() = headers;
+    response
+}
+
+/// Whether a request's If-Range (if any) names the file as we have it, by ETag or date.
+fn if_range_matches(request: &http::Request<Vec<u8>>, etag: &str, last_modified: &str) -> bool {
+    match request.headers().get(header::IF_RANGE) {
+        Some(if_range) => if_range == etag || if_range == last_modified,
+        None => true,
+    }
+}
+
+/// Reads a Range header for a file of `length` bytes. Only a single range of bytes is
+/// supported; anything else gets the whole file, as RFC 9110 allows.
+fn byte_range(value: &str, length: usize) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+    let parse = |bound: &str| {
+        if bound.is_empty() || !bound.bytes().all(|byte| byte.is_ascii_digit()) {
+            None
+        } else {
+            // Anything too long for a usize is past the end of the file anyway
+            Some(bound.parse::<usize>().unwrap_or(usize::MAX))
+        }
+    };
+    match (parse(first), parse(last)) {
+        // The last n bytes
+        (None, Some(suffix)) if first.is_empty() => {
+            if suffix == 0 || length == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Part(length.saturating_sub(suffix), length - 1)
+            }
+        }
+        (Some(start), end) if last.is_empty() || end.is_some() => {
+            let end = end.unwrap_or(usize::MAX);
+            if end < start {
+                ByteRange::Whole
+            } else if start >= length {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Part(start, end.min(length - 1))
+            }
+        }
+        _ => ByteRange::Whole,
+    }
+}
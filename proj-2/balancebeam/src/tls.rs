@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// ALPN protocol identifiers we offer to TLS clients, in order of preference. Clients that
+/// negotiate "h2" are served over HTTP/2; everybody else gets HTTP/1.1.
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+/// Builds a TLS acceptor from a PEM-encoded certificate chain and private key. The acceptor
+/// advertises both HTTP/2 and HTTP/1.1 via ALPN.
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<tokio_rustls::TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("could not read certificate {}: {:?}", cert_path, err))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| format!("could not read private key {}: {:?}", key_path, err))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+    config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()];
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Returns true if the client negotiated HTTP/2 during the TLS handshake.
+pub fn negotiated_h2<S>(stream: &tokio_rustls::server::TlsStream<S>) -> bool {
+    stream.get_ref().1.alpn_protocol() == Some(ALPN_H2)
+}
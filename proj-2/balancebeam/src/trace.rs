@@ -0,0 +1,285 @@
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+
+use crate::upstream;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+/// Longest client-supplied request ID we pass along; longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+/// How often finished spans are sent to the collector
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Spans waiting for export beyond this are dropped, so a dead collector can't eat our memory
+const MAX_QUEUED_SPANS: usize = 4096;
+
+/// Makes sure the request carries an X-Request-Id, generating a random (version 4 UUID) one if
+/// the client didn't send a usable one, and returns it.
+pub fn ensure_request_id(request: &mut http::Request<Vec<u8>>) -> http::HeaderValue {
+    if let Some(id) = request.headers().get(REQUEST_ID_HEADER) {
+        if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.to_str().is_ok() {
+            return id.clone();
+        }
+    }
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = to_hex(&bytes);
+    let id = format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    );
+    let id = http::HeaderValue::from_str(&id).unwrap();
+    request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+    id
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// The parts of a W3C traceparent header: version-trace_id-parent_id-flags.
+struct TraceParent {
+    trace_id: [u8; 16],
+    parent_id: [u8; 8],
+    flags: u8,
+}
+
+fn parse_traceparent(value: &http::HeaderValue) -> Option<TraceParent> {
+    let mut parts = value.to_str().ok()?.trim().split('-');
+    let version = from_hex::<1>(parts.next()?)?[0];
+    let trace_id = from_hex::<16>(parts.next()?)?;
+    let parent_id = from_hex::<8>(parts.next()?)?;
+    let flags = from_hex::<1>(parts.next()?)?[0];
+    // Version 00 has exactly four fields; later versions may append more, and ff is invalid
+    if version == 0xff || (version == 0 && parts.next().is_some()) {
+        return None;
+    }
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some(TraceParent {
+        trace_id,
+        parent_id,
+        flags,
+    })
+}
+
+/// balancebeam's span for one proxied request.
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    sampled: bool,
+    start: SystemTime,
+    method: String,
+    path: String,
+    client_ip: String,
+    request_id: String,
+}
+
+impl Span {
+    /// Starts a span for a request, continuing the client's trace if it sent a valid
+    /// traceparent, and rewrites the request's traceparent so the upstream's spans become
+    /// children of ours. tracestate is passed through untouched, as we add no state of our own.
+    pub fn start(
+        request: &mut http::Request<Vec<u8>>,
+        client_ip: &str,
+        request_id: &http::HeaderValue,
+    ) -> Span {
+        let parent = request
+            .headers()
+            .get(TRACEPARENT_HEADER)
+            .and_then(parse_traceparent);
+        let mut rng = rand::thread_rng();
+        let (trace_id, parent_id, flags) = match parent {
+            Some(parent) => (parent.trace_id, Some(parent.parent_id), parent.flags),
+            None => {
+                // tracestate is meaningless without the traceparent it belongs to
+                request.headers_mut().remove(TRACESTATE_HEADER);
+                (rng.gen(), None, 0x01)
+            }
+        };
+        let span_id: [u8; 8] = loop {
+            let id: [u8; 8] = rng.gen();
+            if id != [0; 8] {
+                break id;
+            }
+        };
+        let traceparent = format!(
+            "00-{}-{}-{:02x}",
+            to_hex(&trace_id),
+            to_hex(&span_id),
+            flags
+        );
+        request.headers_mut().insert(
+            TRACEPARENT_HEADER,
+            http::HeaderValue::from_str(&traceparent).unwrap(),
+        );
+        Span {
+            trace_id,
+            span_id,
+            parent_id,
+            sampled: flags & 0x01 != 0,
+            start: SystemTime::now(),
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            client_ip: client_ip.to_string(),
+            request_id: String::from_utf8_lossy(request_id.as_bytes()).into_owned(),
+        }
+    }
+
+    /// Finishes the span, describing it in OTLP/JSON form.
+    fn finish(self, response: &http::Response<Vec<u8>>) -> serde_json::Value {
+        let nanos = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+        let string = |key: &str, value: &str| {
+            serde_json::json!({
+                "key": key,
+                "value": { "stringValue": value },
+            })
+        };
+        let mut attributes = vec![
+            string("http.request.method", &self.method),
+            string("url.path", &self.path),
+            string("client.address", &self.client_ip),
+            string("balancebeam.request_id", &self.request_id),
+            serde_json::json!({
+                "key": "http.response.status_code",
+                "value": { "intValue": response.status().as_u16().to_string() }
+            }),
+        ];
+        if let Some(upstream) = response
+            .extensions()
+            .get::<crate::access_log::UpstreamTiming>()
+        {
+            attributes.push(string("server.address", &upstream.address));
+        }
+        let mut span = serde_json::json!({
+            "traceId": to_hex(&self.trace_id),
+            "spanId": to_hex(&self.span_id),
+            "name": self.method,
+            // SPAN_KIND_SERVER
+            "kind": 2,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": attributes,
+            // STATUS_CODE_ERROR for failures on our side, STATUS_CODE_UNSET otherwise
+            "status": { "code": if response.status().is_server_error() { 2 } else { 0 } },
+        });
+        if let Some(parent_id) = self.parent_id {
+            span["parentSpanId"] = serde_json::Value::String(to_hex(&parent_id));
+        }
+        span
+    }
+}
+
+/// Batches finished spans and periodically sends them to an OpenTelemetry collector using
+/// OTLP/HTTP with JSON encoding.
+pub struct Exporter {
+    /// Collector address (host:port); spans are POSTed to /v1/traces
+    endpoint: String,
+    service_name: String,
+    queue: parking_lot::Mutex<Vec<serde_json::Value>>,
+}
+
+impl Exporter {
+    pub fn new(endpoint: String, service_name: String) -> Exporter {
+        Exporter {
+            endpoint,
+            service_name,
+            queue: parking_lot::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Finishes a span and queues it for export, unless the trace isn't sampled.
+    pub fn end(&self, span: Span, response: &http::Response<Vec<u8>>) {
+        if !span.sampled {
+            return;
+        }
+        let mut queue = self.queue.lock();
+        if queue.len() >= MAX_QUEUED_SPANS {
+            log::warn!("Dropping span: export queue is full");
+            return;
+        }
+        queue.push(span.finish(response));
+    }
+
+    /// Exports queued spans every EXPORT_INTERVAL, forever.
+    pub async fn run(&self) {
+        loop {
+            tokio::time::sleep(EXPORT_INTERVAL).await;
+            let spans = std::mem::take(&mut *self.queue.lock());
+            if spans.is_empty() {
+                continue;
+            }
+            let count = spans.len();
+            if let Err(err) = self.export(spans).await {
+                log::warn!(
+                    "Failed to export {} spans to {}: {}",
+                    count,
+                    self.endpoint,
+                    err
+                );
+            }
+        }
+    }
+
+    async fn export(&self, spans: Vec<serde_json::Value>) -> Result<(), String> {
+        let body = serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": self.service_name },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "balancebeam" },
+                    "spans": spans,
+                }],
+            }],
+        })
+        .to_string()
+        .into_bytes();
+        let request = http::Request::builder()
+            .method(http::Method::POST)
+            .uri("/v1/traces")
+            .header("Host", &self.endpoint)
+            .header("Content-Type", "application/json")
+            .header("Content-Length", body.len().to_string())
+            .body(body)
+            .unwrap();
+        let mut conn = upstream::Connection::connect(&self.endpoint, upstream::Protocol::Http1)
+            .await
+            .map_err(|err| err.to_string())?;
+        let response = conn
+            .send(request, &self.endpoint)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+        if !response.status().is_success() {
+            return Err(format!("collector responded {}", response.status()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use bytes::Bytes;
+
+use crate::{http2, request, response, socket};
+
+/// The protocol balancebeam speaks when talking to upstream servers.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// One request at a time per connection
+    Http1,
+    /// HTTP/2 with prior knowledge (h2c); many requests are multiplexed onto one connection
+    Http2,
+}
+
+/// An open connection to an upstream server.
+pub enum Connection {
+    Http1(socket::Stream),
+    /// Cloning the SendRequest handle opens another stream on the same HTTP/2 connection
+    Http2(h2::client::SendRequest<Bytes>),
+}
+
+impl Connection {
+    pub async fn connect(address: &str, protocol: Protocol) -> Result<Connection, std::io::Error> {
+        let stream = socket::Stream::connect(address).await?;
+        match protocol {
+            Protocol::Http1 => Ok(Connection::Http1(stream)),
+            Protocol::Http2 => match http2::connect(stream).await {
+                Ok(send_request) => Ok(Connection::Http2(send_request)),
+                Err(err) => Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    format!("HTTP/2 handshake failed: {:?}", err),
+                )),
+            },
+        }
+    }
+
+    /// Sends a request to the upstream and reads back its response, translating between HTTP
+    /// versions if the client and upstream speak different ones.
+    pub async fn send(
+        &mut self,
+        mut request: http::Request<Vec<u8>>,
+        upstream_address: &str,
+    ) -> Result<http::Response<Vec<u8>>, response::Error> {
+        // How the client's connection is managed is none of the upstream's business
+        request::remove_hop_by_hop_headers(request.headers_mut());
+        match self {
+            Connection::Http1(stream) => {
+                http2::downgrade_request(&mut request);
+                // We speak HTTP/1.1 to upstreams, whatever the client spoke to us
+                *request.version_mut() = http::Version::HTTP_11;
+                request::write_to_stream(&request, stream)
+                    .await
+                    .map_err(response::Error::ConnectionError)?;
+                response::read_from_stream(stream, request.method()).await
+            }
+            Connection::Http2(send_request) => {
+                http2::upgrade_request(&mut request, socket::authority(upstream_address));
+                http2::send_request(send_request.clone(), request)
+                    .await
+                    .map_err(response::Error::StreamError)
+            }
+        }
+    }
+}
+
+/// Connections to a single upstream server, shared by the requests of one client connection
+/// (which may arrive concurrently over HTTP/2). HTTP/1.1 connections can only carry one request
+/// at a time, so idle ones are kept around for reuse; an HTTP/2 connection is simply shared by
+/// every stream.
+pub struct ConnectionPool {
+    pub address: String,
+    protocol: Protocol,
+    idle: parking_lot::Mutex<Vec<Connection>>,
+}
+
+impl ConnectionPool {
+    pub fn new(address: String, protocol: Protocol) -> ConnectionPool {
+        ConnectionPool {
+            address,
+            protocol,
+            idle: parking_lot::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes an idle connection from the pool, opening a new one if there is none.
+    pub async fn get(&self) -> Result<Connection, std::io::Error> {
+        if let Some(connection) = self.take_idle() {
+            return Ok(connection);
+        }
+        let connection = Connection::connect(&self.address, self.protocol).await?;
+        if let Connection::Http2(send_request) = &connection {
+            self.idle
+                .lock()
+                .push(Connection::Http2(send_request.clone()));
+        }
+        Ok(connection)
+    }
+
+    fn take_idle(&self) -> Option<Connection> {
+        let mut idle = self.idle.lock();
+        match idle.last() {
+            Some(Connection::Http2(send_request)) => Some(Connection::Http2(send_request.clone())),
+            Some(Connection::Http1(_)) => idle.pop(),
+            None => None,
+        }
+    }
+
+    /// Returns a connection to the pool once the response on it has been read completely.
+    pub fn put(&self, connection: Connection) {
+        if let Connection::Http1(_) = connection {
+            self.idle.lock().push(connection);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use crate::listener::Listener;
+use crate::{shutdown, socket, ProxyState};
+
+/// Threads that each run a single-threaded runtime accepting (and serving) clients on a
+/// SO_REUSEPORT socket of their own for every TCP listener, so that the kernel spreads
+/// connections between them rather than all of them being accepted by one task. Clients stay on
+/// the worker that accepted them; pools, health checks and everything else are still shared.
+pub struct Workers {
+    /// Tells the workers to stop accepting
+    stop: tokio::sync::watch::Sender<bool>,
+    /// Each worker reports here once it has closed its sockets
+    stopped: Vec<tokio::sync::oneshot::Receiver<()>>,
+    /// The sockets the workers opened besides the ones they were given, to hand to a successor
+    fds: Vec<(String, RawFd)>,
+}
+
+impl Workers {
+    /// Starts `count` workers, pinning each one to a CPU of its own if `pin` is set. The first
+    /// worker takes over `sockets`; the rest take over more sockets our predecessor passed us
+    /// (see shutdown::Inherited), or open sockets of their own on the same addresses. If that
+    /// isn't possible because `sockets` weren't opened with SO_REUSEPORT (e.g. we inherited them
+    /// from a process without workers), they share the first worker's sockets instead.
+    pub async fn start(
+        count: usize,
+        pin: bool,
+        sockets: Vec<(socket::Listener, Arc<Listener>)>,
+        inherited: &shutdown::Inherited,
+        state: &Arc<ProxyState>,
+    ) -> Result<Workers, String> {
+        let cpus = if pin {
+            allowed_cpus().map_err(|err| format!("could not read CPU affinity: {}", err))?
+        } else {
+            Vec::new()
+        };
+        let (stop, _) = tokio::sync::watch::channel(false);
+        let mut workers = Workers {
+            stop,
+            stopped: Vec::with_capacity(count),
+            fds: Vec::new(),
+        };
+        // The sockets belong to the main runtime; each worker re-registers its own with its
+        // runtime
+        let first = sockets
+            .into_iter()
+            .map(|(socket, listener)| Ok((into_std(socket)?, listener)))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|err| err.to_string())?;
+        let mut all_sockets = Vec::with_capacity(count);
+        for _ in 1..count {
+            let mut own = Vec::with_capacity(first.len());
+            for (shared, listener) in &first {
+                let (socket, opened) = open_socket(shared, listener, inherited).await?;
+                if opened {
+                    workers
+                        .fds
+                        .push((listener.address.clone(), socket.as_raw_fd()));
+                }
+                own.push((socket, listener.clone()));
+            }
+            all_sockets.push(own);
+        }
+        all_sockets.insert(0, first);
+        for (index, sockets) in all_sockets.into_iter().enumerate() {
+            let cpu = (!cpus.is_empty()).then(|| cpus[index % cpus.len()]);
+            let state = state.clone();
+            let stop = workers.stop.subscribe();
+            let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+            let (stopped_tx, stopped_rx) = tokio::sync::oneshot::channel();
+            std::thread::Builder::new()
+                .name(format!("worker-{}", index))
+                .spawn(move || {
+                    let setup = || {
+                        if let Some(cpu) = cpu {
+                            pin_to_cpu(cpu)
+                                .map_err(|err| format!("could not pin to CPU {}: {}", cpu, err))?;
+                        }
+                        tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()
+                            .map_err(|err| format!("could not start runtime: {}", err))
+                    };
+                    let runtime = match setup() {
+                        Ok(runtime) => runtime,
+                        Err(err) => {
+                            let _ = started_tx.send(Err(format!("worker {}: {}", index, err)));
+                            return;
+                        }
+                    };
+                    runtime.block_on(async move {
+                        let sockets = match register_sockets(sockets) {
+                            Ok(sockets) => sockets,
+                            Err(err) => {
+                                let _ = started_tx.send(Err(format!("worker {}: {}", index, err)));
+                                return;
+                            }
+                        };
+                        let _ = started_tx.send(Ok(()));
+                        serve(index, sockets, state, stop, stopped_tx).await;
+                    });
+                })
+                .map_err(|err| format!("could not start worker thread: {}", err))?;
+            started_rx
+                .await
+                .map_err(|_| format!("worker {} exited while starting", index))??;
+            match cpu {
+                Some(cpu) => log::info!("Started worker {} on CPU {}", index, cpu),
+                None => log::info!("Started worker {}", index),
+            }
+            workers.stopped.push(stopped_rx);
+        }
+        Ok(workers)
+    }
+
+    /// The sockets the workers listen on besides the ones they were started with, which a
+    /// successor needs along with those.
+    pub fn fds(&self) -> &[(String, RawFd)] {
+        &self.fds
+    }
+
+    /// Makes every worker stop accepting, returning once they have all closed their sockets.
+    /// They keep serving the clients they already have.
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+        for stopped in self.stopped {
+            let _ = stopped.await;
+        }
+    }
+}
+
+/// Detaches a TCP socket from the runtime it was opened on.
+fn into_std(socket: socket::Listener) -> io::Result<std::net::TcpListener> {
+    match socket {
+        socket::Listener::Tcp(socket) => socket.into_std(),
+        socket::Listener::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unix sockets can't be shared between workers",
+        )),
+    }
+}
+
+/// Opens another socket for `listener`, besides `shared`, returning it and whether it is one
+/// of its own (rather than a copy of `shared`).
+async fn open_socket(
+    shared: &std::net::TcpListener,
+    listener: &Listener,
+    inherited: &shutdown::Inherited,
+) -> Result<(std::net::TcpListener, bool), String> {
+    let socket = match inherited.take(&listener.address) {
+        Some(socket) => socket,
+        None => socket::Listener::bind(&listener.address, true).await,
+    };
+    match socket {
+        Ok(socket) => Ok((
+            into_std(socket).map_err(|err| format!("{}: {}", listener.address, err))?,
+            true,
+        )),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            log::warn!(
+                "Could not open another socket on {} ({}); the workers will share one",
+                listener.address,
+                err
+            );
+            let socket = shared
+                .try_clone()
+                .map_err(|err| format!("{}: {}", listener.address, err))?;
+            Ok((socket, false))
+        }
+        Err(err) => Err(format!("could not bind to {}: {}", listener.address, err)),
+    }
+}
+
+/// Registers a worker's sockets with its runtime.
+fn register_sockets(
+    sockets: Vec<(std::net::TcpListener, Arc<Listener>)>,
+) -> Result<Vec<(socket::Listener, Arc<Listener>)>, String> {
+    sockets
+        .into_iter()
+        .map(|(socket, listener)| {
+            let socket = tokio::net::TcpListener::from_std(socket)
+                .map_err(|err| format!("{}: {}", listener.address, err))?;
+            Ok((socket::Listener::Tcp(socket), listener))
+        })
+        .collect()
+}
+
+/// Runs worker `index`: accepts on its sockets until told to stop, then closes them and keeps
+/// serving its clients until the process exits.
+async fn serve(
+    index: usize,
+    sockets: Vec<(socket::Listener, Arc<Listener>)>,
+    state: Arc<ProxyState>,
+    mut stop: tokio::sync::watch::Receiver<bool>,
+    stopped: tokio::sync::oneshot::Sender<()>,
+) {
+    let accepting: Vec<_> = sockets
+        .into_iter()
+        .map(|(socket, listener)| {
+            tokio::spawn(crate::accept_clients(
+                socket,
+                listener,
+                Some(index),
+                state.clone(),
+            ))
+        })
+        .collect();
+    let _ = stop.wait_for(|stop| *stop).await;
+    for task in accepting {
+        task.abort();
+        let _ = task.await;
+    }
+    let _ = stopped.send(());
+    // NOTE: returning would drop the runtime, and with it the connections still being served.
+    // The process exits once shutdown has drained them.
+    std::future::pending::<()>().await;
+}
+
+/// The CPUs this process may run on.
+fn allowed_cpus() -> io::Result<Vec<usize>> {
+    // SAFETY: cpu_set_t is plain data, for which all zeroes is a valid (empty) value, and
+    // sched_getaffinity writes at most size_of::<cpu_set_t>() bytes into it
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect())
+    }
+}
+
+/// Restricts the calling thread to one CPU.
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    // SAFETY: as in allowed_cpus; sched_setaffinity only reads the set
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,6 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{bind_any_port, init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
 use std::time::Duration;
 use tokio::time::sleep;
@@ -240,6 +240,39 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// An upstream that accepts connections but never responds should fail its health checks
+/// (rather than hold up the checks of every other upstream), and so stop getting requests.
+#[tokio::test]
+async fn test_active_health_checks_time_out() {
+    init_logging();
+    let (listener, stalled_address) = bind_any_port();
+    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+    let stalled = tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&stalled_address, &upstream.address], Some(1), None).await;
+
+    log::info!("Waiting a few seconds for the active health check to run...");
+    sleep(Duration::from_secs(3)).await;
+
+    log::info!("Sending some requests");
+    for i in 0..10 {
+        let path = format!("/after-check-{}", i);
+        let response_text = tokio::time::timeout(Duration::from_secs(2), balancebeam.get(&path))
+            .await
+            .expect("Request went to the stalled upstream")
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    stalled.abort();
+    Box::new(upstream).stop().await;
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
@@ -271,7 +304,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -0,0 +1,135 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+async fn setup(upstream_protocol: &str) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--upstream-protocol", upstream_protocol],
+    )
+    .await;
+    (balancebeam, upstream)
+}
+
+/// Speak cleartext HTTP/2 with prior knowledge to balancebeam, and make sure requests are
+/// translated to HTTP/1.1 for the upstream.
+#[tokio::test]
+async fn test_h2c_client_to_http1_upstream() {
+    let (balancebeam, upstream) = setup("http1").await;
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+
+    log::info!("Sending a GET request over HTTP/2");
+    let response = client
+        .get(format!("http://{}/first_url", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.version(), http::Version::HTTP_2);
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("GET /first_url HTTP/1.1"));
+    assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+
+    log::info!("Sending a POST request over HTTP/2");
+    let response_text = client
+        .post(format!("http://{}/first_url", balancebeam.address))
+        .body("Hello world!")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("POST /first_url HTTP/1.1"));
+    assert!(response_text.contains("\n\nHello world!"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+}
+
+/// Send plain HTTP/1.1 requests and make sure they reach an HTTP/2 upstream.
+#[tokio::test]
+async fn test_http1_client_to_http2_upstream() {
+    let (balancebeam, upstream) = setup("http2").await;
+
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        // HTTP/2 requests carry an absolute URI, so the echo server prints the authority too
+        assert!(response_text.starts_with("GET http://"));
+        assert!(response_text.contains(&format!("{} HTTP/2.0", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+}
+
+/// Open many concurrent streams on a single HTTP/2 client connection and make sure each one gets
+/// its own response.
+#[tokio::test]
+async fn test_multiplexed_streams() {
+    let num_requests = 20;
+    for upstream_protocol in ["http1", "http2"] {
+        let (balancebeam, upstream) = setup(upstream_protocol).await;
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+
+        let mut tasks = Vec::new();
+        for i in 0..num_requests {
+            let client = client.clone();
+            let url = format!("http://{}/stream-{}", balancebeam.address, i);
+            tasks.push(tokio::spawn(async move {
+                let response_text = client
+                    .get(url)
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam")
+                    .text()
+                    .await
+                    .unwrap();
+                assert!(response_text.contains(&format!("/stream-{} HTTP/", i)));
+            }));
+        }
+        for task in tasks {
+            task.await.expect("Task panicked");
+        }
+
+        let num_requests_received = Box::new(upstream).stop().await;
+        assert_eq!(num_requests_received, num_requests);
+    }
+}
+
+/// A client that stops partway through the HTTP/2 preface shouldn't hold its connection forever:
+/// it is eventually answered as the malformed HTTP/1.1 request the start of the preface is.
+#[tokio::test]
+async fn test_partial_preface_times_out() {
+    let (balancebeam, upstream) = setup("http1").await;
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    stream.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam is still waiting for the rest of the preface")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 400"), "Got {:?}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use common::{encode_message, init_logging, write_config, BalanceBeam, GrpcServer, Server};
+use hyper::body::HttpBody;
+use std::time::Duration;
+use tokio::time::sleep;
+
+type GrpcClient = hyper::Client<hyper::client::HttpConnector>;
+
+fn grpc_client() -> GrpcClient {
+    hyper::Client::builder().http2_only(true).build_http()
+}
+
+/// Makes a unary gRPC call through balancebeam, returning the reply message and grpc-status.
+async fn grpc_call(
+    client: &GrpcClient,
+    balancebeam: &BalanceBeam,
+    path: &str,
+    message: &[u8],
+) -> (Vec<u8>, Option<u32>) {
+    let request = hyper::Request::post(format!("http://{}{}", balancebeam.address, path))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(hyper::Body::from(encode_message(message)))
+        .unwrap();
+    let response = client
+        .request(request)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 200, "gRPC call failed at the HTTP level");
+    let grpc_status = |headers: &http::HeaderMap| {
+        headers
+            .get("grpc-status")
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+    };
+    let header_status = grpc_status(response.headers());
+    let mut body = response.into_body();
+    let data = hyper::body::to_bytes(&mut body).await.unwrap();
+    let trailers = body.trailers().await.unwrap();
+    let status = trailers.as_ref().and_then(grpc_status).or(header_status);
+    (data.get(5..).unwrap_or_default().to_vec(), status)
+}
+
+async fn start_balancebeam(config: &str, args: &[&str]) -> BalanceBeam {
+    let config_path = write_config(config);
+    let mut all_args = vec!["--config", config_path.as_str()];
+    all_args.extend_from_slice(args);
+    BalanceBeam::new_with_args(&[], &all_args).await
+}
+
+/// Route calls to different pools based on the gRPC service they are for.
+#[tokio::test]
+async fn test_grpc_routing_by_service() {
+    init_logging();
+    let greeter = GrpcServer::new().await;
+    let store = GrpcServer::new().await;
+    let config = format!(
+        r#"
+pools:
+  greeter: {{ upstreams: ["{}"], protocol: http2 }}
+  store: {{ upstreams: ["{}"], protocol: http2 }}
+routes:
+  - {{ grpc_service: test.Store, grpc_method: Get, pool: store }}
+  - {{ grpc_service: test.Greeter, pool: greeter }}
+"#,
+        greeter.address, store.address
+    );
+    let balancebeam = start_balancebeam(&config, &[]).await;
+    let client = grpc_client();
+
+    for _ in 0..3 {
+        let (reply, status) = grpc_call(&client, &balancebeam, "/test.Greeter/Hello", b"hi").await;
+        assert_eq!(reply, b"hi");
+        assert_eq!(status, Some(0));
+    }
+    let (reply, status) = grpc_call(&client, &balancebeam, "/test.Store/Get", b"item").await;
+    assert_eq!(reply, b"item");
+    assert_eq!(status, Some(0));
+
+    log::info!("Calling a method that no route matches");
+    let response = client
+        .request(
+            hyper::Request::post(format!("http://{}/test.Store/Put", balancebeam.address))
+                .header("content-type", "application/grpc")
+                .body(hyper::Body::from(encode_message(b"")))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    assert_eq!(Box::new(greeter).stop().await, 3);
+    assert_eq!(Box::new(store).stop().await, 1);
+}
+
+/// Calls made over a single client connection should still be spread across every upstream.
+#[tokio::test]
+async fn test_grpc_per_call_balancing() {
+    init_logging();
+    let n_calls = 30;
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(GrpcServer::new().await);
+    }
+    let addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| format!("\"{}\"", upstream.address))
+        .collect();
+    let config = format!(
+        "pools:\n  grpc: {{ upstreams: [{}], protocol: http2 }}\nroutes:\n  - {{ grpc_service: test.Greeter, pool: grpc }}\n",
+        addresses.join(", ")
+    );
+    let balancebeam = start_balancebeam(&config, &[]).await;
+
+    let client = grpc_client();
+    for _ in 0..n_calls {
+        let (_, status) = grpc_call(&client, &balancebeam, "/test.Greeter/Hello", b"hi").await;
+        assert_eq!(status, Some(0));
+    }
+
+    let mut total = 0;
+    for upstream in upstreams {
+        let count = Box::new(upstream).stop().await;
+        log::info!("Upstream received {} calls", count);
+        assert!(
+            count > 0,
+            "An upstream received no calls; balancing is per connection"
+        );
+        total += count;
+    }
+    assert_eq!(total, n_calls);
+}
+
+/// An upstream answering with grpc-status UNAVAILABLE should be taken out of rotation.
+#[tokio::test]
+async fn test_grpc_status_passive_health_check() {
+    init_logging();
+    let healthy = GrpcServer::new().await;
+    let broken = GrpcServer::new().await;
+    broken.set_unavailable(true);
+    let config = format!(
+        "pools:\n  grpc: {{ upstreams: [\"{}\", \"{}\"], protocol: http2 }}\nroutes:\n  - {{ grpc_service: test.Greeter, pool: grpc }}\n",
+        healthy.address, broken.address
+    );
+    let balancebeam = start_balancebeam(&config, &[]).await;
+
+    let client = grpc_client();
+    let mut failures = 0;
+    for _ in 0..20 {
+        let (_, status) = grpc_call(&client, &balancebeam, "/test.Greeter/Hello", b"hi").await;
+        if status != Some(0) {
+            failures += 1;
+        }
+    }
+    assert!(
+        failures <= 1,
+        "{} calls failed; UNAVAILABLE upstream kept getting traffic",
+        failures
+    );
+    assert!(Box::new(broken).stop().await <= 1);
+    assert!(Box::new(healthy).stop().await >= 19);
+}
+
+/// Active health checks using grpc.health.v1.Health should stop traffic to NOT_SERVING upstreams.
+#[tokio::test]
+async fn test_grpc_active_health_check() {
+    init_logging();
+    let healthy = GrpcServer::new().await;
+    let draining = GrpcServer::new().await;
+    let config = format!(
+        "pools:\n  grpc:\n    upstreams: [\"{}\", \"{}\"]\n    protocol: http2\n    health_check: {{ type: grpc, service: test.Greeter }}\nroutes:\n  - {{ grpc_service: test.Greeter, pool: grpc }}\n",
+        healthy.address, draining.address
+    );
+    let balancebeam = start_balancebeam(&config, &["--active-health-check-interval", "1"]).await;
+
+    log::info!("Marking one upstream NOT_SERVING and waiting for health checks to notice");
+    draining.set_serving(false);
+    sleep(Duration::from_secs(3)).await;
+
+    let client = grpc_client();
+    for _ in 0..10 {
+        let (_, status) = grpc_call(&client, &balancebeam, "/test.Greeter/Hello", b"hi").await;
+        assert_eq!(status, Some(0));
+    }
+    assert_eq!(Box::new(draining).stop().await, 0);
+    assert_eq!(Box::new(healthy).stop().await, 10);
+}
@@ -0,0 +1,255 @@
+mod common;
+
+use common::{free_address, init_logging, temp_path, BalanceBeam, HeaderServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn start_balancebeam(upstream: &HeaderServer, args: &[&str]) -> BalanceBeam {
+    let mut all_args = vec!["--cache-size", "1000000"];
+    all_args.extend_from_slice(args);
+    BalanceBeam::new_with_args(&[&upstream.address], &all_args).await
+}
+
+/// Sends a GET through balancebeam, returning the x-cache header (if any) and the body.
+async fn fetch(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (Option<String>, String) {
+    let client = reqwest::Client::new();
+    let mut request = client.get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let cache_status = response
+        .headers()
+        .get("x-cache")
+        .map(|value| value.to_str().unwrap().to_string());
+    (cache_status, response.text().await.unwrap())
+}
+
+/// A response with max-age should be served from the cache until it expires.
+#[tokio::test]
+async fn test_cache_hit() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream, &[]).await;
+
+    let path = "/page?header=Cache-Control:max-age%3D60";
+    let (cache_status, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status, None);
+    assert_eq!(body, "response 1");
+    for _ in 0..3 {
+        let (cache_status, body) = fetch(&balancebeam, path, &[]).await;
+        assert_eq!(cache_status.as_deref(), Some("HIT"));
+        assert_eq!(body, "response 1");
+    }
+
+    log::info!("Checking that a client asking for a fresh copy bypasses the cache");
+    let (_, body) = fetch(&balancebeam, path, &[("Cache-Control", "no-cache")]).await;
+    assert_eq!(body, "response 2");
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Responses that forbid caching, or that don't say how long they stay fresh, must not be cached.
+#[tokio::test]
+async fn test_cache_uncacheable_responses() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream, &[]).await;
+
+    for path in [
+        "/a?header=Cache-Control:no-store",
+        "/b?header=Cache-Control:private,max-age%3D60",
+        "/c",
+    ] {
+        fetch(&balancebeam, path, &[]).await;
+        let (cache_status, _) = fetch(&balancebeam, path, &[]).await;
+        assert_eq!(cache_status, None, "{} was served from the cache", path);
+    }
+
+    log::info!("Checking that requests with credentials are not answered from the cache");
+    let path = "/d?header=Cache-Control:max-age%3D60";
+    fetch(&balancebeam, path, &[]).await;
+    let (cache_status, _) = fetch(
+        &balancebeam,
+        path,
+        &[("Authorization", "Basic Zm9vOmJhcg==")],
+    )
+    .await;
+    assert_eq!(cache_status, None);
+
+    assert_eq!(Box::new(upstream).stop().await, 8);
+}
+
+/// Each combination of the headers named by Vary should be cached separately.
+#[tokio::test]
+async fn test_cache_vary() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream, &[]).await;
+
+    let path = "/greeting?header=Cache-Control:max-age%3D60&header=Vary:Accept-Language";
+    let (_, english) = fetch(&balancebeam, path, &[("Accept-Language", "en")]).await;
+    let (_, french) = fetch(&balancebeam, path, &[("Accept-Language", "fr")]).await;
+    assert_ne!(english, french);
+
+    let (cache_status, body) = fetch(&balancebeam, path, &[("Accept-Language", "en")]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    assert_eq!(body, english);
+    let (cache_status, body) = fetch(&balancebeam, path, &[("Accept-Language", "fr")]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    assert_eq!(body, french);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// A stored response that must be revalidated should be reused when the upstream answers 304.
+#[tokio::test]
+async fn test_cache_etag_revalidation() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream, &[]).await;
+
+    let path = "/doc?header=Cache-Control:no-cache&header=ETag:%22v1%22";
+    let (_, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(body, "response 1");
+    let (cache_status, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("REVALIDATED"));
+    assert_eq!(body, "response 1");
+
+    log::info!("Checking that a client's own conditional request gets a 304");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("If-None-Match", "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// With stale-while-revalidate, a stale response is served immediately while it is refreshed in
+/// the background.
+#[tokio::test]
+async fn test_cache_stale_while_revalidate() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream, &[]).await;
+
+    let path = "/news?header=Cache-Control:max-age%3D2,stale-while-revalidate%3D60";
+    let (_, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(body, "response 1");
+    sleep(Duration::from_secs(3)).await;
+
+    let (cache_status, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("STALE"));
+    assert_eq!(body, "response 1");
+    sleep(Duration::from_millis(500)).await;
+
+    let (cache_status, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    assert_eq!(body, "response 2");
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Bodies should be kept in --cache-dir, whose other contents aren't the cache's to delete.
+#[tokio::test]
+async fn test_cache_dir() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let dir = std::path::PathBuf::from(temp_path("cache"));
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("notes.txt"), "the operator's").unwrap();
+    std::fs::write(dir.join("entry-999"), "left over from a previous run").unwrap();
+    let balancebeam = start_balancebeam(&upstream, &["--cache-dir", dir.to_str().unwrap()]).await;
+
+    let path = "/page?header=Cache-Control:max-age%3D60";
+    fetch(&balancebeam, path, &[]).await;
+    let (cache_status, body) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    assert_eq!(body, "response 1");
+
+    assert_eq!(
+        std::fs::read_to_string(dir.join("notes.txt")).unwrap(),
+        "the operator's"
+    );
+    assert!(!dir.join("entry-999").exists());
+    let entries = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter(|file| {
+            let name = file.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with("entry-")
+        })
+        .count();
+    assert_eq!(entries, 1);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    drop(balancebeam);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Once the cache is full, the least recently used responses should be evicted first.
+#[tokio::test]
+async fn test_cache_lru_eviction() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--cache-size", "3000"]).await;
+
+    let path = |name: &str| format!("/{}?header=Cache-Control:max-age%3D60&size=1000", name);
+    fetch(&balancebeam, &path("a"), &[]).await;
+    fetch(&balancebeam, &path("b"), &[]).await;
+    // Use a again so that b is the least recently used entry
+    let (cache_status, _) = fetch(&balancebeam, &path("a"), &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    fetch(&balancebeam, &path("c"), &[]).await;
+
+    let (cache_status, _) = fetch(&balancebeam, &path("a"), &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    let (cache_status, _) = fetch(&balancebeam, &path("c"), &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    let (cache_status, _) = fetch(&balancebeam, &path("b"), &[]).await;
+    assert_eq!(cache_status, None, "b should have been evicted");
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+}
+
+/// Unsafe requests and the admin purge endpoint should both drop cached responses.
+#[tokio::test]
+async fn test_cache_invalidation_and_purge() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(&upstream, &["--admin-bind", &admin_address]).await;
+
+    let path = "/item?header=Cache-Control:max-age%3D60";
+    fetch(&balancebeam, path, &[]).await;
+    balancebeam.post(path, "update").await.unwrap();
+    let (cache_status, _) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(
+        cache_status, None,
+        "POST did not invalidate the cached response"
+    );
+
+    let (cache_status, _) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status.as_deref(), Some("HIT"));
+    let purge = reqwest::Client::new()
+        .post(format!("http://{}/cache/purge?prefix=/it", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin interface");
+    assert_eq!(purge.status(), 200);
+    assert_eq!(purge.text().await.unwrap(), "{\"purged\":1}");
+    let (cache_status, _) = fetch(&balancebeam, path, &[]).await;
+    assert_eq!(cache_status, None, "purge did not drop the cached response");
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+}
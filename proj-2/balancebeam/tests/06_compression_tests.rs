@@ -0,0 +1,243 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, HeaderServer, Server};
+use std::io::{Read, Write};
+
+const TEXT_PATH: &str = "/page?header=Content-Type:text/plain%3B%20charset%3Dutf-8&size=5000";
+
+async fn start_balancebeam(upstream: &str, args: &[&str]) -> BalanceBeam {
+    let mut all_args = vec![
+        "--compression",
+        "br",
+        "--compression",
+        "zstd",
+        "--compression",
+        "gzip",
+    ];
+    all_args.extend_from_slice(args);
+    BalanceBeam::new_with_args(&[upstream], &all_args).await
+}
+
+/// Sends a GET through balancebeam, returning the response headers and raw (undecoded) body.
+async fn fetch(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    accept_encoding: Option<&str>,
+) -> (reqwest::header::HeaderMap, Vec<u8>) {
+    let client = reqwest::Client::new();
+    let mut request = client.get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("Accept-Encoding", accept_encoding);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let headers = response.headers().clone();
+    (headers, response.bytes().await.unwrap().to_vec())
+}
+
+fn header<'a>(headers: &'a reqwest::header::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).map(|value| value.to_str().unwrap())
+}
+
+fn decompress(encoding: &str, body: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    match encoding {
+        "gzip" => flate2::read::GzDecoder::new(body)
+            .read_to_end(&mut decompressed)
+            .unwrap(),
+        "br" => brotli::Decompressor::new(body, 4096)
+            .read_to_end(&mut decompressed)
+            .unwrap(),
+        "zstd" => zstd::Decoder::new(body)
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap(),
+        _ => panic!("unexpected encoding {}", encoding),
+    };
+    decompressed
+}
+
+/// Each supported coding should round-trip, with Content-Length and Vary updated to match.
+#[tokio::test]
+async fn test_compression_codings() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream.address, &[]).await;
+
+    for encoding in ["gzip", "br", "zstd"] {
+        let (headers, body) = fetch(&balancebeam, TEXT_PATH, Some(encoding)).await;
+        assert_eq!(header(&headers, "content-encoding"), Some(encoding));
+        assert_eq!(header(&headers, "vary"), Some("Accept-Encoding"));
+        assert_eq!(
+            header(&headers, "content-length"),
+            Some(body.len().to_string().as_str())
+        );
+        assert!(body.len() < 5000);
+        assert_eq!(decompress(encoding, &body), vec![b'x'; 5000]);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// The client's q-values should win, with our configured order breaking ties.
+#[tokio::test]
+async fn test_compression_negotiation() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream.address, &[]).await;
+
+    let cases = [
+        ("gzip, deflate, br, zstd", Some("br")),
+        ("gzip;q=1.0, br;q=0.5", Some("gzip")),
+        ("zstd, *;q=0.1", Some("zstd")),
+        ("*", Some("br")),
+        ("br;q=0, *", Some("zstd")),
+        ("deflate", None),
+        ("gzip;q=0", None),
+    ];
+    for (accept_encoding, expected) in cases {
+        let (headers, _) = fetch(&balancebeam, TEXT_PATH, Some(accept_encoding)).await;
+        assert_eq!(
+            header(&headers, "content-encoding"),
+            expected,
+            "wrong coding chosen for Accept-Encoding: {}",
+            accept_encoding
+        );
+    }
+
+    log::info!("Checking that clients that don't ask for compression get the plain body");
+    let (headers, body) = fetch(&balancebeam, TEXT_PATH, None).await;
+    assert_eq!(header(&headers, "content-encoding"), None);
+    assert_eq!(header(&headers, "vary"), Some("Accept-Encoding"));
+    assert_eq!(body, vec![b'x'; 5000]);
+
+    assert_eq!(Box::new(upstream).stop().await, cases.len() + 1);
+}
+
+/// Small bodies, content types not on the list and already-encoded or no-transform responses
+/// should be left alone.
+#[tokio::test]
+async fn test_compression_eligibility() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(
+        &upstream.address,
+        &[
+            "--compression-mime-type",
+            "text/*",
+            "--compression-min-size",
+            "2000",
+        ],
+    )
+    .await;
+
+    for path in [
+        "/small?header=Content-Type:text/plain&size=1000",
+        "/json?header=Content-Type:application/json&size=5000",
+        "/encoded?header=Content-Type:text/plain&header=Content-Encoding:identity&size=5000",
+        "/no-transform?header=Content-Type:text/plain&header=Cache-Control:no-transform&size=5000",
+        "/untyped?size=5000",
+    ] {
+        let (headers, _) = fetch(&balancebeam, path, Some("gzip")).await;
+        assert_eq!(
+            header(&headers, "content-encoding").filter(|coding| *coding != "identity"),
+            None,
+            "{} should not have been compressed",
+            path
+        );
+    }
+
+    log::info!("Checking that a strong ETag is weakened on compressed responses");
+    let (headers, _) = fetch(
+        &balancebeam,
+        "/tagged?header=Content-Type:text/html&header=ETag:%22v1%22&size=5000",
+        Some("gzip"),
+    )
+    .await;
+    assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+    assert_eq!(header(&headers, "etag"), Some("W/\"v1\""));
+
+    Box::new(upstream).stop().await;
+}
+
+/// A HEAD response should carry the same representation headers as the GET it stands in for.
+#[tokio::test]
+async fn test_head_negotiates_like_get() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = start_balancebeam(&upstream.address, &[]).await;
+    let path = "/tagged?header=Content-Type:text/html&header=ETag:%22v1%22&size=5000";
+
+    let (get_headers, _) = fetch(&balancebeam, path, Some("gzip")).await;
+    let response = reqwest::Client::new()
+        .head(format!("http://{}{}", balancebeam.address, path))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let head_headers = response.headers().clone();
+    for name in ["content-encoding", "etag", "vary"] {
+        assert_eq!(
+            header(&head_headers, name),
+            header(&get_headers, name),
+            "{} differs between HEAD and GET",
+            name
+        );
+    }
+    assert_eq!(header(&head_headers, "content-encoding"), Some("gzip"));
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    log::info!("Checking that a small HEAD response is left alone, like its GET");
+    let response = reqwest::Client::new()
+        .head(format!(
+            "http://{}/small?header=Content-Type:text/plain&size=1000",
+            balancebeam.address
+        ))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(header(response.headers(), "content-encoding"), None);
+    assert_eq!(header(response.headers(), "content-length"), Some("1000"));
+
+    Box::new(upstream).stop().await;
+}
+
+/// With --decompress-requests, upstreams should receive plain request bodies.
+#[tokio::test]
+async fn test_request_decompression() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--decompress-requests"]).await;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"hello upstream").unwrap();
+    let compressed = encoder.finish().unwrap();
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/upload", balancebeam.address))
+        .header("Content-Encoding", "gzip")
+        .body(compressed)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let echoed = response.text().await.unwrap();
+    assert!(echoed.ends_with("\n\nhello upstream"), "{}", echoed);
+    assert!(!echoed.contains("content-encoding"));
+    assert!(echoed.contains("content-length: 14"));
+
+    log::info!("Sending a body that isn't valid gzip");
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/upload", balancebeam.address))
+        .header("Content-Encoding", "gzip")
+        .body("not gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use common::{init_logging, temp_path, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+fn read_lines(path: &str) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+async fn get(balancebeam: &BalanceBeam, path: &str, request_id: &str) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("x-request-id", request_id)
+        .header("user-agent", "access-log-test")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+/// JSON access log lines should describe each request, including rate limiting outcomes.
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_path = temp_path("log");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            &log_path,
+            "--access-log-format",
+            "json",
+            "--max-requests-per-minute",
+            "2",
+        ],
+    )
+    .await;
+
+    assert_eq!(get(&balancebeam, "/first?x=1", "req-1").await, 200);
+    assert_eq!(get(&balancebeam, "/second", "req-2").await, 200);
+    assert_eq!(get(&balancebeam, "/third", "req-3").await, 429);
+
+    let lines = read_lines(&log_path);
+    assert_eq!(lines.len(), 3, "expected one line per request: {:?}", lines);
+    let entries: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("access log line is not JSON"))
+        .collect();
+
+    let first = &entries[0];
+    assert_eq!(first["client_ip"], "127.0.0.1");
+    assert_eq!(first["method"], "GET");
+    assert_eq!(first["path"], "/first?x=1");
+    assert_eq!(first["protocol"], "HTTP/1.1");
+    assert_eq!(first["status"], 200);
+    assert!(first["bytes"].as_u64().unwrap() > 0);
+    assert_eq!(first["upstream"], upstream.address.as_str());
+    assert!(first["upstream_latency_ms"].as_f64().unwrap() >= 0.0);
+    assert!(
+        first["latency_ms"].as_f64().unwrap() >= first["upstream_latency_ms"].as_f64().unwrap()
+    );
+    assert_eq!(first["rate_limit"], "allowed");
+    assert_eq!(first["request_id"], "req-1");
+    assert_eq!(first["user_agent"], "access-log-test");
+    assert!(first["time"].as_str().unwrap().ends_with('Z'));
+
+    let limited = &entries[2];
+    assert_eq!(limited["status"], 429);
+    assert_eq!(limited["rate_limit"], "limited");
+    assert!(limited["upstream"].is_null());
+    assert_eq!(limited["request_id"], "req-3");
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Combined Log Format lines should be parseable by standard tools, with our fields at the end.
+#[tokio::test]
+async fn test_combined_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_path = temp_path("log");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--access-log", &log_path]).await;
+
+    assert_eq!(get(&balancebeam, "/page", "abc").await, 200);
+
+    let lines = read_lines(&log_path);
+    assert_eq!(lines.len(), 1);
+    let line = &lines[0];
+    log::info!("Access log line: {}", line);
+    assert!(line.starts_with("127.0.0.1 - - ["), "{}", line);
+    assert!(
+        line.contains(" +0000] \"GET /page HTTP/1.1\" 200 "),
+        "{}",
+        line
+    );
+    assert!(line.contains(" \"-\" \"access-log-test\" "), "{}", line);
+    assert!(
+        line.contains(&format!(" upstream={} ", upstream.address)),
+        "{}",
+        line
+    );
+    assert!(line.contains(" rate_limit=unlimited "), "{}", line);
+    assert!(line.ends_with(" request_id=abc"), "{}", line);
+
+    Box::new(upstream).stop().await;
+}
+
+/// After logrotate moves the log away, SIGUSR1 should make balancebeam start a new file.
+#[tokio::test]
+async fn test_access_log_reopen_on_sigusr1() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_path = temp_path("log");
+    let rotated_path = format!("{}.1", log_path);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--access-log", &log_path]).await;
+
+    assert_eq!(get(&balancebeam, "/before", "1").await, 200);
+    std::fs::rename(&log_path, &rotated_path).unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get(&balancebeam, "/after", "2").await, 200);
+
+    let rotated = read_lines(&rotated_path);
+    assert_eq!(rotated.len(), 1);
+    assert!(rotated[0].contains("/before"));
+    let current = read_lines(&log_path);
+    assert_eq!(current.len(), 1, "log was not reopened after SIGUSR1");
+    assert!(current[0].contains("/after"));
+
+    Box::new(upstream).stop().await;
+}
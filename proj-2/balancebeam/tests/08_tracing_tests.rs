@@ -0,0 +1,174 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, CollectorServer, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const CLIENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+/// Finds a header's value in the request an EchoServer echoed back.
+fn echoed_header<'a>(echoed: &'a str, name: &str) -> Option<&'a str> {
+    echoed
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+}
+
+async fn get(
+    balancebeam: &BalanceBeam,
+    headers: &[(&str, &str)],
+) -> (reqwest::header::HeaderMap, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}/traced", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let headers = response.headers().clone();
+    (headers, response.text().await.unwrap())
+}
+
+/// Requests without an X-Request-Id should get one, and it should reach both the upstream and
+/// the client. A client-supplied one should be kept.
+#[tokio::test]
+async fn test_request_id() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (headers, echoed) = get(&balancebeam, &[]).await;
+    let upstream_id = echoed_header(&echoed, "x-request-id").expect("no request ID upstream");
+    assert_eq!(upstream_id.len(), 36, "not a UUID: {}", upstream_id);
+    assert_eq!(
+        headers.get("x-request-id").unwrap().to_str().unwrap(),
+        upstream_id
+    );
+
+    let (other_headers, _) = get(&balancebeam, &[]).await;
+    assert_ne!(
+        other_headers.get("x-request-id"),
+        headers.get("x-request-id")
+    );
+
+    let (headers, echoed) = get(&balancebeam, &[("x-request-id", "client-chosen")]).await;
+    assert_eq!(
+        echoed_header(&echoed, "x-request-id"),
+        Some("client-chosen")
+    );
+    assert_eq!(headers.get("x-request-id").unwrap(), "client-chosen");
+
+    Box::new(upstream).stop().await;
+}
+
+/// balancebeam should join the client's trace, hand its own span to the upstream as the parent,
+/// and export that span to the collector.
+#[tokio::test]
+async fn test_trace_propagation_and_export() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let collector = CollectorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--otlp-endpoint", &collector.address],
+    )
+    .await;
+
+    let traceparent = format!("00-{}-{}-01", TRACE_ID, CLIENT_SPAN_ID);
+    let (_, echoed) = get(
+        &balancebeam,
+        &[
+            ("traceparent", &traceparent),
+            ("tracestate", "vendor=opaque"),
+            ("x-request-id", "traced-request"),
+        ],
+    )
+    .await;
+    let forwarded = echoed_header(&echoed, "traceparent").expect("no traceparent upstream");
+    let fields: Vec<&str> = forwarded.split('-').collect();
+    assert_eq!(fields.len(), 4);
+    assert_eq!(fields[0], "00");
+    assert_eq!(fields[1], TRACE_ID, "trace ID was not preserved");
+    assert_ne!(
+        fields[2], CLIENT_SPAN_ID,
+        "balancebeam did not add its own span"
+    );
+    assert_eq!(fields[3], "01");
+    assert_eq!(echoed_header(&echoed, "tracestate"), Some("vendor=opaque"));
+
+    log::info!("Waiting for the span to be exported");
+    sleep(Duration::from_secs(2)).await;
+    let spans = collector.spans();
+    assert_eq!(spans.len(), 1, "expected exactly one span: {:?}", spans);
+    let span = &spans[0];
+    assert_eq!(span["traceId"], TRACE_ID);
+    assert_eq!(span["spanId"], fields[2]);
+    assert_eq!(span["parentSpanId"], CLIENT_SPAN_ID);
+    assert_eq!(span["name"], "GET");
+    assert_eq!(span["kind"], 2);
+    let attribute = |key: &str| {
+        span["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute["key"] == key)
+            .map(|attribute| attribute["value"].clone())
+    };
+    assert_eq!(attribute("url.path").unwrap()["stringValue"], "/traced");
+    assert_eq!(
+        attribute("http.response.status_code").unwrap()["intValue"],
+        "200"
+    );
+    assert_eq!(
+        attribute("server.address").unwrap()["stringValue"],
+        upstream.address.as_str()
+    );
+    assert_eq!(
+        attribute("balancebeam.request_id").unwrap()["stringValue"],
+        "traced-request"
+    );
+
+    Box::new(collector).stop().await;
+    Box::new(upstream).stop().await;
+}
+
+/// Without a valid traceparent, balancebeam should start a new trace; traces the client chose
+/// not to sample should be propagated but not exported.
+#[tokio::test]
+async fn test_new_and_unsampled_traces() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let collector = CollectorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--otlp-endpoint", &collector.address],
+    )
+    .await;
+
+    let (_, echoed) = get(
+        &balancebeam,
+        &[("traceparent", "garbage"), ("tracestate", "orphaned=1")],
+    )
+    .await;
+    let forwarded = echoed_header(&echoed, "traceparent").expect("no traceparent upstream");
+    let new_trace_id = forwarded.split('-').nth(1).unwrap().to_string();
+    assert!(forwarded.starts_with("00-"));
+    assert!(forwarded.ends_with("-01"));
+    assert_eq!(echoed_header(&echoed, "tracestate"), None);
+
+    let unsampled = format!("00-{}-{}-00", TRACE_ID, CLIENT_SPAN_ID);
+    let (_, echoed) = get(&balancebeam, &[("traceparent", &unsampled)]).await;
+    let forwarded = echoed_header(&echoed, "traceparent").unwrap();
+    assert!(forwarded.contains(TRACE_ID));
+    assert!(forwarded.ends_with("-00"));
+
+    sleep(Duration::from_secs(2)).await;
+    let spans = collector.spans();
+    assert_eq!(spans.len(), 1, "unsampled trace was exported: {:?}", spans);
+    assert_eq!(spans[0]["traceId"], new_trace_id.as_str());
+    assert!(spans[0].get("parentSpanId").is_none());
+
+    Box::new(collector).stop().await;
+    Box::new(upstream).stop().await;
+}
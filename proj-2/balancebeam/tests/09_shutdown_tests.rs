@@ -0,0 +1,146 @@
+mod common;
+
+use common::{init_logging, temp_path, BalanceBeam, HeaderServer, Server};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts a slow request through balancebeam in the background.
+fn spawn_slow_request(
+    balancebeam: &BalanceBeam,
+    delay_ms: u64,
+) -> tokio::task::JoinHandle<Result<String, reqwest::Error>> {
+    let url = format!("http://{}/slow?delay={}", balancebeam.address, delay_ms);
+    tokio::spawn(async move { reqwest::get(url).await?.error_for_status()?.text().await })
+}
+
+fn read_pid(path: &str) -> i32 {
+    std::fs::read_to_string(path)
+        .expect("Could not read pid file")
+        .trim()
+        .parse()
+        .expect("pid file does not contain a pid")
+}
+
+/// On SIGTERM, balancebeam should stop accepting connections but finish the requests it is
+/// already handling before exiting.
+#[tokio::test]
+async fn test_sigterm_finishes_in_flight_requests() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let slow_request = spawn_slow_request(&balancebeam, 1500);
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.send_signal(Signal::SIGTERM);
+    sleep(Duration::from_millis(300)).await;
+
+    log::info!("Checking that new connections are refused");
+    assert!(
+        tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .is_err(),
+        "balancebeam still accepted a connection after SIGTERM"
+    );
+
+    let body = slow_request
+        .await
+        .unwrap()
+        .expect("In-flight request was cut off by shutdown");
+    assert_eq!(body, "response 1");
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(3))
+        .await
+        .expect("balancebeam did not exit after its last request finished");
+    assert!(status.success());
+
+    Box::new(upstream).stop().await;
+}
+
+/// Idle keep-alive connections shouldn't hold up shutdown.
+#[tokio::test]
+async fn test_sigterm_closes_idle_connections() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    // The client keeps its connection open in its pool after this request
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    balancebeam.send_signal(Signal::SIGTERM);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(2))
+        .await
+        .expect("balancebeam waited on an idle connection");
+    assert!(status.success());
+    drop(client);
+
+    Box::new(upstream).stop().await;
+}
+
+/// Requests still running when --shutdown-timeout passes are cut off.
+#[tokio::test]
+async fn test_shutdown_deadline() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--shutdown-timeout", "1"]).await;
+
+    let slow_request = spawn_slow_request(&balancebeam, 5000);
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.send_signal(Signal::SIGTERM);
+    balancebeam
+        .wait_for_exit(Duration::from_secs(3))
+        .await
+        .expect("balancebeam did not exit at the shutdown deadline");
+    assert!(slow_request.await.unwrap().is_err());
+
+    Box::new(upstream).stop().await;
+}
+
+/// On SIGUSR2, a new balancebeam process should take over the listening socket, with no requests
+/// failing during the handover.
+#[tokio::test]
+async fn test_sigusr2_handoff() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let pid_file = temp_path("pid");
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--pid-file", &pid_file]).await;
+    let old_pid = read_pid(&pid_file);
+
+    let address = balancebeam.address.clone();
+    let requests = tokio::spawn(async move {
+        for _ in 0..30 {
+            // A fresh client each time, so every request needs a new connection
+            let response = reqwest::get(format!("http://{}/", address)).await;
+            assert_eq!(
+                response.expect("request failed during handoff").status(),
+                200
+            );
+            sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    sleep(Duration::from_millis(500)).await;
+    balancebeam.send_signal(Signal::SIGUSR2);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("old balancebeam did not exit after handing over");
+    assert!(status.success());
+    requests.await.unwrap();
+
+    let new_pid = read_pid(&pid_file);
+    assert_ne!(new_pid, old_pid, "successor did not write its pid");
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(new_pid), Signal::SIGTERM)
+        .expect("successor is not running");
+
+    assert_eq!(Box::new(upstream).stop().await, 30);
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, HeaderServer, Server};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a GET through balancebeam from `local_address` on a fresh connection, returning the
+/// status code.
+async fn get_from(balancebeam: &BalanceBeam, path: &str, local_address: &str) -> u16 {
+    let client = reqwest::Client::builder()
+        .local_address(local_address.parse::<IpAddr>().unwrap())
+        .build()
+        .unwrap();
+    client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Starts a request that the upstream takes `delay_ms` to answer, in the background.
+fn spawn_slow_request(balancebeam: &BalanceBeam, delay_ms: u64) -> tokio::task::JoinHandle<u16> {
+    let url = format!("http://{}/slow?delay={}", balancebeam.address, delay_ms);
+    tokio::spawn(async move {
+        reqwest::get(url)
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    })
+}
+
+/// Connections beyond --max-connections should be turned away with 503 until others close.
+#[tokio::test]
+async fn test_max_connections() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections", "2"]).await;
+
+    let slow_requests = [
+        spawn_slow_request(&balancebeam, 1000),
+        spawn_slow_request(&balancebeam, 1000),
+    ];
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(get_from(&balancebeam, "/", "127.0.0.1").await, 503);
+    // The limit is global, so a different client doesn't get in either
+    assert_eq!(get_from(&balancebeam, "/", "127.0.0.2").await, 503);
+
+    for slow_request in slow_requests {
+        assert_eq!(slow_request.await.unwrap(), 200);
+    }
+    assert_eq!(get_from(&balancebeam, "/", "127.0.0.1").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// --max-connections-per-ip should only turn away the client that is over its limit.
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections-per-ip", "1"]).await;
+
+    let slow_request = spawn_slow_request(&balancebeam, 1000);
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(get_from(&balancebeam, "/", "127.0.0.1").await, 503);
+    assert_eq!(get_from(&balancebeam, "/", "127.0.0.2").await, 200);
+    assert_eq!(slow_request.await.unwrap(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Requests beyond --max-upstream-requests should be shed instead of piling onto the upstream.
+#[tokio::test]
+async fn test_max_upstream_requests() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-upstream-requests", "1"]).await;
+
+    let slow_request = spawn_slow_request(&balancebeam, 1000);
+    sleep(Duration::from_millis(300)).await;
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 503);
+    assert!(response.headers().contains_key("retry-after"));
+    assert_eq!(slow_request.await.unwrap(), 200);
+
+    // Once the slow request is done, there's room again
+    assert_eq!(get_from(&balancebeam, "/", "127.0.0.1").await, 200);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// With --adaptive-concurrency, an upstream that slows down should get a lower limit.
+#[tokio::test]
+async fn test_adaptive_concurrency() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--adaptive-concurrency", "--admin-bind", &admin_address],
+    )
+    .await;
+    let limit = || async {
+        let report = reqwest::get(format!("http://{}/concurrency", admin_address))
+            .await
+            .expect("Error sending request to the admin interface")
+            .text()
+            .await
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        report["default"][&upstream.address]["limit"]
+            .as_u64()
+            .expect("no limit reported for the upstream")
+    };
+
+    for _ in 0..20 {
+        assert_eq!(get_from(&balancebeam, "/", "127.0.0.1").await, 200);
+    }
+    let initial_limit = limit().await;
+    log::info!("Limit after fast requests: {}", initial_limit);
+
+    for _ in 0..10 {
+        assert_eq!(
+            get_from(&balancebeam, "/?delay=200", "127.0.0.1").await,
+            200
+        );
+    }
+    let slowed_limit = limit().await;
+    log::info!("Limit after slow requests: {}", slowed_limit);
+    assert!(
+        slowed_limit < initial_limit,
+        "limit did not shrink as the upstream slowed down ({} -> {})",
+        initial_limit,
+        slowed_limit
+    );
+
+    Box::new(upstream).stop().await;
+}
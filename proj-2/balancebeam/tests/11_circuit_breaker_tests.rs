@@ -0,0 +1,116 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, HeaderServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a GET through balancebeam on a fresh connection, returning the status code.
+async fn get(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// An upstream that keeps failing should stop receiving requests once its circuit opens.
+#[tokio::test]
+async fn test_circuit_opens_on_errors() {
+    init_logging();
+    let working = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working.address, &failing.address],
+        &["--circuit-breaker", "--circuit-breaker-min-requests", "5"],
+    )
+    .await;
+
+    let mut errors = 0;
+    for _ in 0..40 {
+        if get(&balancebeam, "/").await != 200 {
+            errors += 1;
+        }
+    }
+    assert_eq!(errors, 5, "failing upstream kept getting requests");
+    assert_eq!(Box::new(failing).stop().await, 5);
+    assert_eq!(Box::new(working).stop().await, 35);
+}
+
+/// Slow requests should open the circuit too. Once the open time passes, probes decide whether
+/// the circuit closes again or stays open.
+#[tokio::test]
+async fn test_half_open_probes() {
+    init_logging();
+    let upstream = HeaderServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--circuit-breaker",
+            "--circuit-breaker-min-requests",
+            "3",
+            "--circuit-breaker-slow-call-ms",
+            "100",
+            "--circuit-breaker-open-time",
+            "1",
+            "--circuit-breaker-probes",
+            "1",
+        ],
+    )
+    .await;
+
+    for _ in 0..3 {
+        assert_eq!(get(&balancebeam, "/?delay=300").await, 200);
+    }
+    log::info!("The circuit should now be open");
+    assert_eq!(get(&balancebeam, "/").await, 502);
+
+    sleep(Duration::from_millis(1200)).await;
+    log::info!("Sending a probe that is still slow");
+    assert_eq!(get(&balancebeam, "/?delay=300").await, 200);
+    assert_eq!(get(&balancebeam, "/").await, 502);
+
+    sleep(Duration::from_millis(1200)).await;
+    log::info!("Sending a probe that is fast again");
+    assert_eq!(get(&balancebeam, "/").await, 200);
+    for _ in 0..3 {
+        assert_eq!(get(&balancebeam, "/").await, 200);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 8);
+}
+
+/// A failure rate that would open every circuit (0%), or none (over 100%), should be refused.
+#[tokio::test]
+async fn test_failure_rate_out_of_range() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    for rate in ["0", "101"] {
+        let mut balancebeam = BalanceBeam::new_with_args(
+            &[&upstream.address],
+            &["--circuit-breaker-failure-rate", rate],
+        )
+        .await;
+        let status = balancebeam
+            .wait_for_exit(Duration::from_secs(2))
+            .await
+            .unwrap_or_else(|| panic!("balancebeam accepted a failure rate of {}%", rate));
+        assert!(!status.success());
+    }
+
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--circuit-breaker-failure-rate",
+            "100",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    assert!(balancebeam
+        .wait_for_exit(Duration::from_millis(200))
+        .await
+        .is_none());
+    assert_eq!(get(&balancebeam, "/").await, 200);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
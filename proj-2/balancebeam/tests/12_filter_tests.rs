@@ -0,0 +1,245 @@
+mod common;
+
+use common::{init_logging, read_response, write_config, BalanceBeam, EchoServer, Server};
+use std::net::IpAddr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+async fn start_balancebeam(upstream: &EchoServer, routes: &str, args: &[&str]) -> BalanceBeam {
+    let config_path = write_config(&format!(
+        "pools:\n  backend: {{ upstreams: [\"{}\"] }}\nroutes:\n{}",
+        upstream.address, routes
+    ));
+    let mut all_args = vec!["--config", config_path.as_str()];
+    all_args.extend_from_slice(args);
+    BalanceBeam::new_with_args(&[], &all_args).await
+}
+
+/// Sends a request through balancebeam from `local_address`, returning the response.
+async fn send(
+    balancebeam: &BalanceBeam,
+    method: reqwest::Method,
+    path: &str,
+    local_address: &str,
+    headers: &[(&str, &str)],
+) -> reqwest::Response {
+    let client = reqwest::Client::builder()
+        .local_address(local_address.parse::<IpAddr>().unwrap())
+        .build()
+        .unwrap();
+    let mut request = client.request(method, format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+async fn get_status(balancebeam: &BalanceBeam, path: &str, local_address: &str) -> u16 {
+    send(balancebeam, reqwest::Method::GET, path, local_address, &[])
+        .await
+        .status()
+        .as_u16()
+}
+
+/// Sends `GET <target>` as is (clients like reqwest would normalize the target first), returning
+/// the response head and body.
+async fn get_raw(balancebeam: &BalanceBeam, target: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        target
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    (head, String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Routes should only serve clients their allow list covers, and never ones on their deny list.
+#[tokio::test]
+async fn test_allow_and_deny_lists() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let routes = r#"
+  - { path_prefix: /internal, pool: backend, allow: ["10.0.0.0/8", "127.0.0.2"] }
+  - { path_prefix: /public, pool: backend, deny: ["127.0.0.3/32", "::1"] }
+  - { path_prefix: /admin, pool: backend, allow: ["127.0.0.0/8"], deny: ["127.0.0.2"] }
+"#;
+    let balancebeam = start_balancebeam(&upstream, routes, &[]).await;
+
+    assert_eq!(
+        get_status(&balancebeam, "/internal", "127.0.0.1").await,
+        403
+    );
+    assert_eq!(
+        get_status(&balancebeam, "/internal", "127.0.0.2").await,
+        200
+    );
+    assert_eq!(get_status(&balancebeam, "/public", "127.0.0.1").await, 200);
+    assert_eq!(get_status(&balancebeam, "/public", "127.0.0.3").await, 403);
+    assert_eq!(get_status(&balancebeam, "/admin", "127.0.0.1").await, 200);
+    assert_eq!(get_status(&balancebeam, "/admin", "127.0.0.2").await, 403);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Other spellings of a restricted path should be matched (and forwarded) as the path they
+/// stand for, and a prefix should only cover whole segments.
+#[tokio::test]
+async fn test_path_normalized_before_routing() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let routes = r#"
+  - { path_prefix: /admin, pool: backend, allow: ["10.0.0.0/8"] }
+  - { path_prefix: /, pool: backend }
+"#;
+    let balancebeam = start_balancebeam(&upstream, routes, &[]).await;
+
+    for target in [
+        "/admin",
+        "/%61dmin",
+        "//admin",
+        "/admin//users",
+        "/x/../admin",
+        "/./admin/",
+        "/%2e%2e/admin",
+        "/x/%2E./admin?q=1",
+        "/../../admin",
+    ] {
+        let (head, _) = get_raw(&balancebeam, target).await;
+        assert!(head.starts_with("HTTP/1.1 403"), "{}: got {}", target, head);
+    }
+
+    // The upstream sees the path the route was chosen by
+    let (head, body) = get_raw(&balancebeam, "/x/..//%61pi/./v1?q=%61").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+    assert!(body.starts_with("GET /api/v1?q=%61 "), "Got {}", body);
+    // Encoded characters that mean something in a path stay encoded
+    let (_, body) = get_raw(&balancebeam, "/a%2fb/%3F").await;
+    assert!(body.starts_with("GET /a%2Fb/%3F "), "Got {}", body);
+
+    // /admin doesn't cover /administrator
+    let (head, body) = get_raw(&balancebeam, "/administrator").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+    assert!(body.starts_with("GET /administrator "), "Got {}", body);
+
+    let (head, _) = get_raw(&balancebeam, "/%6").await;
+    assert!(head.starts_with("HTTP/1.1 400"), "Got {}", head);
+    let (head, _) = get_raw(&balancebeam, "/%zzadmin").await;
+    assert!(head.starts_with("HTTP/1.1 400"), "Got {}", head);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Methods a route doesn't allow should get 405 with an Allow header.
+#[tokio::test]
+async fn test_method_allow_list() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let routes = "  - { path_prefix: /, pool: backend, methods: [get, HEAD] }\n";
+    let balancebeam = start_balancebeam(&upstream, routes, &[]).await;
+
+    let response = send(&balancebeam, reqwest::Method::GET, "/", "127.0.0.1", &[]).await;
+    assert_eq!(response.status(), 200);
+    let response = send(&balancebeam, reqwest::Method::POST, "/", "127.0.0.1", &[]).await;
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Requests matching a rule's path or header pattern should be rejected with its status.
+#[tokio::test]
+async fn test_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let routes = r#"
+  - path_prefix: /
+    pool: backend
+    rules:
+      - { path: "(?i)<script", status: 400 }
+      - { header: { name: User-Agent, pattern: "(?i)sqlmap" } }
+      - { path: "^/private/", header: { name: X-Debug, pattern: "." }, status: 404 }
+"#;
+    let balancebeam = start_balancebeam(&upstream, routes, &[]).await;
+
+    let status = |path: &'static str, headers: &'static [(&'static str, &'static str)]| {
+        let balancebeam = &balancebeam;
+        async move {
+            send(
+                balancebeam,
+                reqwest::Method::GET,
+                path,
+                "127.0.0.1",
+                headers,
+            )
+            .await
+            .status()
+            .as_u16()
+        }
+    };
+    assert_eq!(status("/search?q=<SCRIPT>alert(1)", &[]).await, 400);
+    assert_eq!(status("/", &[("user-agent", "sqlmap/1.7")]).await, 403);
+    // Both of a rule's patterns have to match
+    assert_eq!(status("/private/x", &[("x-debug", "1")]).await, 404);
+    assert_eq!(status("/private/x", &[]).await, 200);
+    assert_eq!(status("/public", &[("x-debug", "1")]).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Oversized request heads should be rejected with 431 or 414, depending on what is too big.
+#[tokio::test]
+async fn test_request_head_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-header-size",
+            "2000",
+            "--max-headers",
+            "8",
+            "--max-uri-length",
+            "100",
+        ],
+    )
+    .await;
+
+    let long_path = format!("/{}", "a".repeat(150));
+    assert_eq!(get_status(&balancebeam, &long_path, "127.0.0.1").await, 414);
+
+    let many_headers: Vec<(String, String)> = (0..10)
+        .map(|i| (format!("x-header-{}", i), "value".to_string()))
+        .collect();
+    let many_headers: Vec<(&str, &str)> = many_headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let response = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "/",
+        "127.0.0.1",
+        &many_headers,
+    )
+    .await;
+    assert_eq!(response.status(), 431);
+
+    let big_value = "x".repeat(3000);
+    let response = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "/",
+        "127.0.0.1",
+        &[("x-big", &big_value)],
+    )
+    .await;
+    assert_eq!(response.status(), 431);
+
+    // Requests within the limits still go through
+    assert_eq!(get_status(&balancebeam, "/fine", "127.0.0.1").await, 200);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{
+    free_address, free_port, init_logging, BalanceBeam, DnsRecord, DnsServer, EchoServer, Server,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a GET through balancebeam on a fresh connection, so that each one is balanced anew.
+async fn get(balancebeam: &BalanceBeam) {
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 200);
+}
+
+/// A hostname should expand to an upstream per address, and follow its records as they change.
+#[tokio::test]
+async fn test_hostname_expansion() {
+    init_logging();
+    let dns = DnsServer::new(1).await;
+    let port = free_port();
+    let first = EchoServer::new_at_address(format!("127.0.0.1:{}", port)).await;
+    let second = EchoServer::new_at_address(format!("127.0.0.2:{}", port)).await;
+    dns.set_records(
+        "backend.test",
+        vec![
+            DnsRecord::A("127.0.0.1".parse().unwrap()),
+            DnsRecord::A("127.0.0.2".parse().unwrap()),
+        ],
+    );
+    let upstream = format!("backend.test:{}", port);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--dns-server",
+            &dns.address,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    for _ in 0..20 {
+        get(&balancebeam).await;
+    }
+    log::info!("Dropping 127.0.0.1 from the records");
+    dns.set_records(
+        "backend.test",
+        vec![DnsRecord::A("127.0.0.2".parse().unwrap())],
+    );
+    sleep(Duration::from_millis(2500)).await;
+    for _ in 0..10 {
+        get(&balancebeam).await;
+    }
+
+    let first_count = Box::new(first).stop().await;
+    let second_count = Box::new(second).stop().await;
+    assert!(first_count > 0, "127.0.0.1 never got a request");
+    assert!(second_count > 10, "127.0.0.2 got {} requests", second_count);
+    assert_eq!(first_count + second_count, 30);
+    assert!(dns.queries_received() > 2, "records were never re-resolved");
+}
+
+/// SRV records should be balanced by weight within the lowest priority, falling back to higher
+/// priorities once no upstream with a lower one is alive.
+#[tokio::test]
+async fn test_srv_records() {
+    init_logging();
+    let dns = DnsServer::new(300).await;
+    let heavy = EchoServer::new().await;
+    let light = EchoServer::new().await;
+    let backup = EchoServer::new().await;
+    let port = |server: &EchoServer| server.address.rsplit_once(':').unwrap().1.parse().unwrap();
+    dns.set_records(
+        "_http._tcp.backend.test",
+        vec![
+            DnsRecord::Srv {
+                priority: 10,
+                weight: 3,
+                port: port(&heavy),
+                target: "heavy.test",
+            },
+            DnsRecord::Srv {
+                priority: 10,
+                weight: 1,
+                port: port(&light),
+                target: "light.test",
+            },
+            DnsRecord::Srv {
+                priority: 20,
+                weight: 1,
+                port: port(&backup),
+                target: "backup.test",
+            },
+        ],
+    );
+    for name in ["heavy.test", "light.test", "backup.test"] {
+        dns.set_records(name, vec![DnsRecord::A("127.0.0.1".parse().unwrap())]);
+    }
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &["srv+_http._tcp.backend.test"],
+        &[
+            "--dns-server",
+            &dns.address,
+            "--admin-bind",
+            &admin_address,
+            // Keep health check requests out of the counts
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let upstreams: serde_json::Value = serde_json::from_str(
+        &reqwest::get(format!("http://{}/upstreams", admin_address))
+            .await
+            .expect("Error sending request to the admin interface")
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    let upstreams = upstreams["default"].as_array().unwrap();
+    assert_eq!(upstreams.len(), 3);
+    assert!(upstreams
+        .iter()
+        .any(|upstream| upstream["address"] == heavy.address
+            && upstream["priority"] == 10
+            && upstream["weight"] == 3
+            && upstream["alive"] == true));
+
+    for _ in 0..80 {
+        get(&balancebeam).await;
+    }
+    log::info!("Taking down the priority 10 upstreams");
+    let heavy_count = Box::new(heavy).stop().await;
+    let light_count = Box::new(light).stop().await;
+    assert_eq!(heavy_count + light_count, 80);
+    assert!(
+        heavy_count > light_count && light_count > 0,
+        "weights were not respected: {} vs {}",
+        heavy_count,
+        light_count
+    );
+    for _ in 0..5 {
+        get(&balancebeam).await;
+    }
+    assert_eq!(Box::new(backup).stop().await, 5);
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::{
+    free_address, init_logging, temp_path, write_config, BalanceBeam, EchoServer, RegistryServer,
+    Server,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts balancebeam with a single pool whose upstreams come from `discovery`.
+async fn start_balancebeam(discovery: &str, admin_address: &str) -> BalanceBeam {
+    let config_path = write_config(&format!(
+        "pools:\n  backend: {{ discovery: {} }}\nroutes:\n  - {{ path_prefix: /, pool: backend }}\n",
+        discovery
+    ));
+    BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--admin-bind",
+            admin_address,
+            // Keep health check requests out of the counts
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await
+}
+
+/// Returns (address, alive) for each upstream of the pool, as the admin interface reports them.
+async fn upstreams(admin_address: &str) -> Vec<(String, bool)> {
+    let upstreams: serde_json::Value = serde_json::from_str(
+        &reqwest::get(format!("http://{}/upstreams", admin_address))
+            .await
+            .expect("Error sending request to the admin interface")
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    let mut upstreams: Vec<(String, bool)> = upstreams["backend"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| {
+            (
+                upstream["address"].as_str().unwrap().to_string(),
+                upstream["alive"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    upstreams.sort();
+    upstreams
+}
+
+/// Sends a GET through balancebeam on a fresh connection, returning the status code.
+async fn get(balancebeam: &BalanceBeam) -> u16 {
+    reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Upstreams listed in a file should follow the file as it changes, and upstreams that stay in
+/// the list should keep their health state.
+#[tokio::test]
+async fn test_file_discovery() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let dead = free_address();
+    let path = temp_path("yaml");
+    std::fs::write(&path, format!("- \"{}\"\n- \"{}\"\n", first.address, dead)).unwrap();
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &format!("{{ type: file, path: \"{}\" }}", path),
+        &admin_address,
+    )
+    .await;
+
+    // Connecting to the dead upstream fails, so it gets marked down and we fail over
+    for _ in 0..10 {
+        assert_eq!(get(&balancebeam).await, 200);
+    }
+    let mut expected = vec![(first.address.clone(), true), (dead.clone(), false)];
+    expected.sort();
+    assert_eq!(upstreams(&admin_address).await, expected);
+
+    log::info!("Adding the second upstream to the file");
+    std::fs::write(
+        &path,
+        format!(
+            "[\"{}\", \"{}\", {{ \"address\": \"{}\", \"weight\": 2 }}]",
+            first.address, dead, second.address
+        ),
+    )
+    .unwrap();
+    sleep(Duration::from_millis(500)).await;
+    let mut expected = vec![
+        (first.address.clone(), true),
+        (dead.clone(), false),
+        (second.address.clone(), true),
+    ];
+    expected.sort();
+    assert_eq!(upstreams(&admin_address).await, expected);
+
+    log::info!("Breaking the file, which should leave the upstreams alone");
+    std::fs::write(&path, "- not an address\n").unwrap();
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(upstreams(&admin_address).await, expected);
+
+    log::info!("Replacing the file with one that lists only the second upstream");
+    let replacement = format!("{}.new", path);
+    std::fs::write(&replacement, format!("- \"{}\"\n", second.address)).unwrap();
+    std::fs::rename(&replacement, &path).unwrap();
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(
+        upstreams(&admin_address).await,
+        vec![(second.address.clone(), true)]
+    );
+    let first_count = Box::new(first).stop().await;
+    for _ in 0..5 {
+        assert_eq!(get(&balancebeam).await, 200);
+    }
+    assert!(first_count >= 10);
+    assert!(Box::new(second).stop().await >= 5);
+}
+
+/// Upstreams from an HTTP endpoint should be picked up on every poll.
+#[tokio::test]
+async fn test_http_discovery() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let registry = RegistryServer::new(serde_json::json!([first.address])).await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &format!(
+            "{{ type: http, url: \"http://{}/pools/backend\", interval: 1 }}",
+            registry.address
+        ),
+        &admin_address,
+    )
+    .await;
+
+    assert_eq!(get(&balancebeam).await, 200);
+    registry.set_upstreams(serde_json::json!([{ "address": second.address }]));
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(
+        upstreams(&admin_address).await,
+        vec![(second.address.clone(), true)]
+    );
+    for _ in 0..3 {
+        assert_eq!(get(&balancebeam).await, 200);
+    }
+
+    assert_eq!(Box::new(first).stop().await, 1);
+    assert_eq!(Box::new(second).stop().await, 3);
+    assert!(Box::new(registry).stop().await >= 2);
+}
@@ -0,0 +1,229 @@
+mod common;
+
+use common::{
+    bind_any_port, free_address, init_logging, write_config, BalanceBeam, EchoServer, ErrorServer,
+    HeaderServer, Server,
+};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+/// Starts balancebeam with a route to `primary` that mirrors to the shadow pool as configured.
+async fn start_balancebeam(
+    primary: &str,
+    shadow: &str,
+    mirror: &str,
+    admin_address: &str,
+) -> BalanceBeam {
+    let config_path = write_config(&format!(
+        "pools:\n  primary: {{ upstreams: [\"{}\"] }}\n  shadow: {{ upstreams: [\"{}\"] }}\nroutes:\n  - {{ path_prefix: /, pool: primary, mirror: {} }}\n",
+        primary, shadow, mirror
+    ));
+    BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--admin-bind",
+            admin_address,
+            // Keep health check requests out of the counts
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await
+}
+
+async fn mirror_stats(admin_address: &str) -> serde_json::Value {
+    let stats: serde_json::Value = serde_json::from_str(
+        &reqwest::get(format!("http://{}/mirrors", admin_address))
+            .await
+            .expect("Error sending request to the admin interface")
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    stats["shadow"].clone()
+}
+
+/// Starts a shadow upstream that answers every request with an empty 200, passing the head of
+/// each request it gets (lowercased) to the returned receiver.
+fn recording_shadow() -> (String, mpsc::UnboundedReceiver<String>) {
+    let (listener, address) = bind_any_port();
+    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+    let (heads_tx, heads_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0_u8; 1];
+                if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                    break;
+                }
+                head.push(byte[0]);
+            }
+            let _ = heads_tx.send(String::from_utf8_lossy(&head).to_lowercase());
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await;
+        }
+    });
+    (address, heads_rx)
+}
+
+/// Every request should reach the shadow pool, whose different answers get counted but never
+/// reach the client.
+#[tokio::test]
+async fn test_mirror_requests() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let shadow = ErrorServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &primary.address,
+        &shadow.address,
+        "{ pool: shadow }",
+        &admin_address,
+    )
+    .await;
+
+    for i in 0..10 {
+        let path = format!("/item/{}", i);
+        let response = balancebeam
+            .post(&path, "payload")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response.starts_with(&format!("POST {} ", path)));
+        assert!(response.ends_with("payload"));
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let stats = mirror_stats(&admin_address).await;
+    assert_eq!(stats["mirrored"], 10);
+    assert_eq!(stats["failed"], 0);
+    assert_eq!(stats["status_mismatches"], 10);
+    assert_eq!(Box::new(primary).stop().await, 10);
+    assert_eq!(Box::new(shadow).stop().await, 10);
+}
+
+/// A slow or unreachable shadow pool must not slow down or break the real requests.
+#[tokio::test]
+async fn test_shadow_does_not_affect_primary() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let shadow = HeaderServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &primary.address,
+        &shadow.address,
+        "{ pool: shadow }",
+        &admin_address,
+    )
+    .await;
+    let started = Instant::now();
+    for _ in 0..5 {
+        balancebeam
+            .get("/?delay=2000")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(
+        started.elapsed() < Duration::from_millis(1500),
+        "the shadow pool slowed down requests"
+    );
+    sleep(Duration::from_millis(2500)).await;
+    let stats = mirror_stats(&admin_address).await;
+    assert_eq!(stats["mirrored"], 5);
+    assert_eq!(stats["status_mismatches"], 0);
+    assert!(stats["shadow_latency_ms"].as_f64().unwrap() >= 2000.0);
+    assert_eq!(Box::new(shadow).stop().await, 5);
+
+    let dead = free_address();
+    let admin_address = free_address();
+    let balancebeam =
+        start_balancebeam(&primary.address, &dead, "{ pool: shadow }", &admin_address).await;
+    for _ in 0..5 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    sleep(Duration::from_millis(500)).await;
+    let stats = mirror_stats(&admin_address).await;
+    assert_eq!(stats["mirrored"], 5);
+    assert_eq!(stats["failed"], 5);
+    assert_eq!(Box::new(primary).stop().await, 10);
+}
+
+/// Only about the configured share of requests should be mirrored.
+#[tokio::test]
+async fn test_mirror_percentage() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let shadow = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &primary.address,
+        &shadow.address,
+        "{ pool: shadow, percent: 25 }",
+        &admin_address,
+    )
+    .await;
+    for _ in 0..80 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    sleep(Duration::from_millis(500)).await;
+    let mirrored = Box::new(shadow).stop().await;
+    assert!(
+        (5..=40).contains(&mirrored),
+        "{} of 80 requests were mirrored at 25%",
+        mirrored
+    );
+    assert_eq!(mirror_stats(&admin_address).await["mirrored"], mirrored);
+    assert_eq!(Box::new(primary).stop().await, 80);
+}
+
+/// Copies should go out without the client's credentials, unless the route opts in.
+#[tokio::test]
+async fn test_mirror_strips_credentials() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    for (mirror, forwarded) in [
+        ("{ pool: shadow }", false),
+        ("{ pool: shadow, forward_credentials: true }", true),
+    ] {
+        let (shadow_address, mut heads) = recording_shadow();
+        let admin_address = free_address();
+        let balancebeam =
+            start_balancebeam(&primary.address, &shadow_address, mirror, &admin_address).await;
+        let response_text = reqwest::Client::new()
+            .get(format!("http://{}/private", balancebeam.address))
+            .header("Authorization", "Bearer secret-token")
+            .header("Cookie", "session=secret-session")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains("authorization: Bearer secret-token"));
+
+        let head = tokio::time::timeout(Duration::from_secs(5), heads.recv())
+            .await
+            .expect("The request was never mirrored")
+            .unwrap();
+        assert!(head.starts_with("get /private http/1.1"), "{}", head);
+        for credential in [
+            "authorization: bearer secret-token",
+            "cookie: session=secret-session",
+        ] {
+            assert_eq!(head.contains(credential), forwarded, "{}: {}", mirror, head);
+        }
+    }
+    Box::new(primary).stop().await;
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use common::{
+    free_address, init_logging, write_config, BalanceBeam, EchoServer, ErrorServer, Server,
+};
+
+/// Starts balancebeam with a route to the stable pool that has a canary, configured as given.
+async fn start_balancebeam(
+    stable: &str,
+    canary: &str,
+    canary_config: &str,
+    admin_address: &str,
+) -> BalanceBeam {
+    let config_path = write_config(&format!(
+        "pools:\n  stable: {{ upstreams: [\"{}\"] }}\n  canary: {{ upstreams: [\"{}\"] }}\nroutes:\n  - {{ path_prefix: /, pool: stable, canary: {} }}\n",
+        stable, canary, canary_config
+    ));
+    BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--admin-bind",
+            admin_address,
+            // Keep health check requests out of the counts
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await
+}
+
+/// Sends a GET through balancebeam on a fresh connection, returning the status code.
+async fn get(balancebeam: &BalanceBeam, headers: &[(&str, &str)]) -> u16 {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+async fn admin(
+    method: reqwest::Method,
+    admin_address: &str,
+    path: &str,
+) -> (u16, serde_json::Value) {
+    let response = reqwest::Client::new()
+        .request(method, format!("http://{}{}", admin_address, path))
+        .send()
+        .await
+        .expect("Error sending request to the admin interface");
+    let status = response.status().as_u16();
+    (
+        status,
+        serde_json::from_str(&response.text().await.unwrap()).unwrap(),
+    )
+}
+
+/// The canary should get requests that ask for it, plus whatever share the admin interface
+/// gives it.
+#[tokio::test]
+async fn test_canary_split() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &stable.address,
+        &canary.address,
+        "{ pool: canary, header: { name: X-Canary, value: always }, cookie: { name: canary, value: always } }",
+        &admin_address,
+    )
+    .await;
+
+    for _ in 0..10 {
+        assert_eq!(get(&balancebeam, &[]).await, 200);
+    }
+    assert_eq!(get(&balancebeam, &[("x-canary", "always")]).await, 200);
+    assert_eq!(
+        get(&balancebeam, &[("cookie", "session=abc; canary=always")]).await,
+        200
+    );
+    assert_eq!(get(&balancebeam, &[("cookie", "canary=never")]).await, 200);
+
+    let (status, _) = admin(
+        reqwest::Method::POST,
+        &admin_address,
+        "/canaries?pool=canary&percent=100",
+    )
+    .await;
+    assert_eq!(status, 200);
+    for _ in 0..10 {
+        assert_eq!(get(&balancebeam, &[]).await, 200);
+    }
+    let (status, _) = admin(
+        reqwest::Method::POST,
+        &admin_address,
+        "/canaries?pool=canary&percent=50",
+    )
+    .await;
+    assert_eq!(status, 200);
+    for _ in 0..40 {
+        assert_eq!(get(&balancebeam, &[]).await, 200);
+    }
+
+    let (status, canaries) = admin(reqwest::Method::GET, &admin_address, "/canaries").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        canaries,
+        serde_json::json!({ "canary": { "pool": "stable", "percent": 50.0 } })
+    );
+    let (status, _) = admin(
+        reqwest::Method::POST,
+        &admin_address,
+        "/canaries?pool=canary&percent=150",
+    )
+    .await;
+    assert_eq!(status, 400);
+    let (status, _) = admin(
+        reqwest::Method::POST,
+        &admin_address,
+        "/canaries?pool=stable&percent=10",
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    let stable_count = Box::new(stable).stop().await;
+    let canary_count = Box::new(canary).stop().await;
+    assert_eq!(stable_count + canary_count, 63);
+    // 11 stable requests before the split changed, 2 that asked for the canary, 10 at 100%
+    assert!(
+        (16..=46).contains(&stable_count),
+        "stable pool got {} requests",
+        stable_count
+    );
+}
+
+/// Each pool's error rate should be reported separately, so the canary can be compared to the
+/// stable version.
+#[tokio::test]
+async fn test_pool_stats() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = ErrorServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = start_balancebeam(
+        &stable.address,
+        &canary.address,
+        "{ pool: canary, header: { name: X-Canary, value: \"1\" } }",
+        &admin_address,
+    )
+    .await;
+
+    for _ in 0..6 {
+        assert_eq!(get(&balancebeam, &[]).await, 200);
+    }
+    for _ in 0..3 {
+        assert_eq!(get(&balancebeam, &[("x-canary", "1")]).await, 500);
+    }
+
+    let (status, pools) = admin(reqwest::Method::GET, &admin_address, "/pools").await;
+    assert_eq!(status, 200);
+    assert_eq!(pools["stable"]["requests"], 6);
+    assert_eq!(pools["stable"]["errors"], 0);
+    assert_eq!(pools["stable"]["error_rate"], 0.0);
+    assert_eq!(pools["canary"]["requests"], 3);
+    assert_eq!(pools["canary"]["errors"], 3);
+    assert_eq!(pools["canary"]["error_rate"], 1.0);
+
+    assert_eq!(Box::new(stable).stop().await, 6);
+    assert_eq!(Box::new(canary).stop().await, 3);
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{
+    free_address, init_logging, temp_path, write_config, BalanceBeam, EchoServer, Server,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Returns (address, weight, effective weight) for each upstream of the pool, as the admin
+/// interface reports them.
+async fn weights(admin_address: &str) -> Vec<(String, f64, f64)> {
+    let upstreams: serde_json::Value = serde_json::from_str(
+        &reqwest::get(format!("http://{}/upstreams", admin_address))
+            .await
+            .expect("Error sending request to the admin interface")
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    let mut weights: Vec<(String, f64, f64)> = upstreams["backend"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| {
+            (
+                upstream["address"].as_str().unwrap().to_string(),
+                upstream["weight"].as_f64().unwrap(),
+                upstream["effective_weight"].as_f64().unwrap(),
+            )
+        })
+        .collect();
+    weights.sort_by(|a, b| a.0.cmp(&b.0));
+    weights
+}
+
+/// An upstream added to a running pool should start out with a fraction of its weight and get
+/// all of it once the slow-start window has passed. Upstreams the pool started with shouldn't
+/// ramp up at all.
+#[tokio::test]
+async fn test_slow_start_added_upstream() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let path = temp_path("yaml");
+    std::fs::write(&path, format!("- \"{}\"\n", first.address)).unwrap();
+    let config_path = write_config(&format!(
+        "pools:\n  backend: {{ discovery: {{ type: file, path: \"{}\" }}, slow_start: 3 }}\nroutes:\n  - {{ path_prefix: /, pool: backend }}\n",
+        path
+    ));
+    let admin_address = free_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    assert_eq!(
+        weights(&admin_address).await,
+        vec![(first.address.clone(), 1.0, 1.0)]
+    );
+
+    log::info!("Adding the second upstream with weight 4");
+    std::fs::write(
+        &path,
+        format!(
+            "[\"{}\", {{ \"address\": \"{}\", \"weight\": 4 }}]",
+            first.address, second.address
+        ),
+    )
+    .unwrap();
+    sleep(Duration::from_millis(500)).await;
+    let ramping = weights(&admin_address).await;
+    let (_, weight, effective_weight) = ramping
+        .iter()
+        .find(|(address, _, _)| address == &second.address)
+        .expect("The second upstream should have been added")
+        .clone();
+    assert_eq!(weight, 4.0);
+    assert!(
+        effective_weight > 0.0 && effective_weight < 4.0,
+        "The second upstream should be ramping up, but has effective weight {}",
+        effective_weight
+    );
+    let (_, _, first_weight) = ramping
+        .iter()
+        .find(|(address, _, _)| address == &first.address)
+        .unwrap()
+        .clone();
+    assert_eq!(first_weight, 1.0);
+
+    log::info!("Waiting out the slow-start window");
+    sleep(Duration::from_secs(3)).await;
+    let mut expected = vec![
+        (first.address.clone(), 1.0, 1.0),
+        (second.address.clone(), 4.0, 4.0),
+    ];
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(weights(&admin_address).await, expected);
+}
+
+/// An upstream that fails health checks and then passes them again should ramp back up.
+#[tokio::test]
+async fn test_slow_start_revived_upstream() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let second_address = second.address.clone();
+    let config_path = write_config(&format!(
+        "pools:\n  backend: {{ upstreams: [\"{}\", \"{}\"] }}\nroutes:\n  - {{ path_prefix: /, pool: backend }}\n",
+        first.address, second_address
+    ));
+    let admin_address = free_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "1",
+            "--slow-start",
+            "4",
+        ],
+    )
+    .await;
+
+    log::info!("Stopping the second upstream until a health check notices");
+    Box::new(second).stop().await;
+    sleep(Duration::from_millis(2500)).await;
+
+    log::info!("Bringing the second upstream back");
+    let _second = EchoServer::new_at_address(second_address.clone()).await;
+    sleep(Duration::from_millis(2000)).await;
+    let (_, _, effective_weight) = weights(&admin_address)
+        .await
+        .into_iter()
+        .find(|(address, _, _)| address == &second_address)
+        .unwrap();
+    assert!(
+        effective_weight < 1.0,
+        "The second upstream should be ramping up, but has effective weight {}",
+        effective_weight
+    );
+
+    log::info!("Waiting out the slow-start window");
+    sleep(Duration::from_secs(4)).await;
+    let mut expected = vec![
+        (first.address.clone(), 1.0, 1.0),
+        (second_address.clone(), 1.0, 1.0),
+    ];
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(weights(&admin_address).await, expected);
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, HeaderServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// The EWMA balancer should send most requests to the faster upstream, while still probing the
+/// slower one now and then as its latency estimate decays.
+#[tokio::test]
+async fn test_ewma_prefers_fast_upstream() {
+    init_logging();
+    // Requests ask for a delay, which only the header server honors
+    let slow = HeaderServer::new().await;
+    let fast = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow.address, &fast.address],
+        &[
+            "--balancer",
+            "ewma",
+            "--ewma-decay-ms",
+            "300",
+            // Keep health check requests out of the counts
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    for _ in 0..30 {
+        let response = reqwest::get(format!("http://{}/?delay=100", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let slow_requests = Box::new(slow).stop().await;
+    let fast_requests = Box::new(fast).stop().await;
+    log::info!(
+        "Slow upstream got {} requests, fast upstream got {}",
+        slow_requests,
+        fast_requests
+    );
+    assert_eq!(slow_requests + fast_requests, 30);
+    assert!(
+        fast_requests >= 22,
+        "The fast upstream should get most requests"
+    );
+    assert!(
+        slow_requests >= 2,
+        "The slow upstream should be probed again once its estimate decays"
+    );
+}
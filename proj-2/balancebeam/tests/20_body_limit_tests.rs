@@ -0,0 +1,113 @@
+mod common;
+
+use common::{
+    init_logging, read_response, write_config, BalanceBeam, EchoServer, FramingServer, Server,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// Starts balancebeam with a route that accepts bodies of up to 16 bytes under /small/, and the
+/// global limit everywhere else.
+async fn start_balancebeam(upstream: &str) -> BalanceBeam {
+    let config_path = write_config(&format!(
+        "pools:\n  backend: {{ upstreams: [\"{}\"] }}\nroutes:\n  - {{ path_prefix: /small/, pool: backend, max_body_size: 16 }}\n  - {{ path_prefix: /, pool: backend }}\n",
+        upstream
+    ));
+    BalanceBeam::new_with_args(&[], &["--config", &config_path, "--max-body-size", "1024"]).await
+}
+
+/// Bodies are limited per route, and the global limit applies to routes that don't set one.
+#[tokio::test]
+async fn test_route_body_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&upstream.address).await;
+    let client = reqwest::Client::new();
+    let post = |path: &str, size: usize| {
+        client
+            .post(format!("http://{}{}", balancebeam.address, path))
+            .body(vec![b'x'; size])
+            .send()
+    };
+
+    assert_eq!(post("/small/a", 16).await.unwrap().status(), 200);
+    assert_eq!(post("/small/a", 17).await.unwrap().status(), 413);
+    assert_eq!(post("/big/a", 1000).await.unwrap().status(), 200);
+    assert_eq!(post("/big/a", 1025).await.unwrap().status(), 413);
+
+    Box::new(upstream).stop().await;
+}
+
+/// A client that expects 100 Continue should get it before sending its body, or a final error
+/// straight away if the body would be refused.
+#[tokio::test]
+async fn test_expect_continue() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&upstream.address).await;
+
+    log::info!("Sending a body the route accepts");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST /small/a HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 100"), "Got {}", head);
+    assert!(body.is_empty());
+    stream.write_all(b"hello").await.unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.ends_with("\n\nhello"), "Upstream saw {}", body);
+    assert!(
+        !body.to_lowercase().contains("expect:"),
+        "The upstream shouldn't wait for a body it already has: {}",
+        body
+    );
+
+    log::info!("Declaring a body that is too big");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST /small/a HTTP/1.1\r\nHost: test\r\nContent-Length: 100\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 413"), "Got {}", head);
+    assert!(head.to_lowercase().contains("connection: close"));
+
+    log::info!("Expecting something we can't do");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST /small/a HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nExpect: 200-ok\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 417"), "Got {}", head);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Interim responses from the upstream shouldn't be mistaken for the real one.
+#[tokio::test]
+async fn test_upstream_interim_response() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/interim", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "ok");
+}
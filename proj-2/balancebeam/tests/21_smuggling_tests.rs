@@ -0,0 +1,193 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, FramingServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Writes raw bytes to balancebeam and returns everything it sends back before hanging up (or
+/// going quiet for a second).
+async fn send_raw(balancebeam: &BalanceBeam, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    loop {
+        match tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+            Ok(Ok(bytes_read)) => received.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+    String::from_utf8_lossy(&received).into_owned()
+}
+
+/// Requests that could be framed more than one way should be refused, and the connection closed
+/// so that nothing after them is read as another request.
+#[tokio::test]
+async fn test_smuggling_vectors_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let vectors: &[(&str, &[u8], &str)] = &[
+        (
+            "CL.TE",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 13\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /x HTTP/1.1\r\n\r\n",
+            "400",
+        ),
+        (
+            "TE.CL",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n8\r\nGET /x H\r\n0\r\n\r\n",
+            "400",
+        ),
+        (
+            "TE.TE",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n0\r\n\r\n",
+            "501",
+        ),
+        (
+            "conflicting Content-Length",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 20\r\n\r\nabcGET /x HTTP/1.1\r\n\r\n",
+            "400",
+        ),
+        (
+            "conflicting Content-Length list",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3, 20\r\n\r\nabcGET /x HTTP/1.1\r\n\r\n",
+            "400",
+        ),
+        (
+            "signed Content-Length",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +3\r\n\r\nabc",
+            "400",
+        ),
+        (
+            "whitespace before colon",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding : chunked\r\nContent-Length: 3\r\n\r\nabc",
+            "400",
+        ),
+        (
+            "obsolete line folding",
+            b"POST / HTTP/1.1\r\nHost: a\r\nX-Padding: a\r\n Transfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\nabc",
+            "400",
+        ),
+        ("bare LF", b"GET / HTTP/1.1\nHost: a\n\n", "400"),
+        (
+            "bare CR",
+            b"GET / HTTP/1.1\r\nHost: a\rX: b\r\n\r\n",
+            "400",
+        ),
+        (
+            "control character",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-Foo: a\x01b\r\n\r\n",
+            "400",
+        ),
+        (
+            "multiple Host headers",
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            "400",
+        ),
+    ];
+    for (name, request, status) in vectors {
+        log::info!("Trying {}", name);
+        let response = send_raw(&balancebeam, request).await;
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {}", status)),
+            "{}: expected {}, got {:?}",
+            name,
+            status,
+            response
+        );
+        assert_eq!(
+            response.matches("HTTP/1.1").count(),
+            1,
+            "{}: the rest of the connection shouldn't be read as another request: {:?}",
+            name,
+            response
+        );
+    }
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        0,
+        "None of these requests should reach the upstream"
+    );
+}
+
+/// Repeating the same Content-Length is harmless; it should be forwarded as a single header.
+#[tokio::test]
+async fn test_duplicate_content_length_folded() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let response = send_raw(
+        &balancebeam,
+        b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got {:?}", response);
+    assert_eq!(
+        response
+            .to_lowercase()
+            .matches("content-length: 5\n")
+            .count(),
+        1,
+        "The upstream should see exactly one Content-Length: {:?}",
+        response
+    );
+    assert!(response.ends_with("hello"));
+}
+
+/// Bytes after a request's body are the next request on the connection, never more body, so a
+/// request can't be smuggled in after one that closes the connection.
+#[tokio::test]
+async fn test_bytes_after_body_not_forwarded() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    // Whatever follows the body is the next request on the connection, not more body; with
+    // Connection: close it must never reach the upstream at all
+    let response = send_raw(
+        &balancebeam,
+        b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nConnection: close\r\n\r\n\
+          helloGET /smuggled HTTP/1.1\r\nHost: a\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got {:?}", response);
+    assert!(response.ends_with("hello"), "Got {:?}", response);
+    assert!(!response.contains("smuggled"), "Got {:?}", response);
+    let num_requests = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests, 1,
+        "The upstream should see exactly one request"
+    );
+}
+
+/// An upstream response with conflicting lengths shouldn't be passed on to the client.
+#[tokio::test]
+async fn test_ambiguous_upstream_response_rejected() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/ambiguous-length", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 502);
+}
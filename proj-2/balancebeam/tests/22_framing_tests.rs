@@ -0,0 +1,201 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, FramingServer, Server, FRAMING_SERVER_BODY};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn start_balancebeam(upstream: &FramingServer) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await
+}
+
+/// Responses that can't have a body (204, 304, and anything answering HEAD) should end with
+/// their headers, even if they carry a Content-Length, and leave the upstream connection usable.
+#[tokio::test]
+async fn test_bodiless_responses() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("http://{}/no-content", balancebeam.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    assert!(response.text().await.unwrap().is_empty());
+
+    let response = client
+        .get(format!("http://{}/not-modified", balancebeam.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers()["etag"], "\"v1\"");
+    assert!(response.text().await.unwrap().is_empty());
+
+    let response = client
+        .head(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-length"],
+        FRAMING_SERVER_BODY.len().to_string().as_str(),
+        "HEAD should get the Content-Length a GET would"
+    );
+    assert!(response.text().await.unwrap().is_empty());
+
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), FRAMING_SERVER_BODY);
+
+    assert_eq!(
+        upstream.connections_accepted(),
+        1,
+        "Every request should have gone over the same upstream connection"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 4);
+}
+
+/// 1xx responses before the final one should be skipped rather than forwarded as the response.
+#[tokio::test]
+async fn test_interim_responses_skipped() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..2 {
+        let response = client
+            .get(format!("http://{}/interim", balancebeam.address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+    assert_eq!(upstream.connections_accepted(), 1);
+    Box::new(upstream).stop().await;
+}
+
+/// Chunked bodies should be decoded, and the upstream connection reused after them.
+#[tokio::test]
+async fn test_chunked_response() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..2 {
+        let response = client
+            .get(format!("http://{}/chunked", balancebeam.address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), FRAMING_SERVER_BODY);
+    }
+    assert_eq!(upstream.connections_accepted(), 1);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Whatever an upstream sends past its Content-Length should be dropped, along with the
+/// connection, rather than passed on or taken for its next response.
+#[tokio::test]
+async fn test_over_sent_response() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+    let client = reqwest::Client::new();
+
+    for path in ["/over-send", "/"] {
+        let response = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), FRAMING_SERVER_BODY);
+    }
+    assert_eq!(
+        upstream.connections_accepted(),
+        2,
+        "The connection the extra bytes came on shouldn't have been reused"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Whatever an upstream sends after the end of a chunked body should be dropped the same way.
+#[tokio::test]
+async fn test_over_sent_chunked_response() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+    let client = reqwest::Client::new();
+
+    for path in ["/chunked-over-send", "/"] {
+        let response = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), FRAMING_SERVER_BODY);
+    }
+    assert_eq!(
+        upstream.connections_accepted(),
+        2,
+        "The connection the extra bytes came on shouldn't have been reused"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// A body that ends when the upstream hangs up should be read to the end, and then the client
+/// connection closed too, since the upstream connection can't be reused.
+#[tokio::test]
+async fn test_close_delimited_response() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+        stream
+            .write_all(b"GET /close HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .expect("balancebeam should close the connection after the response")
+            .unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200"), "Got {:?}", received);
+        assert!(
+            received.to_lowercase().contains("connection: close"),
+            "Got {:?}",
+            received
+        );
+        assert!(
+            received.ends_with(FRAMING_SERVER_BODY),
+            "Got {:?}",
+            received
+        );
+    }
+    assert_eq!(
+        upstream.connections_accepted(),
+        2,
+        "The upstream connection shouldn't be reused after a close-delimited body"
+    );
+    Box::new(upstream).stop().await;
+}
@@ -0,0 +1,211 @@
+mod common;
+
+use common::{
+    init_logging, read_response, BalanceBeam, EchoServer, FramingServer, Server,
+    FRAMING_SERVER_BODY,
+};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Panics unless balancebeam hangs up on the connection (without sending anything more).
+async fn assert_closed(stream: &mut TcpStream) {
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam should have closed the connection")
+        .unwrap();
+    assert!(rest.is_empty(), "Got {:?}", String::from_utf8_lossy(&rest));
+}
+
+/// A client that says Connection: close should get it, without the upstream hearing about it.
+#[tokio::test]
+async fn test_client_connection_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /a HTTP/1.1\r\nHost: test\r\nConnection: close, X-Hop\r\nX-Hop: 1\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+    assert!(head.to_lowercase().contains("connection: close"));
+    let body = String::from_utf8(body).unwrap().to_lowercase();
+    assert!(
+        !body.contains("connection:") && !body.contains("x-hop:"),
+        "Hop-by-hop headers shouldn't be forwarded: {}",
+        body
+    );
+    assert_closed(&mut stream).await;
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// HTTP/1.0 connections close after each response, unless the client asks for keep-alive.
+#[tokio::test]
+async fn test_pipelined_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    // Both requests arrive in one read; the second must be served after the first, not lost or
+    // taken for part of it
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"GET /first HTTP/1.1\r\nHost: test\r\n\r\n\
+              GET /second HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut responses = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut responses))
+        .await
+        .expect("balancebeam should have answered both requests and hung up")
+        .unwrap();
+    let responses = String::from_utf8(responses).unwrap();
+    assert_eq!(
+        responses.matches("HTTP/1.1 200").count(),
+        2,
+        "Got {:?}",
+        responses
+    );
+    let first = responses
+        .find("GET /first ")
+        .expect("Missing first response");
+    let second = responses
+        .find("GET /second ")
+        .expect("Missing second response");
+    assert!(first < second, "Responses out of order: {:?}", responses);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+#[tokio::test]
+async fn test_http10_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    log::info!("Sending an HTTP/1.0 request");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(b"GET /a HTTP/1.0\r\n\r\n").await.unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+    assert!(head.to_lowercase().contains("connection: close"));
+    let body = String::from_utf8(body).unwrap();
+    assert!(
+        body.starts_with("GET /a HTTP/1.1\n"),
+        "We should speak HTTP/1.1 to the upstream: {}",
+        body
+    );
+    assert_closed(&mut stream).await;
+
+    log::info!("Sending HTTP/1.0 requests with keep-alive");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        stream
+            .write_all(b"GET /b HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+        assert!(
+            head.to_lowercase().contains("connection: keep-alive"),
+            "Got {}",
+            head
+        );
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// With --max-requests-per-connection, the last request allowed gets Connection: close.
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "600",
+            "--max-requests-per-connection",
+            "3",
+        ],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for i in 1..=3 {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+        assert_eq!(
+            head.to_lowercase().contains("connection: close"),
+            i == 3,
+            "Request {}: {}",
+            i,
+            head
+        );
+    }
+    assert_closed(&mut stream).await;
+    Box::new(upstream).stop().await;
+}
+
+/// An upstream connection shouldn't be reused once the upstream says it's closing (or is an
+/// HTTP/1.0 server that didn't offer keep-alive), and that shouldn't close the client's.
+#[tokio::test]
+async fn test_upstream_closes_connection() {
+    init_logging();
+    let upstream = FramingServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for path in [
+        "/connection-close",
+        "/http10",
+        "/connection-close",
+        "/http10",
+    ] {
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let (head, body) = read_response(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}: got {}", path, head);
+        assert!(
+            !head.to_lowercase().contains("connection:"),
+            "{}: the upstream's Connection header shouldn't reach the client: {}",
+            path,
+            head
+        );
+        assert_eq!(String::from_utf8(body).unwrap(), FRAMING_SERVER_BODY);
+    }
+    assert_eq!(
+        upstream.connections_accepted(),
+        4,
+        "Each request needed a new upstream connection"
+    );
+    Box::new(upstream).stop().await;
+}
@@ -0,0 +1,334 @@
+mod common;
+
+use common::{init_logging, temp_path, write_config, BalanceBeam, ErrorServer, Server};
+
+/// balancebeam's default --max-body-size
+const DEFAULT_MAX_BODY_SIZE: usize = 10000000;
+
+/// Creates a directory of files to serve:
+///
+/// * a.txt (the digits 0 to 9)
+/// * index.html
+/// * sub/index.html
+/// * .secret
+fn make_site() -> String {
+    let root = temp_path("site");
+    std::fs::create_dir_all(format!("{}/sub", root)).unwrap();
+    std::fs::write(format!("{}/a.txt", root), "0123456789").unwrap();
+    std::fs::write(format!("{}/index.html", root), "<h1>home</h1>").unwrap();
+    std::fs::write(format!("{}/sub/index.html", root), "<h1>sub</h1>").unwrap();
+    std::fs::write(format!("{}/.secret", root), "hunter2").unwrap();
+    root
+}
+
+/// Starts balancebeam serving make_site() under /static/, with no upstreams at all.
+async fn start_balancebeam() -> BalanceBeam {
+    let config_path = write_config(&format!(
+        "routes:\n  - {{ path_prefix: /static/, static_files: {{ root: \"{}\" }} }}\n",
+        make_site()
+    ));
+    BalanceBeam::new_with_args(&[], &["--config", &config_path]).await
+}
+
+#[tokio::test]
+async fn test_static_files() {
+    init_logging();
+    let balancebeam = start_balancebeam().await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client.get(url("/static/a.txt")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/plain; charset=utf-8"
+    );
+    assert_eq!(response.headers()["accept-ranges"], "bytes");
+    assert!(response.headers().contains_key("last-modified"));
+    let etag = response.headers()["etag"].clone();
+    assert_eq!(response.text().await.unwrap(), "0123456789");
+
+    log::info!("Serving directory indexes");
+    let response = client.get(url("/static/")).send().await.unwrap();
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(response.text().await.unwrap(), "<h1>home</h1>");
+    let response = client.get(url("/static/sub")).send().await.unwrap();
+    assert_eq!(response.url().path(), "/static/sub/", "Should redirect");
+    assert_eq!(response.text().await.unwrap(), "<h1>sub</h1>");
+
+    log::info!("Refusing what shouldn't be served");
+    for path in [
+        "/static/missing.txt",
+        "/static/.secret",
+        "/static/%2e%2e/%2e%2e/etc/passwd",
+        "/static/sub%2f..%2f.secret",
+    ] {
+        let response = client.get(url(path)).send().await.unwrap();
+        assert_eq!(response.status(), 404, "{}", path);
+    }
+    let response = client.post(url("/static/a.txt")).send().await.unwrap();
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+
+    log::info!("Answering HEAD and conditional requests");
+    let response = client.head(url("/static/a.txt")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-length"], "10");
+    assert!(response.text().await.unwrap().is_empty());
+    let response = client
+        .get(url("/static/a.txt"))
+        .header("If-None-Match", etag.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+}
+
+#[tokio::test]
+async fn test_static_file_ranges() {
+    init_logging();
+    let balancebeam = start_balancebeam().await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/static/a.txt", balancebeam.address);
+    let etag = client.get(&url).send().await.unwrap().headers()["etag"].clone();
+
+    let get_range = |range: &str| client.get(&url).header("Range", range).send();
+    for (range, body, content_range) in [
+        ("bytes=2-4", "234", "bytes 2-4/10"),
+        ("bytes=7-", "789", "bytes 7-9/10"),
+        ("bytes=-3", "789", "bytes 7-9/10"),
+        ("bytes=8-100", "89", "bytes 8-9/10"),
+    ] {
+        let response = get_range(range).await.unwrap();
+        assert_eq!(response.status(), 206, "{}", range);
+        assert_eq!(response.headers()["content-range"], content_range);
+        assert_eq!(response.text().await.unwrap(), body);
+    }
+
+    let response = get_range("bytes=10-").await.unwrap();
+    assert_eq!(response.status(), 416);
+    assert_eq!(response.headers()["content-range"], "bytes */10");
+
+    // Ranges we don't support (or can't make sense of) get the whole file
+    for range in ["bytes=0-1,4-5", "bytes=5-2", "lines=1-2"] {
+        let response = get_range(range).await.unwrap();
+        assert_eq!(response.status(), 200, "{}", range);
+        assert_eq!(response.text().await.unwrap(), "0123456789");
+    }
+
+    log::info!("Checking If-Range");
+    let response = client
+        .get(&url)
+        .header("Range", "bytes=0-0")
+        .header("If-Range", etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 206);
+    let response = client
+        .get(&url)
+        .header("Range", "bytes=0-0")
+        .header("If-Range", "\"something-else\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+/// A range of a file larger than the body limit comes back on its own, and HEAD reports the
+/// file's full length.
+#[tokio::test]
+async fn test_static_file_large_range() {
+    init_logging();
+    let root = temp_path("large-site");
+    std::fs::create_dir_all(&root).unwrap();
+    let length = DEFAULT_MAX_BODY_SIZE + 1000000;
+    let contents: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+    std::fs::write(format!("{}/large.bin", root), &contents).unwrap();
+    let config_path = write_config(&format!(
+        "routes:\n  - {{ path_prefix: /static/, static_files: {{ root: \"{}\" }} }}\n",
+        root
+    ));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", &config_path]).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/static/large.bin", balancebeam.address);
+
+    let (start, end) = (length - 5000, length - 1000);
+    let response = client
+        .get(&url)
+        .header("Range", format!("bytes={}-{}", start, end))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 206);
+    assert_eq!(
+        response.headers()["content-range"],
+        format!("bytes {}-{}/{}", start, end, length).as_str()
+    );
+    assert_eq!(
+        response.bytes().await.unwrap().as_ref(),
+        &contents[start..=end]
+    );
+
+    let response = client.head(&url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-length"],
+        length.to_string().as_str()
+    );
+    assert!(response.bytes().await.unwrap().is_empty());
+}
+
+/// A response that would carry more of a file than max_size is refused, for HEAD as for GET,
+/// while ranges within it are still served.
+#[tokio::test]
+async fn test_static_file_max_size() {
+    init_logging();
+    let root = temp_path("capped-site");
+    std::fs::create_dir_all(&root).unwrap();
+    let contents: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+    std::fs::write(format!("{}/large.bin", root), &contents).unwrap();
+    std::fs::write(format!("{}/small.txt", root), "0123456789").unwrap();
+    let config_path = write_config(&format!(
+        "routes:\n  - {{ path_prefix: /static/, static_files: {{ root: \"{}\", max_size: 1000 }} }}\n",
+        root
+    ));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", &config_path]).await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client.get(url("/static/large.bin")).send().await.unwrap();
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.headers()["accept-ranges"], "bytes");
+    let response = client.head(url("/static/large.bin")).send().await.unwrap();
+    assert_eq!(response.status(), 500);
+
+    log::info!("Fetching the file a range at a time");
+    for (start, end) in [(0, 999), (1000, 1999)] {
+        let response = client
+            .get(url("/static/large.bin"))
+            .header("Range", format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(
+            response.bytes().await.unwrap().as_ref(),
+            &contents[start..=end]
+        );
+    }
+    let response = client
+        .get(url("/static/large.bin"))
+        .header("Range", "bytes=0-1000")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+
+    let response = client.get(url("/static/small.txt")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "0123456789");
+}
+
+/// Errors balancebeam generates get the configured page (the route's first, then the global
+/// one, picking HTML or JSON by the client's Accept header); errors from upstreams don't.
+#[tokio::test]
+async fn test_error_pages() {
+    init_logging();
+    let pages = temp_path("pages");
+    std::fs::create_dir_all(&pages).unwrap();
+    let page = |name: &str, contents: &str| {
+        let path = format!("{}/{}", pages, name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    };
+    let html = page(
+        "5xx.html",
+        "<p>{{status}} {{reason}}</p><p>Request {{request_id}}</p>",
+    );
+    let json = page(
+        "5xx.json",
+        "{\"status\": {{status}}, \"request_id\": \"{{request_id}}\"}",
+    );
+    let api_json = page("api.json", "{\"error\": \"api is down\"}");
+    let client_html = page("4xx.html", "<p>your fault: {{status}}</p>");
+    let errors = ErrorServer::new().await;
+    let config_path = write_config(&format!(
+        "pools:\n  down: {{ upstreams: [\"127.0.0.1:1\"] }}\n  errors: {{ upstreams: [\"{}\"] }}\n\
+        routes:\n  - {{ path_prefix: /api/, pool: down, error_pages: {{ 502: {{ json: \"{}\" }} }} }}\n\
+        \x20 - {{ path_prefix: /errors/, pool: errors }}\n  - {{ path_prefix: /, pool: down }}\n\
+        error_pages:\n  5xx: {{ html: \"{}\", json: \"{}\" }}\n  4xx: {{ html: \"{}\" }}\n",
+        errors.address, api_json, html, json, client_html
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--max-uri-length",
+            "64",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client
+        .get(url("/page"))
+        .header("Accept", "text/html,*/*")
+        .header("X-Request-Id", "<b>req-1</b>")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 502);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(
+        response.text().await.unwrap(),
+        "<p>502 Bad Gateway</p><p>Request &lt;b&gt;req-1&lt;/b&gt;</p>"
+    );
+
+    let response = client
+        .get(url("/page"))
+        .header("Accept", "application/json")
+        .header("X-Request-Id", "req-\"2\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"status\": 502, \"request_id\": \"req-\\\"2\\\"\"}"
+    );
+
+    log::info!("Using the route's own page");
+    let response = client.get(url("/api/things")).send().await.unwrap();
+    assert_eq!(response.status(), 502);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"error\": \"api is down\"}"
+    );
+
+    log::info!("Using a class's page for a request we refuse before routing");
+    let response = client
+        .get(url(&format!("/{}", "x".repeat(100))))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 414);
+    assert_eq!(response.text().await.unwrap(), "<p>your fault: 414</p>");
+
+    log::info!("Leaving upstream errors alone");
+    let response = client.get(url("/errors/")).send().await.unwrap();
+    assert_eq!(response.status(), 500);
+    assert!(response.text().await.unwrap().is_empty());
+
+    Box::new(errors).stop().await;
+}
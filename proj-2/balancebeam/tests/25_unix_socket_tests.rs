@@ -0,0 +1,84 @@
+mod common;
+
+use common::{init_logging, read_response, temp_path, BalanceBeam, EchoServer, Server};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+
+/// Upstreams can listen on Unix sockets, for both proxied requests and health checks.
+#[tokio::test]
+async fn test_unix_upstream() {
+    init_logging();
+    let upstream = EchoServer::new_at_unix_path(&temp_path("sock")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/unix")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET /unix HTTP/1.1"));
+    assert!(
+        response_text.contains(&format!("host: {}", balancebeam.address)),
+        "The client's Host should reach the upstream: {}",
+        response_text
+    );
+
+    log::info!("Waiting for a health check");
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    let response_text = balancebeam.get("/again").await.unwrap();
+    assert!(
+        response_text.starts_with("GET /again HTTP/1.1"),
+        "The upstream should still be considered alive: {}",
+        response_text
+    );
+
+    let requests_received = Box::new(upstream).stop().await;
+    assert!(
+        requests_received >= 3,
+        "Expected the health check to reach the upstream as well, but it got {} requests",
+        requests_received
+    );
+}
+
+/// balancebeam can listen on a Unix socket, replacing one a previous process left behind.
+#[tokio::test]
+async fn test_unix_listener() {
+    init_logging();
+    let path = temp_path("sock");
+    // Leave a stale socket file at the path, as a process that crashed would
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_at_address(
+        format!("unix:{}", path),
+        &[&upstream.address],
+        &["--active-health-check-interval", "600"],
+    )
+    .await;
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    for uri in ["/first", "/second"] {
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: sidecar\r\n\r\n", uri).as_bytes())
+            .await
+            .unwrap();
+        let (head, body) = read_response(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.starts_with(&format!("GET {} HTTP/1.1", uri)),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("x-forwarded-for: 127.0.0.1"),
+            "Unix socket clients should count as local: {}",
+            body
+        );
+    }
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
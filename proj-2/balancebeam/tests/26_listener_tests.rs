@@ -0,0 +1,162 @@
+mod common;
+
+use common::{
+    free_address, init_logging, write_config, BalanceBeam, EchoServer, HeaderServer, Server,
+};
+
+/// Sends a GET for `path` to `address`, returning the status code and body.
+async fn get(address: &str, path: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Each listener uses its own routes, or the top-level ones if it has none, and they all share
+/// the same pools.
+#[tokio::test]
+async fn test_listener_routes() {
+    init_logging();
+    let public = EchoServer::new().await;
+    let internal = EchoServer::new().await;
+    let internal_address = free_address();
+    let inheriting_address = free_address();
+    let config_path = write_config(&format!(
+        "pools:\n  public: {{ upstreams: [\"{}\"] }}\n  internal: {{ upstreams: [\"{}\"] }}\n\
+        routes:\n  - {{ path_prefix: /, pool: public }}\n\
+        listeners:\n  - bind: \"{}\"\n    routes:\n\
+        \x20     - {{ path_prefix: /internal/, pool: internal }}\n\
+        \x20     - {{ path_prefix: /, pool: public }}\n\
+        \x20 - bind: \"{}\"\n",
+        public.address, internal.address, internal_address, inheriting_address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let (status, _) = get(&balancebeam.address, "/internal/a").await;
+    assert_eq!(status, 200);
+    let (status, body) = get(&internal_address, "/internal/b").await;
+    assert_eq!(status, 200);
+    assert!(body.starts_with("GET /internal/b "), "{}", body);
+    let (status, _) = get(&internal_address, "/other").await;
+    assert_eq!(status, 200);
+    let (status, _) = get(&inheriting_address, "/internal/c").await;
+    assert_eq!(status, 200);
+
+    assert_eq!(
+        Box::new(internal).stop().await,
+        1,
+        "Only the listener with the /internal/ route should use the internal pool"
+    );
+    assert_eq!(Box::new(public).stop().await, 3);
+}
+
+/// A listener's limits override the command-line ones for its clients only.
+#[tokio::test]
+async fn test_listener_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let strict_address = free_address();
+    let config_path = write_config(&format!(
+        "listeners:\n  - {{ bind: \"{}\", max_requests_per_minute: 1, max_body_size: 8 }}\n",
+        strict_address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--config",
+            &config_path,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let post = |address: &str| {
+        client
+            .post(format!("http://{}/", address))
+            .body("more than eight bytes")
+            .send()
+    };
+
+    log::info!("Checking body size limits");
+    assert_eq!(post(&balancebeam.address).await.unwrap().status(), 200);
+    assert_eq!(post(&strict_address).await.unwrap().status(), 413);
+
+    log::info!("Checking rate limits");
+    for _ in 0..3 {
+        assert_eq!(get(&balancebeam.address, "/").await.0, 200);
+    }
+    assert_eq!(get(&strict_address, "/").await.0, 200);
+    assert_eq!(get(&strict_address, "/").await.0, 429);
+
+    Box::new(upstream).stop().await;
+}
+
+/// Listeners share the cache, but never each other's responses: the same URL may be served by
+/// different upstreams on each.
+#[tokio::test]
+async fn test_listener_cache_separate() {
+    init_logging();
+    let first = HeaderServer::new().await;
+    let second = HeaderServer::new().await;
+    let second_address = free_address();
+    let config_path = write_config(&format!(
+        "pools:\n  first: {{ upstreams: [\"{}\"] }}\n  second: {{ upstreams: [\"{}\"] }}\n\
+        routes:\n  - {{ path_prefix: /, pool: first }}\n\
+        listeners:\n  - bind: \"{}\"\n    routes:\n\
+        \x20     - {{ path_prefix: /, pool: second }}\n",
+        first.address, second.address, second_address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--cache-size",
+            "1000000",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let path = "/page?header=Cache-Control:max-age%3D60";
+    let client = reqwest::Client::new();
+    for (address, expected) in [
+        (&balancebeam.address, None),
+        (&balancebeam.address, Some("HIT")),
+        (&second_address, None),
+        (&second_address, Some("HIT")),
+    ] {
+        let response = client
+            .get(format!("http://{}{}", address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), 200);
+        let cache_status = response
+            .headers()
+            .get("x-cache")
+            .map(|value| value.to_str().unwrap().to_string());
+        assert_eq!(cache_status.as_deref(), expected, "{}", address);
+    }
+
+    assert_eq!(Box::new(first).stop().await, 1);
+    assert_eq!(
+        Box::new(second).stop().await,
+        1,
+        "The second listener should have gone to its own upstream, not the first's cached response"
+    );
+}
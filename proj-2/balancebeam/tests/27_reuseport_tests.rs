@@ -0,0 +1,260 @@
+mod common;
+
+use common::{
+    free_address, init_logging, read_response, temp_path, BalanceBeam, EchoServer, Server,
+};
+use nix::sys::signal::Signal;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// How long each benchmark run sends requests for
+const BENCH_DURATION: Duration = Duration::from_secs(3);
+/// Clients sending requests at once during a benchmark run
+const BENCH_CONCURRENCY: usize = 32;
+
+/// Sends a GET on a fresh connection, returning the status line.
+async fn get(address: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    head.lines().next().unwrap().to_string()
+}
+
+/// With --reuseport-workers, clients are accepted (and served) by the worker threads, spread
+/// between them by the kernel, and shutdown still waits for them.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reuseport_workers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--reuseport-workers",
+            "4",
+            "--pin-workers",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let requests: Vec<_> = (0..40)
+        .map(|i| {
+            let address = balancebeam.address.clone();
+            tokio::spawn(async move { get(&address, &format!("/{}", i)).await })
+        })
+        .collect();
+    for request in requests {
+        let status_line = request.await.unwrap();
+        assert!(
+            status_line.starts_with("HTTP/1.1 200"),
+            "Got {}",
+            status_line
+        );
+    }
+
+    // Each connection came from a different port, which the kernel hashes to pick a socket; the
+    // chance of all 40 landing on one of the four is negligible
+    let workers = reqwest::get(format!("http://{}/workers", admin_address))
+        .await
+        .expect("Error sending request to the admin interface")
+        .text()
+        .await
+        .unwrap();
+    let workers: Vec<serde_json::Value> = serde_json::from_str(&workers).unwrap();
+    let accepted: Vec<u64> = workers
+        .iter()
+        .map(|worker| worker["accepted"].as_u64().unwrap())
+        .collect();
+    assert_eq!(accepted.len(), 4);
+    assert_eq!(accepted.iter().sum::<u64>(), 40, "{:?}", accepted);
+    assert!(
+        accepted.iter().filter(|&&count| count > 0).count() > 1,
+        "Every connection went to one worker: {:?}",
+        accepted
+    );
+
+    log::info!("Shutting down");
+    balancebeam.send_signal(Signal::SIGTERM);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(3))
+        .await
+        .expect("balancebeam should have exited");
+    assert!(status.success(), "Exited with {}", status);
+    assert_eq!(Box::new(upstream).stop().await, 40);
+}
+
+/// On SIGUSR2, the successor should take over every worker's sockets, so that no connection
+/// queued on any of them is lost.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reuseport_workers_handoff() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let pid_file = temp_path("pid");
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--reuseport-workers",
+            "3",
+            "--pid-file",
+            &pid_file,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let old_pid = std::fs::read_to_string(&pid_file).unwrap();
+
+    let address = balancebeam.address.clone();
+    let requests = tokio::spawn(async move {
+        for i in 0..30 {
+            let status_line = get(&address, &format!("/{}", i)).await;
+            assert!(
+                status_line.starts_with("HTTP/1.1 200"),
+                "Got {}",
+                status_line
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    balancebeam.send_signal(Signal::SIGUSR2);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("old balancebeam did not exit after handing over");
+    assert!(status.success(), "Exited with {}", status);
+    requests.await.unwrap();
+
+    let new_pid = std::fs::read_to_string(&pid_file).unwrap();
+    assert_ne!(new_pid, old_pid, "successor did not write its pid");
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(new_pid.trim().parse().unwrap()),
+        Signal::SIGTERM,
+    )
+    .expect("successor is not running");
+    assert_eq!(Box::new(upstream).stop().await, 30);
+}
+
+/// Sends requests to `address` from BENCH_CONCURRENCY clients for `duration`, returning how
+/// many were answered per second. Clients reuse their connections if `keep_alive` is set, and
+/// open one per request (so that accepting is most of the work) otherwise.
+async fn measure_throughput(address: &str, keep_alive: bool, duration: Duration) -> f64 {
+    let request: &[u8] = if keep_alive {
+        b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n"
+    } else {
+        b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n"
+    };
+    let deadline = Instant::now() + duration;
+    let clients: Vec<_> = (0..BENCH_CONCURRENCY)
+        .map(|_| {
+            let address = address.to_string();
+            tokio::spawn(async move {
+                let mut completed = 0_usize;
+                let mut stream = None;
+                while Instant::now() < deadline {
+                    if stream.is_none() {
+                        stream = Some(TcpStream::connect(&address).await.unwrap());
+                    }
+                    let connection = stream.as_mut().unwrap();
+                    connection.write_all(request).await.unwrap();
+                    let (head, _) = read_response(connection).await;
+                    assert!(head.starts_with("HTTP/1.1 200"), "Got {}", head);
+                    completed += 1;
+                    if !keep_alive {
+                        stream = None;
+                    }
+                }
+                completed
+            })
+        })
+        .collect();
+    let mut completed = 0;
+    for client in clients {
+        completed += client.await.unwrap();
+    }
+    completed as f64 / duration.as_secs_f64()
+}
+
+/// A short run of what bench_reuseport_throughput measures, so that the benchmark keeps working
+/// even though it doesn't run by default: many clients at once on kept-alive connections, all
+/// answered.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reuseport_throughput_smoke() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--reuseport-workers",
+            "2",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    let rps = measure_throughput(&balancebeam.address, true, Duration::from_millis(500)).await;
+    assert!(rps > 0.0);
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+}
+
+/// Compares requests/second with everything accepted on the main runtime against one
+/// SO_REUSEPORT worker per CPU. It opens thousands of connections, whose ports stay in
+/// TIME_WAIT for a minute afterwards, so run it on its own:
+///
+/// ```text
+/// cargo test --release --test 27_reuseport_tests -- --ignored --nocapture
+/// ```
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_reuseport_throughput() {
+    // Logging every request would be most of what balancebeam spends its time on
+    std::env::set_var("RUST_LOG", "warn");
+    let upstream = EchoServer::new().await;
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |cpus| cpus.get())
+        .to_string();
+    let designs: [(&str, Vec<&str>); 2] = [
+        ("main runtime", vec![]),
+        (
+            "reuseport workers",
+            vec!["--reuseport-workers", &workers, "--pin-workers"],
+        ),
+    ];
+
+    println!(
+        "{:<20} {:>16} {:>16}",
+        "design", "keep-alive rps", "new conn rps"
+    );
+    for (name, extra_args) in designs {
+        let mut args = vec!["--active-health-check-interval", "600"];
+        args.extend(extra_args);
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+        // Warm up, so that neither design pays for connecting to the upstream first
+        measure_throughput(&balancebeam.address, true, BENCH_DURATION).await;
+        let keep_alive = measure_throughput(&balancebeam.address, true, BENCH_DURATION).await;
+        let new_connections = measure_throughput(&balancebeam.address, false, BENCH_DURATION).await;
+        println!(
+            "{:<20} {:>16.0} {:>16.0}",
+            name, keep_alive, new_connections
+        );
+        drop(balancebeam);
+    }
+
+    Box::new(upstream).stop().await;
+}
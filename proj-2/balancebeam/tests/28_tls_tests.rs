@@ -0,0 +1,220 @@
+mod common;
+
+use common::{
+    free_address, init_logging, read_response, temp_path, write_config, BalanceBeam, EchoServer,
+    Server,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+
+/// A self-signed certificate for localhost, written to temporary files for balancebeam to load.
+struct Certificate {
+    cert_path: String,
+    key_path: String,
+    der: CertificateDer<'static>,
+}
+
+impl Certificate {
+    fn new() -> Certificate {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = temp_path("crt");
+        let key_path = temp_path("key");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        Certificate {
+            cert_path,
+            key_path,
+            der: certified.cert.der().clone(),
+        }
+    }
+}
+
+/// Opens a TLS connection to `address` that trusts only `certificate` and offers `alpn`,
+/// returning it along with the protocol the server picked.
+async fn connect(
+    address: &str,
+    certificate: &Certificate,
+    alpn: &[&[u8]],
+) -> (tokio_rustls::client::TlsStream<TcpStream>, Option<Vec<u8>>) {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certificate.der.clone()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(address).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let stream = connector
+        .connect(server_name, stream)
+        .await
+        .expect("TLS handshake failed");
+    let negotiated = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+    (stream, negotiated)
+}
+
+/// Sends `GET <path>` over HTTP/1.1 on a TLS connection, returning the response head and body.
+async fn get_http1(
+    mut stream: tokio_rustls::client::TlsStream<TcpStream>,
+    path: &str,
+) -> (String, String) {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    (head, String::from_utf8(body).unwrap())
+}
+
+/// Sends `GET <path>` over HTTP/2 on a TLS connection, returning the status code and body.
+async fn get_http2(
+    stream: tokio_rustls::client::TlsStream<TcpStream>,
+    path: &str,
+) -> (u16, String) {
+    let (mut client, connection) = h2::client::handshake(stream)
+        .await
+        .expect("HTTP/2 handshake failed");
+    tokio::spawn(connection);
+    let request = http::Request::get(format!("https://localhost{}", path))
+        .body(())
+        .unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let response = response.await.expect("Error receiving HTTP/2 response");
+    let status = response.status().as_u16();
+    let mut body = response.into_body();
+    let mut contents = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.unwrap();
+        let _ = body.flow_control().release_capacity(chunk.len());
+        contents.extend_from_slice(&chunk);
+    }
+    (status, String::from_utf8(contents).unwrap())
+}
+
+/// Clients that negotiate h2 over ALPN are served over HTTP/2, and ones that negotiate http/1.1
+/// (or nothing) over HTTP/1.1; the upstream gets HTTP/1.1 either way.
+#[tokio::test]
+async fn test_tls_alpn() {
+    init_logging();
+    let certificate = Certificate::new();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            &certificate.cert_path,
+            "--tls-key",
+            &certificate.key_path,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    log::info!("Negotiating HTTP/2");
+    let (stream, negotiated) = connect(&balancebeam.address, &certificate, &[b"h2"]).await;
+    assert_eq!(negotiated.as_deref(), Some(&b"h2"[..]));
+    let (status, body) = get_http2(stream, "/over-h2").await;
+    assert_eq!(status, 200);
+    assert!(body.starts_with("GET /over-h2 HTTP/1.1"), "{}", body);
+
+    log::info!("Negotiating HTTP/1.1");
+    let (stream, negotiated) = connect(&balancebeam.address, &certificate, &[b"http/1.1"]).await;
+    assert_eq!(negotiated.as_deref(), Some(&b"http/1.1"[..]));
+    let (head, body) = get_http1(stream, "/over-http1").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert!(body.starts_with("GET /over-http1 HTTP/1.1"), "{}", body);
+
+    log::info!("Offering no protocol at all");
+    let (stream, negotiated) = connect(&balancebeam.address, &certificate, &[]).await;
+    assert_eq!(negotiated, None);
+    let (head, _) = get_http1(stream, "/no-alpn").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+}
+
+/// A listener's own tls block terminates TLS on that listener only.
+#[tokio::test]
+async fn test_listener_tls() {
+    init_logging();
+    let certificate = Certificate::new();
+    let upstream = EchoServer::new().await;
+    let tls_address = free_address();
+    let config_path = write_config(&format!(
+        "pools:\n  backend: {{ upstreams: [\"{}\"] }}\n\
+        routes:\n  - {{ path_prefix: /, pool: backend }}\n\
+        listeners:\n  - bind: \"{}\"\n    tls: {{ cert: \"{}\", key: \"{}\" }}\n",
+        upstream.address, tls_address, certificate.cert_path, certificate.key_path
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            &config_path,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let (stream, negotiated) = connect(&tls_address, &certificate, &[b"h2", b"http/1.1"]).await;
+    assert_eq!(negotiated.as_deref(), Some(&b"h2"[..]));
+    let (status, body) = get_http2(stream, "/secure").await;
+    assert_eq!(status, 200);
+    assert!(body.starts_with("GET /secure HTTP/1.1"), "{}", body);
+    let (stream, _) = connect(&tls_address, &certificate, &[b"http/1.1"]).await;
+    let (head, _) = get_http1(stream, "/secure").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+
+    log::info!("Checking that the main listener still speaks cleartext");
+    let response_text = balancebeam
+        .get("/plain")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET /plain HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+}
+
+/// A client that connects and never finishes the TLS handshake is hung up on, rather than
+/// holding its connection forever.
+#[tokio::test]
+async fn test_stalled_handshake_times_out() {
+    init_logging();
+    let certificate = Certificate::new();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            &certificate.cert_path,
+            "--tls-key",
+            &certificate.key_path,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut received = Vec::new();
+    let read = stream.read_to_end(&mut received);
+    tokio::time::timeout(Duration::from_secs(10), read)
+        .await
+        .expect("balancebeam is still waiting for the handshake")
+        .unwrap();
+    assert!(received.is_empty());
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
@@ -0,0 +1,124 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{atomic, Arc};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    /// Every span received so far, in OTLP/JSON form
+    pub spans: parking_lot::Mutex<Vec<serde_json::Value>>,
+}
+
+/// Accepts OTLP/HTTP JSON exports on /v1/traces and keeps the spans in them.
+async fn collect(
+    server_state: Arc<ServerState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    if req.method() != hyper::Method::POST || req.uri().path() != "/v1/traces" {
+        return Ok(Response::builder().status(404).body(Body::empty()).unwrap());
+    }
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let export: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(export) => export,
+        Err(_) => return Ok(Response::builder().status(400).body(Body::empty()).unwrap()),
+    };
+    let mut spans = server_state.spans.lock();
+    for resource_spans in export["resourceSpans"].as_array().into_iter().flatten() {
+        for scope_spans in resource_spans["scopeSpans"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            spans.extend(
+                scope_spans["spans"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .cloned(),
+            );
+        }
+    }
+    Ok(Response::new(Body::from("{}")))
+}
+
+/// Stands in for an OpenTelemetry collector.
+pub struct CollectorServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl CollectorServer {
+    #[allow(dead_code)]
+    pub async fn new() -> CollectorServer {
+        let (listener, bind_addr_string) = crate::common::bind_any_port();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            spans: parking_lot::Mutex::new(Vec::new()),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        let server_task_state = server_task_state.clone();
+                        collect(server_task_state, req)
+                    }))
+                }
+            });
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in CollectorServer: {}", e);
+            }
+        });
+
+        CollectorServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// The spans received so far.
+    #[allow(dead_code)]
+    pub fn spans(&self) -> Vec<serde_json::Value> {
+        self.state.spans.lock().clone()
+    }
+}
+
+#[async_trait]
+impl Server for CollectorServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("CollectorServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
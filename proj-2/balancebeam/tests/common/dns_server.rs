@@ -0,0 +1,140 @@
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::{A, AAAA, SRV};
+use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{atomic, Arc};
+
+/// A record the DnsServer can answer with.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum DnsRecord {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: &'static str,
+    },
+}
+
+struct ServerState {
+    /// Records by lowercase name, without the trailing dot
+    records: parking_lot::Mutex<HashMap<String, Vec<DnsRecord>>>,
+    ttl: u32,
+    queries_received: atomic::AtomicUsize,
+}
+
+fn answer(state: &ServerState, packet: &[u8]) -> Option<Vec<u8>> {
+    let request = Message::from_vec(packet).ok()?;
+    let query = request.queries().first()?.clone();
+    state
+        .queries_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_authoritative(true)
+        .add_query(query.clone());
+    let name = query.name().to_ascii().trim_end_matches('.').to_lowercase();
+    match state.records.lock().get(&name) {
+        Some(records) => {
+            for record in records {
+                let rdata = match (record, query.query_type()) {
+                    (DnsRecord::A(ip), RecordType::A) => RData::A(A(*ip)),
+                    (DnsRecord::Aaaa(ip), RecordType::AAAA) => RData::AAAA(AAAA(*ip)),
+                    (
+                        DnsRecord::Srv {
+                            priority,
+                            weight,
+                            port,
+                            target,
+                        },
+                        RecordType::SRV,
+                    ) => RData::SRV(SRV::new(
+                        *priority,
+                        *weight,
+                        *port,
+                        Name::from_ascii(format!("{}.", target)).unwrap(),
+                    )),
+                    _ => continue,
+                };
+                response.add_answer(Record::from_rdata(query.name().clone(), state.ttl, rdata));
+            }
+        }
+        None => {
+            response.set_response_code(ResponseCode::NXDomain);
+        }
+    }
+    response.to_vec().ok()
+}
+
+/// Answers DNS queries over UDP from records that tests can change while it runs.
+pub struct DnsServer {
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl DnsServer {
+    /// Starts a server that gives every answer a TTL of `ttl` seconds.
+    #[allow(dead_code)]
+    pub async fn new(ttl: u32) -> DnsServer {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("Could not bind DnsServer socket");
+        let address = socket.local_addr().unwrap().to_string();
+        let state = Arc::new(ServerState {
+            records: parking_lot::Mutex::new(HashMap::new()),
+            ttl,
+            queries_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = state.clone();
+        let server_task = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        log::error!("Error in DnsServer: {}", e);
+                        return;
+                    }
+                };
+                if let Some(response) = answer(&server_task_state, &buf[..len]) {
+                    let _ = socket.send_to(&response, peer).await;
+                }
+            }
+        });
+        DnsServer {
+            server_task,
+            address,
+            state,
+        }
+    }
+
+    /// Replaces the records for `name` (an empty list still answers, just with no records).
+    #[allow(dead_code)]
+    pub fn set_records(&self, name: &str, records: Vec<DnsRecord>) {
+        self.state
+            .records
+            .lock()
+            .insert(name.to_lowercase(), records);
+    }
+
+    #[allow(dead_code)]
+    pub fn queries_received(&self) -> usize {
+        self.state.queries_received.load(atomic::Ordering::SeqCst)
+    }
+}
+
+impl Drop for DnsServer {
+    fn drop(&mut self) {
+        self.server_task.abort();
+    }
+}
@@ -2,7 +2,6 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -11,6 +10,7 @@ struct ServerState {
     pub requests_received: atomic::AtomicUsize,
 }
 
+#[allow(dead_code)]
 async fn echo(
     server_state: Arc<ServerState>,
     req: Request<Body>,
@@ -40,13 +40,22 @@ pub struct EchoServer {
 }
 
 impl EchoServer {
+    #[allow(dead_code)]
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        let (listener, address) = crate::common::bind_any_port();
+        EchoServer::serve(listener, address).await
     }
 
+    #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let listener = std::net::TcpListener::bind(&bind_addr_string)
+            .unwrap_or_else(|err| panic!("Could not bind {}: {}", bind_addr_string, err));
+        listener.set_nonblocking(true).unwrap();
+        EchoServer::serve(listener, bind_addr_string).await
+    }
+
+    /// Serves on an already-bound listener, whose address is `bind_addr_string`.
+    async fn serve(listener: std::net::TcpListener, bind_addr_string: String) -> EchoServer {
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -65,7 +74,8 @@ impl EchoServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -83,6 +93,46 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// Starts an EchoServer listening on a Unix domain socket at `path`. Its address is
+    /// `unix:<path>`, as balancebeam expects it.
+    #[allow(dead_code)]
+    pub async fn new_at_unix_path(path: &str) -> EchoServer {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("Error in EchoServer: {}", e);
+                            return;
+                        }
+                    },
+                    _ = &mut shutdown_rx => return,
+                };
+                let server_task_state = server_task_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| echo(server_task_state.clone(), req));
+                    let _ = hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .await;
+                });
+            }
+        });
+
+        EchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: format!("unix:{}", path),
+        }
+    }
 }
 
 #[async_trait]
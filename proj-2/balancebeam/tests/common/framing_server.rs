@@ -0,0 +1,185 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    pub connections_accepted: atomic::AtomicUsize,
+}
+
+/// The body of responses that have an ordinary one.
+pub const FRAMING_SERVER_BODY: &str = "hello world\n";
+
+/// The raw response to send for a request, and whether to hang up after it.
+fn respond(method: &str, path: &str) -> (Vec<u8>, bool) {
+    let response: &[u8] = match path {
+        "/no-content" => b"HTTP/1.1 204 No Content\r\n\r\n",
+        // Describes the representation the client already has; there's still no body
+        "/not-modified" => b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 12\r\n\r\n",
+        "/interim" => {
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+        }
+        "/chunked" => {
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6;note=x\r\nhello \r\n6\r\nworld\n\r\n0\r\nX-Checksum: 1234\r\n\r\n"
+        }
+        "/close" => return (b"HTTP/1.1 200 OK\r\n\r\nhello world\n".to_vec(), true),
+        "/connection-close" => {
+            return (
+                b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 12\r\n\r\nhello world\n"
+                    .to_vec(),
+                true,
+            )
+        }
+        // HTTP/1.0 connections close after each response unless both ends ask for keep-alive
+        "/http10" => {
+            return (
+                b"HTTP/1.0 200 OK\r\nContent-Length: 12\r\n\r\nhello world\n".to_vec(),
+                true,
+            )
+        }
+        // Sends a response after the one it was asked for
+        "/over-send" => {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nhello world\nHTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nsmuggled"
+        }
+        "/chunked-over-send" => {
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nc\r\nhello world\n\r\n0\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nsmuggled"
+        }
+        "/ambiguous-length" => {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Length: 30\r\n\r\nokHTTP/1.1 200 OK\r\n\r\n"
+        }
+        _ => {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                FRAMING_SERVER_BODY.len()
+            )
+            .into_bytes();
+            if method != "HEAD" {
+                response.extend_from_slice(FRAMING_SERVER_BODY.as_bytes());
+            }
+            return (response, false);
+        }
+    };
+    (response.to_vec(), false)
+}
+
+/// Serves requests on a single connection until the client (or the response) ends it.
+async fn serve_connection(mut stream: tokio::net::TcpStream, state: Arc<ServerState>) {
+    let mut buffer = Vec::new();
+    loop {
+        let head_len = loop {
+            if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position + 4;
+            }
+            let mut chunk = [0_u8; 4096];
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(bytes_read) => buffer.extend_from_slice(&chunk[..bytes_read]),
+            }
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_len]).into_owned();
+        buffer.drain(..head_len);
+        state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst);
+        let mut request_line = head.split_whitespace();
+        let method = request_line.next().unwrap_or("").to_string();
+        let path = request_line.next().unwrap_or("").to_string();
+        let (response, close) = respond(&method, &path);
+        if stream.write_all(&response).await.is_err() || close {
+            return;
+        }
+    }
+}
+
+/// Writes raw responses that exercise every way HTTP/1.1 delimits a response body, picked by the
+/// request path:
+///
+/// * `/no-content` answers 204 No Content
+/// * `/not-modified` answers 304 Not Modified, with a Content-Length but no body
+/// * `/interim` sends 100 Continue and 103 Early Hints before a 200 with body "ok"
+/// * `/chunked` sends a chunked body (FRAMING_SERVER_BODY, in two chunks) with a trailer
+/// * `/close` sends a body with neither a length nor chunking, then hangs up
+/// * `/connection-close` says Connection: close, then hangs up after its (delimited) body
+/// * `/http10` answers as an HTTP/1.0 server, then hangs up
+/// * `/over-send` and `/chunked-over-send` send another response right after the one asked for
+/// * `/ambiguous-length` sends conflicting Content-Length headers
+///
+/// Anything else gets a 200 with FRAMING_SERVER_BODY and a Content-Length (and no body, if it
+/// was a HEAD request). Connections are kept alive otherwise.
+pub struct FramingServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl FramingServer {
+    #[allow(dead_code)]
+    pub async fn new() -> FramingServer {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Could not bind FramingServer");
+        let bind_addr_string = listener.local_addr().unwrap().to_string();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            connections_accepted: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("Error in FramingServer: {}", e);
+                            return;
+                        }
+                    },
+                    _ = &mut shutdown_rx => return,
+                };
+                server_task_state
+                    .connections_accepted
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                tokio::spawn(serve_connection(stream, server_task_state.clone()));
+            }
+        });
+
+        FramingServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// How many connections have been opened to this server so far.
+    #[allow(dead_code)]
+    pub fn connections_accepted(&self) -> usize {
+        self.state
+            .connections_accepted
+            .load(atomic::Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Server for FramingServer {
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("FramingServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,153 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{atomic, Arc};
+use tokio::sync::oneshot;
+
+const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    /// What grpc.health.v1.Health/Check reports
+    pub serving: atomic::AtomicBool,
+    /// Fail every call with grpc-status UNAVAILABLE
+    pub unavailable: atomic::AtomicBool,
+}
+
+/// Wraps a protobuf message in gRPC's length-prefixed framing.
+#[allow(dead_code)]
+pub fn encode_message(message: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// Builds a gRPC response: an optional message followed by a grpc-status trailer.
+fn grpc_response(message: Option<Vec<u8>>, grpc_status: u32) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Some(message) = message {
+            let _ = sender.send_data(encode_message(&message).into()).await;
+        }
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", grpc_status.into());
+        let _ = sender.send_trailers(trailers).await;
+    });
+    Response::builder()
+        .header("content-type", "application/grpc")
+        .body(body)
+        .unwrap()
+}
+
+/// Answers health checks, and echoes the message of every other call back to the caller.
+async fn handle_call(
+    server_state: Arc<ServerState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    if req.uri().path() == HEALTH_CHECK_PATH {
+        // HealthCheckResponse { status: SERVING (1) or NOT_SERVING (2) }
+        let status = if server_state.serving.load(atomic::Ordering::SeqCst) {
+            1
+        } else {
+            2
+        };
+        return Ok(grpc_response(Some(vec![0x08, status]), 0));
+    }
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    if server_state.unavailable.load(atomic::Ordering::SeqCst) {
+        return Ok(grpc_response(None, 14));
+    }
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let message = body.get(5..).unwrap_or_default().to_vec();
+    Ok(grpc_response(Some(message), 0))
+}
+
+pub struct GrpcServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl GrpcServer {
+    #[allow(dead_code)]
+    pub async fn new() -> GrpcServer {
+        let (listener, bind_addr_string) = crate::common::bind_any_port();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            serving: atomic::AtomicBool::new(true),
+            unavailable: atomic::AtomicBool::new(false),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        let server_task_state = server_task_state.clone();
+                        handle_call(server_task_state, req)
+                    }))
+                }
+            });
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
+                .http2_only(true)
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in GrpcServer: {}", e);
+            }
+        });
+
+        GrpcServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// Changes what the server reports to gRPC health checks.
+    #[allow(dead_code)]
+    pub fn set_serving(&self, serving: bool) {
+        self.state.serving.store(serving, atomic::Ordering::SeqCst);
+    }
+
+    /// Makes every call fail with grpc-status UNAVAILABLE.
+    #[allow(dead_code)]
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.state
+            .unavailable
+            .store(unavailable, atomic::Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Server for GrpcServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("GrpcServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
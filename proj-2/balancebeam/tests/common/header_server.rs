@@ -0,0 +1,134 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{atomic, Arc};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Responds with whatever headers the query string asks for, so tests can control caching
+/// behavior per request:
+///
+/// * `header=Name:Value` adds a response header (may be repeated)
+/// * `size=N` makes the body N bytes long
+/// * `delay=N` waits N milliseconds before responding
+///
+/// Otherwise the body is "response N", where N counts requests received, so tests can tell
+/// whether a response came from the upstream or from a cache. If the request's If-None-Match
+/// matches the ETag the query asks for, the server answers 304 Not Modified instead.
+async fn respond(
+    server_state: Arc<ServerState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let count = server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst)
+        + 1;
+    let mut builder = Response::builder();
+    let mut etag = None;
+    let mut body = format!("response {}", count).into_bytes();
+    for (key, value) in form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes()) {
+        match key.as_ref() {
+            "header" => {
+                let (name, value) = value.split_once(':').expect("header=Name:Value");
+                if name.eq_ignore_ascii_case("etag") {
+                    etag = Some(value.to_string());
+                }
+                builder = builder.header(name, value);
+            }
+            "size" => body = vec![b'x'; value.parse().expect("size=N")],
+            "delay" => {
+                let millis = value.parse().expect("delay=N");
+                tokio::time::sleep(std::time::Duration::from_millis(millis)).await;
+            }
+            _ => {}
+        }
+    }
+    let not_modified = etag.is_some_and(|etag| {
+        req.headers()
+            .get("if-none-match")
+            .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    });
+    if not_modified {
+        return Ok(builder.status(304).body(Body::empty()).unwrap());
+    }
+    Ok(builder.body(Body::from(body)).unwrap())
+}
+
+pub struct HeaderServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl HeaderServer {
+    #[allow(dead_code)]
+    pub async fn new() -> HeaderServer {
+        let (listener, bind_addr_string) = crate::common::bind_any_port();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        let server_task_state = server_task_state.clone();
+                        respond(server_task_state, req)
+                    }))
+                }
+            });
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in HeaderServer: {}", e);
+            }
+        });
+
+        HeaderServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// How many requests have reached this server so far.
+    #[allow(dead_code)]
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Server for HeaderServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("HeaderServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
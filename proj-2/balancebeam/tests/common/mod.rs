@@ -1,13 +1,35 @@
 mod balancebeam;
+mod collector_server;
+mod dns_server;
 mod echo_server;
 mod error_server;
+mod framing_server;
+mod grpc_server;
+mod header_server;
+mod registry_server;
 mod server;
 
+use rand::Rng;
 use std::sync;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use collector_server::CollectorServer;
+#[allow(unused_imports)]
+pub use dns_server::{DnsRecord, DnsServer};
+#[allow(unused_imports)]
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use framing_server::{FramingServer, FRAMING_SERVER_BODY};
+#[allow(unused_imports)]
+pub use grpc_server::{encode_message, GrpcServer};
+#[allow(unused_imports)]
+pub use header_server::HeaderServer;
+#[allow(unused_imports)]
+pub use registry_server::RegistryServer;
 pub use server::Server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
@@ -20,3 +42,84 @@ pub fn init_logging() {
             .init();
     });
 }
+
+/// Binds a listener on localhost to a port the OS picks, returning it along with its address.
+/// (Picking a port at random instead risks colliding with another test's server.)
+#[allow(dead_code)]
+pub fn bind_any_port() -> (std::net::TcpListener, String) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Could not bind a port");
+    listener.set_nonblocking(true).unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    (listener, address)
+}
+
+/// Returns a localhost port nothing was listening on a moment ago, for servers that bind their
+/// own listeners (like balancebeam itself), or for an address that should refuse connections.
+#[allow(dead_code)]
+pub fn free_port() -> u16 {
+    let (listener, _) = bind_any_port();
+    listener.local_addr().unwrap().port()
+}
+
+/// Returns `127.0.0.1:<free_port()>`.
+#[allow(dead_code)]
+pub fn free_address() -> String {
+    format!("127.0.0.1:{}", free_port())
+}
+
+/// Returns a fresh path in the temporary directory, ending in `extension`.
+#[allow(dead_code)]
+pub fn temp_path(extension: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.{}",
+        rng.gen_range(0..u64::MAX),
+        extension
+    ));
+    path.to_str().unwrap().to_string()
+}
+
+/// Writes a config file for balancebeam to a fresh temporary path and returns that path.
+#[allow(dead_code)]
+pub fn write_config(contents: &str) -> String {
+    let path = temp_path("yaml");
+    std::fs::write(&path, contents).expect("Could not write config file");
+    path
+}
+
+/// Reads one response off a raw connection, returning its head (status line and headers) and
+/// body.
+#[allow(dead_code)]
+pub async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> (String, Vec<u8>) {
+    let mut buffer = Vec::new();
+    let head_len = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        let mut chunk = [0_u8; 1024];
+        let bytes_read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut chunk))
+                .await
+                .expect("Timed out waiting for a response")
+                .expect("Error reading from balancebeam");
+        assert!(bytes_read > 0, "balancebeam hung up without responding");
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    };
+    let head = String::from_utf8(buffer[..head_len].to_vec()).unwrap();
+    let content_length = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().unwrap())
+        })
+        .unwrap_or(0);
+    let mut body = buffer[head_len..].to_vec();
+    while body.len() < content_length {
+        let mut chunk = [0_u8; 1024];
+        let bytes_read = stream.read(&mut chunk).await.unwrap();
+        assert!(bytes_read > 0, "balancebeam hung up partway through a body");
+        body.extend_from_slice(&chunk[..bytes_read]);
+    }
+    (head, body)
+}
@@ -0,0 +1,103 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{atomic, Arc};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    /// The list of upstreams to answer with
+    pub upstreams: parking_lot::Mutex<serde_json::Value>,
+}
+
+async fn list(
+    server_state: Arc<ServerState>,
+    _req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    let body = server_state.upstreams.lock().to_string();
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+/// Stands in for a service registry, answering every request with a JSON list of upstreams.
+pub struct RegistryServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl RegistryServer {
+    #[allow(dead_code)]
+    pub async fn new(upstreams: serde_json::Value) -> RegistryServer {
+        let (listener, bind_addr_string) = crate::common::bind_any_port();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            upstreams: parking_lot::Mutex::new(upstreams),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        let server_task_state = server_task_state.clone();
+                        list(server_task_state, req)
+                    }))
+                }
+            });
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in RegistryServer: {}", e);
+            }
+        });
+
+        RegistryServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// Changes the list of upstreams the server answers with.
+    #[allow(dead_code)]
+    pub fn set_upstreams(&self, upstreams: serde_json::Value) {
+        *self.state.upstreams.lock() = upstreams;
+    }
+}
+
+#[async_trait]
+impl Server for RegistryServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("RegistryServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}